@@ -0,0 +1,146 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Decide who may call D-Bus methods that change froyodevs. Property
+// reads and read-only methods are not checked.
+
+use std::collections::BTreeSet;
+use std::error::Error;
+
+use dbus::{Connection, BusType, Message, MessageItem};
+use dbus::tree::MethodErr;
+
+use types::{FroyoResult, FroyoError, InternalError};
+use consts::DBUS_TIMEOUT;
+
+// polkit action ids, matching the shipped polkit policy
+pub const ACTION_CREATE: &'static str = "org.freedesktop.froyo1.create";
+pub const ACTION_DESTROY: &'static str = "org.freedesktop.froyo1.destroy";
+pub const ACTION_MODIFY: &'static str = "org.freedesktop.froyo1.modify";
+pub const ACTION_RESHAPE: &'static str = "org.freedesktop.froyo1.reshape";
+
+pub trait Authorizer {
+    // May the connection with this unique bus name perform action?
+    fn is_authorized(&self, sender: &str, action: &str) -> FroyoResult<bool>;
+}
+
+// Allow root, plus a configured set of other users
+pub struct UidAuthorizer {
+    conn: Connection,
+    allowed_uids: BTreeSet<u32>,
+}
+
+impl UidAuthorizer {
+    pub fn new(allowed_uids: &[u32]) -> FroyoResult<UidAuthorizer> {
+        let mut uids = allowed_uids.iter().cloned().collect::<BTreeSet<_>>();
+        uids.insert(0);
+
+        Ok(UidAuthorizer {
+            conn: try!(Connection::get_private(BusType::System)),
+            allowed_uids: uids,
+        })
+    }
+}
+
+impl Authorizer for UidAuthorizer {
+    fn is_authorized(&self, sender: &str, _action: &str) -> FroyoResult<bool> {
+        let mut m = Message::new_method_call(
+            "org.freedesktop.DBus",
+            "/org/freedesktop/DBus",
+            "org.freedesktop.DBus",
+            "GetConnectionUnixUser").unwrap();
+        m.append_items(&[sender.into()]);
+        let r = try!(self.conn.send_with_reply_and_block(m, DBUS_TIMEOUT));
+
+        let uid: u32 = try!(
+            r.get_items().get(0)
+                .and_then(|i| i.inner().ok())
+                .ok_or_else(|| FroyoError::Froyo(InternalError(
+                    "Unexpected reply from GetConnectionUnixUser".into()))));
+
+        Ok(self.allowed_uids.contains(&uid))
+    }
+}
+
+// Ask polkit. It isn't allowed to prompt the caller to authenticate,
+// since the main loop can't wait for that, so actions needing
+// authentication are denied.
+pub struct PolkitAuthorizer {
+    conn: Connection,
+}
+
+impl PolkitAuthorizer {
+    pub fn new() -> FroyoResult<PolkitAuthorizer> {
+        Ok(PolkitAuthorizer {
+            conn: try!(Connection::get_private(BusType::System)),
+        })
+    }
+}
+
+impl Authorizer for PolkitAuthorizer {
+    fn is_authorized(&self, sender: &str, action: &str) -> FroyoResult<bool> {
+        let subject = MessageItem::Struct(vec![
+            "system-bus-name".into(),
+            MessageItem::Array(
+                vec![MessageItem::DictEntry(
+                    Box::new("name".into()),
+                    Box::new(MessageItem::Variant(Box::new(sender.into()))))],
+                "{sv}".into())]);
+        let details = MessageItem::Array(Vec::new(), "{ss}".into());
+        let allow_user_interaction = 0u32;
+
+        let mut m = Message::new_method_call(
+            "org.freedesktop.PolicyKit1",
+            "/org/freedesktop/PolicyKit1/Authority",
+            "org.freedesktop.PolicyKit1.Authority",
+            "CheckAuthorization").unwrap();
+        m.append_items(&[subject, action.into(), details,
+                         allow_user_interaction.into(), "".into()]);
+        let r = try!(self.conn.send_with_reply_and_block(m, DBUS_TIMEOUT));
+
+        // Reply is (is_authorized, is_challenge, details)
+        let err_msg = "Unexpected reply from CheckAuthorization";
+        let reply = r.get_items();
+        let result: &Vec<_> = try!(
+            reply.get(0)
+                .and_then(|i| i.inner().ok())
+                .ok_or_else(|| FroyoError::Froyo(InternalError(err_msg.into()))));
+        let authorized: bool = try!(
+            result.get(0)
+                .and_then(|i| i.inner().ok())
+                .ok_or_else(|| FroyoError::Froyo(InternalError(err_msg.into()))));
+        let challenge: bool = try!(
+            result.get(1)
+                .and_then(|i| i.inner().ok())
+                .ok_or_else(|| FroyoError::Froyo(InternalError(err_msg.into()))));
+
+        if challenge {
+            dbgp!("{} would need to authenticate for {}", sender, action);
+        }
+
+        Ok(authorized)
+    }
+}
+
+// Check a method call's sender before acting on it
+pub fn check(auth: &Authorizer, m: &Message, action: &str) -> Result<(), MethodErr> {
+    let sender = match m.sender() {
+        Some(sender) => sender.to_string(),
+        None => return Err(("org.freedesktop.DBus.Error.AccessDenied",
+                            "Method call has no sender").into()),
+    };
+
+    match auth.is_authorized(&sender, action) {
+        Ok(true) => Ok(()),
+        Ok(false) => {
+            dbgp!("{} denied {}", sender, action);
+            Err(("org.freedesktop.DBus.Error.AccessDenied",
+                 format!("Not authorized for {}", action)).into())
+        },
+        Err(err) => {
+            let msg = format!("Authorization check failed: {}", err.description());
+            Err(MethodErr::failed(&msg))
+        },
+    }
+}
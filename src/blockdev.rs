@@ -22,7 +22,7 @@ use byteorder::{LittleEndian, ByteOrder};
 use uuid::Uuid;
 use bytesize::ByteSize;
 
-use types::{Sectors, SectorOffset, FroyoResult, FroyoError};
+use types::{Sectors, SectorOffset, FroyoResult, FroyoError, FroyoErrorKind, InternalError};
 use consts::*;
 use util::blkdev_size;
 use dmdevice::DmDevice;
@@ -94,10 +94,10 @@ impl BlockDev {
             try!(f.read(&mut buf));
 
             if buf.iter().any(|x| *x != 0) {
-                return Err(FroyoError::Io(io::Error::new(
-                    ErrorKind::InvalidInput,
-                    format!("First 4K of {} is not zeroed, need to use --force",
-                            path.display()))));
+                return Err(FroyoError::Typed(
+                    FroyoErrorKind::NeedsForce,
+                    InternalError(format!("First 4K of {} is not zeroed, need to use --force",
+                                          path.display()).into())));
             }
         }
 
@@ -157,9 +157,10 @@ impl BlockDev {
         let crc = crc32::checksum_ieee(&buf[4..HEADER_SIZE as usize]);
         if crc != LittleEndian::read_u32(&buf[..4]) {
             dbgp!("{} Froyo header CRC failed", path.display());
-            return Err(FroyoError::Io(io::Error::new(
-                ErrorKind::InvalidInput,
-                format!("{} Froyo header CRC failed", path.display()))));
+            return Err(FroyoError::Typed(
+                FroyoErrorKind::Corrupt,
+                InternalError(format!("{} Froyo header CRC failed",
+                                      path.display()).into())));
             // TODO: Try to read end-of-disk copy
         }
 
@@ -274,8 +275,8 @@ impl BlockDev {
         try!(f.read_exact(&mut buf));
 
         if younger_mda.crc != crc32::checksum_ieee(&buf) {
-            return Err(FroyoError::Io(io::Error::new(
-                ErrorKind::InvalidInput, "Froyo MDA CRC failed")))
+            return Err(FroyoError::Typed(
+                FroyoErrorKind::Corrupt, InternalError("Froyo MDA CRC failed".into())))
             // TODO: Read backup copy
         }
 
@@ -6,6 +6,13 @@ use types::{Sectors, SectorOffset};
 
 pub const DBUS_TIMEOUT: i32 = 20000; // millieconds
 
+// Froyodev names also name a /dev/froyo node
+pub const MAX_NAME_LEN: usize = 64;
+// Froyodevs may also be given by the start of their uuid, at least
+// as much of it as short_id() shows, so a mistyped name doesn't
+// match some other froyodev
+pub const MIN_ID_PREFIX_LEN: usize = 8;
+
 pub const REDUNDANCY: usize = 1;
 pub const MIN_BLK_DEVS: usize = 2;
 
@@ -25,6 +32,9 @@ pub const MDAX_ZONE_SECTORS: Sectors = Sectors(1020);
 pub const MDAA_ZONE_OFFSET: SectorOffset = SectorOffset(8);
 pub const MDAB_ZONE_OFFSET: SectorOffset = SectorOffset(1028);
 
+// DmDevice::clear() zeroes this much per write
+pub const CLEAR_CHUNK_BYTES: u64 = MEGA;
+
 pub const FRO_MAGIC: &'static [u8] = b"!IamFroy0\x86\xffGO\x02^\x41";
 pub const STRIPE_SECTORS: Sectors = Sectors(MEGA / SECTOR_SIZE);
 
@@ -49,10 +59,52 @@ pub const DEFAULT_REGION_SECTORS: Sectors = Sectors(DEFAULT_REGION_SIZE / SECTOR
 pub const IDEAL_RAID_COUNT: usize = 10;
 
 pub const DATA_BLOCK_SIZE: Sectors = Sectors(MEGA / SECTOR_SIZE);
+// Kernel limits on thin pool data block size. It must also be a
+// multiple of the minimum.
+pub const TPOOL_MIN_DATA_BLOCK_SIZE: Sectors = Sectors(64 * 1024 / SECTOR_SIZE);
+pub const TPOOL_MAX_DATA_BLOCK_SIZE: Sectors = Sectors(GIGA / SECTOR_SIZE);
 pub const TPOOL_LOW_WATER_BLOCKS: u64 = 512; // 512MiB
 
 pub const TPOOL_INITIAL_META_SECTORS: Sectors = Sectors(4 * MEGA / SECTOR_SIZE);
 pub const TPOOL_INITIAL_DATA_SECTORS: Sectors = Sectors(2 * GIGA / SECTOR_SIZE);
 pub const TPOOL_EXTEND_SECTORS: Sectors = Sectors(GIGA / SECTOR_SIZE);
 
-pub const THIN_INITIAL_SECTORS: Sectors = Sectors(128 * GIGA / SECTOR_SIZE);
+// Thin pool metadata is allocated in 4KiB blocks. The kernel can't
+// use more than about 16GiB of it.
+pub const TPOOL_META_BLOCK_SECTORS: Sectors = Sectors(4096 / SECTOR_SIZE);
+pub const TPOOL_META_LOW_WATER_BLOCKS: u64 = 256; // 1MiB
+pub const TPOOL_MAX_META_SECTORS: Sectors = Sectors(255 * (1 << 14) * 8);
+// Size metadata extensions to last at least this long at the
+// observed growth rate.
+pub const TPOOL_META_PREDICT_SECS: u64 = 24 * 60 * 60;
+// Minimum time between metadata usage samples for the growth rate
+pub const TPOOL_META_SAMPLE_SECS: i64 = 60;
+
+// Throttle writes to thin devs by this much when the thin pool has
+// less than this much free data space and can't be extended. Stop
+// once there's twice as much.
+pub const TPOOL_THROTTLE_SECTORS: Sectors = Sectors(2 * GIGA / SECTOR_SIZE);
+pub const TPOOL_THROTTLE_DELAY_MS: u32 = 100;
+
+// By default, once a thin dev has mapped this percent of its size,
+// extend it by THIN_EXTEND_PCT of its size.
+pub const THIN_EXTEND_THRESHOLD_PCT: u8 = 80;
+pub const THIN_EXTEND_PCT: u8 = 25;
+
+// Default thin pool metadata backup settings. Backups go in a
+// per-froyodev subdirectory of THIN_META_BACKUP_DIR.
+pub const THIN_META_BACKUP_DIR: &'static str = "/var/lib/froyo/thin-meta";
+pub const THIN_META_BACKUP_INTERVAL_SECS: u64 = 60 * 60;
+pub const THIN_META_BACKUP_KEEP: u32 = 8;
+
+// Held by the running daemon, so only one manages the disks
+pub const DAEMON_LOCK_PATH: &'static str = "/run/froyo.lock";
+
+// The daemon checks froyodevs this often, as well as when their dm
+// devices have events
+pub const STATE_CHECK_SECS: i64 = 30;
+
+// Finished D-Bus jobs stay around this long for clients to read
+pub const JOB_KEEP_SECS: i64 = 5 * 60;
+// How often the CLI checks on a job it is waiting for
+pub const JOB_POLL_MS: i64 = 500;
@@ -6,32 +6,226 @@ use std::rc::Rc;
 use std::cell::RefCell;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::collections::BTreeMap;
 use std::error::Error;
 
-use dbus::{Connection, NameFlag};
+use dbus::{Connection, NameFlag, RequestNameReply, Message};
 use dbus::tree::{Factory, Tree, Property, MethodFn, MethodErr, EmitsChangedSignal, Interface};
 use dbus::MessageItem;
 
-use froyo::Froyo;
-use blockdev::{BlockMember, BlockDevs};
-use types::FroyoResult;
+use froyo::{Froyo, FroyoAvailable, ReshapeProgress, ReshapeBlocker};
+use blockdev::{BlockDev, BlockMember, BlockDevs};
+use thin::{ThinSizePolicy, MetaBackupPolicy, ThinPoolOptions, DiscardMode, ThinStatus};
+use raid::{RecoveryRate, RecoveryRatePolicy, RecoverySchedule, RaidMember, RaidStatus};
+use types::{FroyoResult, FroyoError, FroyoErrorKind, InternalError, Sectors};
+use jobs::{Jobs, JobStep, JobStatus, JobCancel};
+use auth::{self, Authorizer, ACTION_CREATE, ACTION_DESTROY, ACTION_MODIFY,
+           ACTION_RESHAPE};
+use uevent::{Uevent, UeventAction};
+use consts::REDUNDANCY;
+
+const FROYODEVS_PATH: &'static str = "/org/freedesktop/froyodevs";
+const FROYODEV_IFACE: &'static str = "org.freedesktop.FroyoDevice1";
 
 #[derive(Debug, Clone)]
 pub struct DbusContext<'a> {
+    conn: &'a Connection,
     name_prop: Arc<Property<MethodFn<'a>>>,
     pub remaining_prop: Arc<Property<MethodFn<'a>>>,
     pub total_prop: Arc<Property<MethodFn<'a>>>,
     pub status_prop: Arc<Property<MethodFn<'a>>>,
     pub running_status_prop: Arc<Property<MethodFn<'a>>>,
     pub block_devices_prop: Arc<Property<MethodFn<'a>>>,
+    pub discard_passdown_prop: Arc<Property<MethodFn<'a>>>,
+    pub reshape_progress_prop: Arc<Property<MethodFn<'a>>>,
+    pub reshape_blockers_prop: Arc<Property<MethodFn<'a>>>,
+}
+
+// Set a property, and emit PropertiesChanged if its value changed
+pub fn set_property<'a>(c: &Connection, prop: &Arc<Property<MethodFn<'a>>>, m: MessageItem)
+                    -> FroyoResult<()> {
+    if prop.get_value() == m {
+        return Ok(())
+    }
+
+    let signals = try!(prop.set_value(m)
+                       .map_err(|_| FroyoError::Froyo(InternalError(
+                           "Cannot change a constant property".into()))));
+    for s in signals {
+        // As in the main loop, there's nothing to do if this fails
+        let _ = c.send(s);
+    }
+    Ok(())
+}
+
+// A D-Bus error with the typed name of the error's kind, if it has one
+pub fn get_method_err(action: &str, err: &FroyoError) -> MethodErr {
+    let msg = format!("{} failed: {}", action, err.description());
+    match err.kind() {
+        Some(kind) => (kind.dbus_name(), msg).into(),
+        None => MethodErr::failed(&msg),
+    }
+}
+
+fn get_not_found_err(msg: &str) -> MethodErr {
+    (FroyoErrorKind::NotFound.dbus_name(), msg).into()
+}
+
+// The froyodev `key` names or is a uuid prefix of, and its index
+fn find_froyo<'a>(froyos: &[Rc<RefCell<Froyo<'a>>>], key: &str)
+                  -> FroyoResult<(Rc<RefCell<Froyo<'a>>>, usize)> {
+    let idx = {
+        let borrowed = froyos.iter().map(|f| f.borrow()).collect::<Vec<_>>();
+        let names_and_ids = borrowed.iter()
+            .map(|f| (&*f.name, &*f.id))
+            .collect::<Vec<_>>();
+        try!(Froyo::resolve(key, &names_and_ids))
+    };
+
+    Ok((froyos[idx].clone(), idx))
+}
+
+// Froyodev names must be unique, ignoring the one being renamed if
+// any. A new froyodev's first volume takes its name too, so then it
+// mustn't clash with an existing volume either.
+fn check_name_free(froyos: &[Rc<RefCell<Froyo>>], name: &str, renaming: Option<&str>)
+                   -> FroyoResult<()> {
+    for froyo in froyos {
+        let froyo = froyo.borrow();
+        if Some(&*froyo.id) == renaming {
+            continue
+        }
+
+        if froyo.name == name {
+            return Err(FroyoError::Typed(FroyoErrorKind::InUse, InternalError(
+                format!("Froyodev name \"{}\" is already in use", name).into())))
+        }
+        if renaming.is_none() && froyo.has_thin_dev(name) {
+            return Err(FroyoError::Typed(FroyoErrorKind::InUse, InternalError(
+                format!("Volume name \"{}\" is already in use", name).into())))
+        }
+    }
+
+    Ok(())
+}
+
+// Property names and values as a{sv}
+fn get_props_msgitem(props: Vec<(&str, MessageItem)>) -> MessageItem {
+    let entries = props.into_iter()
+        .map(|(name, value)| MessageItem::DictEntry(
+            Box::new(name.into()),
+            Box::new(MessageItem::Variant(Box::new(value)))))
+        .collect();
+
+    MessageItem::Array(entries, "{sv}".into())
+}
+
+// Create's thin pool options, from an a{sv}. Missing ones keep
+// their defaults.
+fn get_pool_options(item: &MessageItem) -> Result<ThinPoolOptions, MethodErr> {
+    let entries = match *item {
+        MessageItem::Array(ref entries, _) => entries,
+        ref x => return Err(MethodErr::invalid_arg(x)),
+    };
+
+    let mut options = ThinPoolOptions::default();
+    for entry in entries {
+        let (key, value) = match *entry {
+            MessageItem::DictEntry(ref key, ref value) => match (&**key, &**value) {
+                (&MessageItem::Str(ref key), &MessageItem::Variant(ref value)) =>
+                    (key, &**value),
+                _ => return Err(MethodErr::invalid_arg(entry)),
+            },
+            _ => return Err(MethodErr::invalid_arg(entry)),
+        };
+
+        match &**key {
+            "DataBlockSectors" => {
+                let sectors: u64 = try!(value.inner()
+                                        .map_err(|_| MethodErr::invalid_arg(value)));
+                options.data_block_size = Sectors(sectors);
+            },
+            "ZeroBlocks" => {
+                options.zero_blocks = try!(value.inner()
+                                           .map_err(|_| MethodErr::invalid_arg(value)));
+            },
+            "Discard" => {
+                let discard: &str = try!(value.inner()
+                                         .map_err(|_| MethodErr::invalid_arg(value)));
+                options.discard = try!(DiscardMode::parse(discard)
+                                       .map_err(|err| get_method_err("Froyo create", &err)));
+            },
+            "ErrorIfNoSpace" => {
+                options.error_if_no_space = try!(value.inner()
+                                                 .map_err(|_| MethodErr::invalid_arg(value)));
+            },
+            _ => return Err(MethodErr::invalid_arg(key)),
+        }
+    }
+
+    Ok(options)
+}
+
+// ObjectManager signal for a newly registered object
+fn get_interfaces_added_signal(path: &str, iface: &str, props: MessageItem) -> Message {
+    let no_props = || MessageItem::Array(Vec::new(), "{sv}".into());
+    let ifaces = vec![
+        (iface, props),
+        ("org.freedesktop.DBus.Introspectable", no_props()),
+        ("org.freedesktop.DBus.Properties", no_props()),
+        ];
+    let ifaces = ifaces.into_iter()
+        .map(|(name, props)| MessageItem::DictEntry(
+            Box::new(name.into()), Box::new(props)))
+        .collect();
+
+    Message::signal(&FROYODEVS_PATH.into(),
+                    &"org.freedesktop.DBus.ObjectManager".into(),
+                    &"InterfacesAdded".into())
+        .append(MessageItem::ObjectPath(path.to_owned().into()))
+        .append(MessageItem::Array(ifaces, "{sa{sv}}".into()))
+}
+
+// ObjectManager signal for an object that went away
+fn get_interfaces_removed_signal(path: &str, iface: &str) -> Message {
+    let ifaces = [iface,
+                  "org.freedesktop.DBus.Introspectable",
+                  "org.freedesktop.DBus.Properties"];
+    let ifaces = ifaces.iter().map(|&i| i.into()).collect();
+
+    Message::signal(&FROYODEVS_PATH.into(),
+                    &"org.freedesktop.DBus.ObjectManager".into(),
+                    &"InterfacesRemoved".into())
+        .append(MessageItem::ObjectPath(path.to_owned().into()))
+        .append(MessageItem::Array(ifaces, "s".into()))
 }
 
 impl<'a> DbusContext<'a> {
-    pub fn update_one(prop: &Arc<Property<MethodFn<'a>>>, m: MessageItem)
+    pub fn update_one(&self, prop: &Arc<Property<MethodFn<'a>>>, m: MessageItem)
                       -> FroyoResult<()> {
-        prop.set_value(m);
-        // TODO: result is signals we need to be sending for PropertyChanged???
-        Ok(())
+        set_property(self.conn, prop, m)
+    }
+
+    // ObjectManager signal for a newly registered froyodev object
+    pub fn get_interfaces_added_signal(&self, path: &str) -> Message {
+        let props = vec![
+            ("Name", self.name_prop.get_value()),
+            ("RemainingSectors", self.remaining_prop.get_value()),
+            ("TotalSectors", self.total_prop.get_value()),
+            ("Status", self.status_prop.get_value()),
+            ("RunningStatus", self.running_status_prop.get_value()),
+            ("BlockDevices", self.block_devices_prop.get_value()),
+            ("DiscardPassdown", self.discard_passdown_prop.get_value()),
+            ("ReshapeProgress", self.reshape_progress_prop.get_value()),
+            ("ReshapeBlockers", self.reshape_blockers_prop.get_value()),
+            ];
+
+        get_interfaces_added_signal(path, FROYODEV_IFACE, get_props_msgitem(props))
+    }
+
+    // ObjectManager signal for a froyodev object that went away
+    pub fn get_interfaces_removed_signal(path: &str) -> Message {
+        get_interfaces_removed_signal(path, FROYODEV_IFACE)
     }
 
     pub fn get_block_devices_msgitem(block_devs: &BlockDevs)
@@ -58,17 +252,298 @@ impl<'a> DbusContext<'a> {
         MessageItem::new_array(msg_vec)
             .expect("Froyodev with no blockdev members???")
     }
+
+    pub fn get_reshape_blockers_msgitem(blockers: &[ReshapeBlocker])
+                                        -> MessageItem {
+        let msg_vec = blockers.iter()
+            .map(|b| {
+                let (value, limit) = b.values();
+                MessageItem::Struct(vec![
+                    b.kind().into(), value.into(), limit.into(), b.to_string().into()])
+            })
+            .collect();
+
+        MessageItem::Array(msg_vec, "(stts)".into())
+    }
+
+    // An empty phase means no reshape, and an eta of 0 means unknown
+    pub fn get_reshape_progress_msgitem(progress: Option<&ReshapeProgress>)
+                                        -> MessageItem {
+        match progress {
+            Some(p) => MessageItem::Struct(vec![
+                p.phase.into(),
+                (*p.step_done).into(),
+                (*p.step_total).into(),
+                (*p.overall_done).into(),
+                (*p.overall_total).into(),
+                p.eta_secs.unwrap_or(0).into()]),
+            None => MessageItem::Struct(vec![
+                "".into(), 0u64.into(), 0u64.into(),
+                0u64.into(), 0u64.into(), 0u64.into()]),
+        }
+    }
+}
+
+const BLOCKDEV_IFACE: &'static str = "org.freedesktop.FroyoBlockDevice1";
+const RAID_IFACE: &'static str = "org.freedesktop.FroyoRaid1";
+const VOLUME_IFACE: &'static str = "org.freedesktop.FroyoVolume1";
+
+// An object below a froyodev, and the property values it should have
+struct ComponentDesc {
+    path: String,
+    iface: &'static str,
+    props: Vec<(&'static str, MessageItem)>,
+}
+
+fn blockdev_path(froyo_path: &str, id: &str) -> String {
+    format!("{}/blockdevs/{}", froyo_path, id)
+}
+
+fn raid_path(froyo_path: &str, id: &str) -> String {
+    format!("{}/raids/{}", froyo_path, id)
+}
+
+fn get_blockdev_descs(froyo: &Froyo, froyo_path: &str) -> Vec<ComponentDesc> {
+    // Which raids is each blockdev a member of?
+    let mut bd_raids = BTreeMap::new();
+    for (rd_id, rd) in froyo.raids() {
+        for rm in &rd.borrow().members {
+            let bd_id = match *rm {
+                RaidMember::Present(ref ld) => match ld.borrow().parent.upgrade() {
+                    Some(bd) => bd.borrow().id.clone(),
+                    None => continue,
+                },
+                RaidMember::Absent((_, ref lds)) => lds.parent.clone(),
+                RaidMember::Removed => continue,
+            };
+            bd_raids.entry(bd_id).or_insert_with(Vec::new)
+                .push(MessageItem::ObjectPath(raid_path(froyo_path, rd_id).into()));
+        }
+    }
+
+    froyo.block_devs.0.iter()
+        .map(|(id, bm)| {
+            let raids = bd_raids.remove(id).unwrap_or_else(Vec::new);
+            let (path, sectors, present, free_areas) = match *bm {
+                BlockMember::Present(ref bd) => {
+                    let bd = bd.borrow();
+                    (bd.path.to_string_lossy().into_owned(), bd.sectors, true,
+                     bd.avail_areas())
+                },
+                BlockMember::Absent(ref sbd) =>
+                    (sbd.path.to_string_lossy().into_owned(), sbd.sectors, false,
+                     Vec::new()),
+            };
+            let role = match (present, raids.is_empty()) {
+                (false, _) => "absent",
+                (true, false) => "member",
+                (true, true) => "spare",
+            };
+            let free_sectors: Sectors = free_areas.iter().map(|&(_, len)| len).sum();
+            let free_areas = free_areas.iter()
+                .map(|&(start, len)| MessageItem::Struct(vec![
+                    (*start).into(), (*len).into()]))
+                .collect();
+
+            ComponentDesc {
+                path: blockdev_path(froyo_path, id),
+                iface: BLOCKDEV_IFACE,
+                props: vec![
+                    ("Id", id.to_owned().into()),
+                    ("Path", path.into()),
+                    ("Sectors", (*sectors).into()),
+                    ("Present", present.into()),
+                    ("Role", role.into()),
+                    ("FreeSectors", (*free_sectors).into()),
+                    ("FreeAreas", MessageItem::Array(free_areas, "(tt)".into())),
+                    ("Raids", MessageItem::Array(raids, "o".into())),
+                    ],
+            }
+        })
+        .collect()
+}
+
+fn get_raid_descs(froyo: &Froyo, froyo_path: &str) -> Vec<ComponentDesc> {
+    froyo.raids().iter()
+        .map(|(id, rd)| {
+            let rd = rd.borrow();
+
+            // Report what we can if the kernel won't tell us
+            let (health, degraded, action) = match rd.status() {
+                Ok((RaidStatus::Good, action)) => ("good", 0, action.name()),
+                Ok((RaidStatus::Degraded(x), action)) =>
+                    ("degraded", x as u32, action.name()),
+                Ok((RaidStatus::Failed, action)) => ("failed", 0, action.name()),
+                Err(_) => ("unknown", 0, "unknown"),
+            };
+            let (sync_done, sync_total) = rd.sync_progress()
+                .unwrap_or((Sectors(0), Sectors(0)));
+            let mismatches = rd.mismatch_count().unwrap_or(0);
+
+            let members = rd.members.iter()
+                .map(|rm| {
+                    let (bd_path, state) = match *rm {
+                        RaidMember::Present(ref ld) => match ld.borrow().parent.upgrade() {
+                            Some(bd) => (blockdev_path(froyo_path, &bd.borrow().id),
+                                         "present"),
+                            None => ("/".to_owned(), "present"),
+                        },
+                        RaidMember::Absent((_, ref lds)) =>
+                            (blockdev_path(froyo_path, &lds.parent), "absent"),
+                        RaidMember::Removed => ("/".to_owned(), "removed"),
+                    };
+                    MessageItem::Struct(vec![
+                        MessageItem::ObjectPath(bd_path.into()), state.into()])
+                })
+                .collect();
+
+            ComponentDesc {
+                path: raid_path(froyo_path, id),
+                iface: RAID_IFACE,
+                props: vec![
+                    ("Id", id.to_owned().into()),
+                    ("Sectors", (*rd.length).into()),
+                    ("AvailSectors", (*rd.avail_sectors()).into()),
+                    ("StripeSectors", (*rd.stripe_sectors).into()),
+                    ("RegionSectors", (*rd.region_sectors).into()),
+                    ("Health", health.into()),
+                    ("DegradedMembers", degraded.into()),
+                    ("SyncAction", action.into()),
+                    ("SyncProgress", MessageItem::Struct(vec![
+                        (*sync_done).into(), (*sync_total).into()])),
+                    ("MismatchCount", mismatches.into()),
+                    ("Members", MessageItem::Array(members, "(os)".into())),
+                    ],
+            }
+        })
+        .collect()
+}
+
+fn get_volume_descs(froyo: &Froyo, froyo_path: &str) -> Vec<ComponentDesc> {
+    froyo.thin_devs().iter()
+        .map(|td| {
+            let (health, mapped) = match td.status() {
+                Ok(ThinStatus::Good(mapped)) => ("good", mapped),
+                Ok(ThinStatus::Fail) => ("failed", Sectors(0)),
+                Err(_) => ("unknown", Sectors(0)),
+            };
+
+            ComponentDesc {
+                path: format!("{}/volumes/{}", froyo_path, td.thin_number),
+                iface: VOLUME_IFACE,
+                props: vec![
+                    ("Name", td.name.to_owned().into()),
+                    ("Number", td.thin_number.into()),
+                    ("DevNode", format!("/dev/froyo/{}", td.name).into()),
+                    ("Sectors", (*td.size).into()),
+                    ("MappedSectors", (*mapped).into()),
+                    ("Health", health.into()),
+                    ("Throttled", td.is_throttled().into()),
+                    ("PendingGrow", td.pending_grow.into()),
+                    ],
+            }
+        })
+        .collect()
+}
+
+struct ComponentObject<'a> {
+    iface: &'static str,
+    props: BTreeMap<&'static str, Arc<Property<MethodFn<'a>>>>,
+}
+
+// Objects for the blockdevs, raids and volumes within each froyodev.
+// These come and go as froyodevs change, and a tree can't be changed
+// while it's handling a message, so the main loop calls update()
+// between messages.
+#[derive(Default)]
+pub struct DbusComponents<'a> {
+    objects: BTreeMap<String, ComponentObject<'a>>,
 }
 
-fn froyo_interface<'a>(froyo: &Rc<RefCell<Froyo<'a>>>) -> Interface<MethodFn<'a>> {
+impl<'a> DbusComponents<'a> {
+    pub fn new() -> DbusComponents<'a> {
+        DbusComponents { objects: BTreeMap::new() }
+    }
+
+    // Add, update and remove objects in tree to match froyos
+    pub fn update(&mut self,
+                  c: &'a Connection,
+                  tree: &RefCell<Tree<MethodFn<'a>>>,
+                  froyos: &[Rc<RefCell<Froyo<'a>>>])
+                  -> FroyoResult<()> {
+        let mut descs = Vec::new();
+        for froyo in froyos {
+            let froyo = froyo.borrow();
+            let froyo_path = format!("{}/{}", FROYODEVS_PATH, froyo.id);
+            descs.extend(get_blockdev_descs(&froyo, &froyo_path));
+            descs.extend(get_raid_descs(&froyo, &froyo_path));
+            descs.extend(get_volume_descs(&froyo, &froyo_path));
+        }
+
+        let gone = self.objects.keys()
+            .filter(|path| !descs.iter().any(|d| d.path == **path))
+            .cloned()
+            .collect::<Vec<_>>();
+        for path in gone {
+            let obj = self.objects.remove(&path).unwrap();
+            c.unregister_object_path(&path);
+            tree.borrow_mut().remove(&path.clone().into());
+            let _ = c.send(get_interfaces_removed_signal(&path, obj.iface));
+        }
+
+        for desc in descs {
+            if let Some(obj) = self.objects.get(&desc.path) {
+                for (name, value) in desc.props {
+                    if let Some(prop) = obj.props.get(name) {
+                        try!(set_property(c, prop, value));
+                    }
+                }
+                continue
+            }
+
+            let f = Factory::new_fn();
+            let mut iface = f.interface(desc.iface);
+            let mut props = BTreeMap::new();
+            for &(name, ref value) in &desc.props {
+                props.insert(name, iface.add_p_ref(f.property(name, value.clone())));
+            }
+            let obj_path = f.object_path(desc.path.clone())
+                .introspectable()
+                .add(iface);
+
+            try!(c.register_object_path(&desc.path));
+            tree.borrow_mut().add_o_ref(obj_path);
+            let _ = c.send(get_interfaces_added_signal(
+                &desc.path, desc.iface, get_props_msgitem(desc.props)));
+
+            self.objects.insert(desc.path, ComponentObject {
+                iface: desc.iface,
+                props: props,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+fn froyo_interface<'a>(c: &'a Connection,
+                       froyo: &Rc<RefCell<Froyo<'a>>>,
+                       froyos: &Rc<RefCell<Vec<Rc<RefCell<Froyo<'a>>>>>>,
+                       auth: &Rc<Authorizer>,
+                       jobs: &Rc<RefCell<Jobs<'a>>>)
+                       -> Interface<MethodFn<'a>> {
     let f = Factory::new_fn();
-    let mut iface = f.interface("org.freedesktop.FroyoDevice1");
+    let mut iface = f.interface(FROYODEV_IFACE);
     let name_p = iface.add_p_ref(f.property(
         "Name", froyo.borrow().name.to_owned()));
+    let auth_closed_over = auth.clone();
     let p_closed_over = name_p.clone();
     let froyo_closed_over = froyo.clone();
+    let froyos_closed_over = froyos.clone();
     let mut iface = iface.add_m(
         f.method("SetName", move |m,_,_| {
+            try!(auth::check(&*auth_closed_over, m, ACTION_MODIFY));
+
             let mut items = m.get_items();
             if items.len() < 1 {
                 return Err(MethodErr::no_arg())
@@ -79,18 +554,21 @@ fn froyo_interface<'a>(froyo: &Rc<RefCell<Froyo<'a>>>) -> Interface<MethodFn<'a>
                                       .map_err(|_| MethodErr::invalid_arg(&i))
                                       .map(|i| i.to_owned())));
 
+            let id = froyo_closed_over.borrow().id.clone();
+            try!(Froyo::validate_name(&name)
+                 .and_then(|_| check_name_free(&froyos_closed_over.borrow(), &name, Some(&id)))
+                 .map_err(|err| get_method_err("SetName", &err)));
+
             let mut froyo = froyo_closed_over.borrow_mut();
             froyo.name = name.clone();
             try!(froyo.save_state()
-                 .map_err(|err| {
-                     let msg = format!("Saving state failed: {}",
-                                       err.description());
-                     MethodErr::failed(&msg)
-                 }));
-
-            try!(p_closed_over.set_value(name.into())
-                 .map_err(|_| MethodErr::invalid_arg(&"name")));
-            Ok(vec![m.method_return()])
+                 .map_err(|err| get_method_err("Saving state", &err)));
+
+            let signals = try!(p_closed_over.set_value(name.into())
+                               .map_err(|_| MethodErr::invalid_arg(&"name")));
+            let mut msgs = vec![m.method_return()];
+            msgs.extend(signals);
+            Ok(msgs)
         })
             .in_arg(("new_name", "s")));
 
@@ -100,10 +578,19 @@ fn froyo_interface<'a>(froyo: &Rc<RefCell<Froyo<'a>>>) -> Interface<MethodFn<'a>
                                 .emits_changed(EmitsChangedSignal::False));
     let status_p = iface.add_p_ref(f.property("Status", 0u32));
     let running_status_p = iface.add_p_ref(f.property("RunningStatus", 0u32));
+    let discard_passdown_p = iface.add_p_ref(f.property("DiscardPassdown", false));
+    let reshape_progress_p = iface.add_p_ref(f.property(
+        "ReshapeProgress", DbusContext::get_reshape_progress_msgitem(None)));
+    let reshape_blockers_p = iface.add_p_ref(f.property(
+        "ReshapeBlockers", DbusContext::get_reshape_blockers_msgitem(&[])));
 
+    let auth_closed_over = auth.clone();
     let froyo_closed_over = froyo.clone();
+    let jobs_closed_over = jobs.clone();
     let iface = iface.add_m(
         f.method("AddBlockDevice", move |m,_,_| {
+            try!(auth::check(&*auth_closed_over, m, ACTION_MODIFY));
+
             let mut items = m.get_items();
             if items.len() < 2 {
                 return Err(MethodErr::no_arg())
@@ -122,27 +609,51 @@ fn froyo_interface<'a>(froyo: &Rc<RefCell<Froyo<'a>>>) -> Interface<MethodFn<'a>
                               .map_err(|_| MethodErr::invalid_arg(&i))
                               .map(|i| i.to_owned())));
 
-            let mut froyo = froyo_closed_over.borrow_mut();
-            try!(froyo.add_block_device(Path::new(&new_dev), force)
-                 .map_err(|err| {
-                     let msg = format!("Adding block device failed: {}",
-                                       err.description());
-                     MethodErr::failed(&msg)
-                 }));
-            try!(froyo.save_state()
-                 .map_err(|err| {
-                     let msg = format!("Saving state failed: {}",
-                                       err.description());
-                     MethodErr::failed(&msg)
-                 }));
-            Ok(vec![m.method_return()])
+            let target = format!("{} {}", froyo_closed_over.borrow().name, new_dev);
+
+            // Initialize the blockdev, then set up raids on it
+            let prepared = Rc::new(RefCell::new(None));
+            let froyo = froyo_closed_over.clone();
+            let prepared_closed_over = prepared.clone();
+            let step: JobStep = Box::new(move || {
+                let mut froyo = froyo.borrow_mut();
+                let mut prepared = prepared_closed_over.borrow_mut();
+                match prepared.take() {
+                    None => {
+                        *prepared = Some(try!(
+                            froyo.prepare_block_device(Path::new(&new_dev), force)));
+                        Ok(JobStatus::Working(1, 2))
+                    },
+                    Some(bd) => {
+                        try!(froyo.add_prepared_block_device(bd));
+                        try!(froyo.save_state());
+                        Ok(JobStatus::Done(String::new()))
+                    },
+                }
+            });
+            let cancel: JobCancel = Box::new(move || {
+                match prepared.borrow_mut().take() {
+                    Some(bd) => bd.discard(),
+                    None => Ok(()),
+                }
+            });
+
+            let job_path = try!(jobs_closed_over.borrow_mut()
+                                .add("AddBlockDevice", &target, ACTION_MODIFY,
+                                     step, Some(cancel))
+                                .map_err(|err| get_method_err("Queueing job", &err)));
+            Ok(vec![m.method_return().append(job_path)])
         })
             .in_arg(("device_path", "s"))
-            .in_arg(("force", "b")));
+            .in_arg(("force", "b"))
+            .out_arg(("job_path", "s")));
 
+    let auth_closed_over = auth.clone();
     let froyo_closed_over = froyo.clone();
     let iface = iface.add_m(
         f.method("RemoveBlockDevice", move |m,_,_| {
+            try!(auth::check(&*auth_closed_over, m, ACTION_MODIFY));
+
             let mut items = m.get_items();
             if items.len() < 1 {
                 return Err(MethodErr::no_arg())
@@ -163,35 +674,324 @@ fn froyo_interface<'a>(froyo: &Rc<RefCell<Froyo<'a>>>) -> Interface<MethodFn<'a>
 
             let mut froyo = froyo_closed_over.borrow_mut();
             try!(froyo.remove_block_device(Path::new(&removing_dev), wipe)
-                 .map_err(|err| {
-                     let msg = format!("Removing block device failed: {}",
-                                       err.description());
-                     MethodErr::failed(&msg)
-                 }));
+                 .map_err(|err| get_method_err("Removing block device", &err)));
             try!(froyo.save_state()
-                 .map_err(|err| {
-                     let msg = format!("Saving state failed: {}",
-                                       err.description());
-                     MethodErr::failed(&msg)
-                 }));
+                 .map_err(|err| get_method_err("Saving state", &err)));
             Ok(vec![m.method_return()])
         })
             .in_arg(("device_path", "s"))
             .in_arg(("wipe", "b")));
 
+    let auth_closed_over = auth.clone();
     let froyo_closed_over = froyo.clone();
-    let mut iface = iface.add_m(
+    let iface = iface.add_m(
+        f.method("SetVolumeSizePolicy", move |m,_,_| {
+            try!(auth::check(&*auth_closed_over, m, ACTION_MODIFY));
+
+            let mut items = m.get_items();
+            if items.len() < 5 {
+                return Err(MethodErr::no_arg())
+            }
+
+            let max_sectors: u64 = try!(
+                items.pop()
+                    .ok_or_else(MethodErr::no_arg)
+                    .and_then(
+                        |i| i.inner().map_err(|_| MethodErr::invalid_arg(&i))));
+
+            let extend_pct: u8 = try!(
+                items.pop()
+                    .ok_or_else(MethodErr::no_arg)
+                    .and_then(
+                        |i| i.inner().map_err(|_| MethodErr::invalid_arg(&i))));
+
+            let threshold_pct: u8 = try!(
+                items.pop()
+                    .ok_or_else(MethodErr::no_arg)
+                    .and_then(
+                        |i| i.inner().map_err(|_| MethodErr::invalid_arg(&i))));
+
+            let auto_extend: bool = try!(
+                items.pop()
+                    .ok_or_else(MethodErr::no_arg)
+                    .and_then(
+                        |i| i.inner().map_err(|_| MethodErr::invalid_arg(&i))));
+
+            let volume = try!(
+                items.pop()
+                    .ok_or_else(MethodErr::no_arg)
+                    .and_then(|i| i.inner::<&str>()
+                              .map_err(|_| MethodErr::invalid_arg(&i))
+                              .map(|i| i.to_owned())));
+
+            let policy = ThinSizePolicy {
+                auto_extend: auto_extend,
+                threshold_pct: threshold_pct,
+                extend_pct: extend_pct,
+                max_size: Sectors(max_sectors),
+            };
+
+            let mut froyo = froyo_closed_over.borrow_mut();
+            try!(froyo.set_thin_size_policy(&volume, policy)
+                 .map_err(|err| get_method_err("Setting volume size policy", &err)));
+            Ok(vec![m.method_return()])
+        })
+            .in_arg(("volume", "s"))
+            .in_arg(("auto_extend", "b"))
+            .in_arg(("threshold_pct", "y"))
+            .in_arg(("extend_pct", "y"))
+            .in_arg(("max_sectors", "t")));
+
+    let auth_closed_over = auth.clone();
+    let froyo_closed_over = froyo.clone();
+    let iface = iface.add_m(
+        f.method("SetMetaBackupPolicy", move |m,_,_| {
+            try!(auth::check(&*auth_closed_over, m, ACTION_MODIFY));
+
+            let mut items = m.get_items();
+            if items.len() < 4 {
+                return Err(MethodErr::no_arg())
+            }
+
+            let keep: u32 = try!(
+                items.pop()
+                    .ok_or_else(MethodErr::no_arg)
+                    .and_then(
+                        |i| i.inner().map_err(|_| MethodErr::invalid_arg(&i))));
+
+            let interval_secs: u64 = try!(
+                items.pop()
+                    .ok_or_else(MethodErr::no_arg)
+                    .and_then(
+                        |i| i.inner().map_err(|_| MethodErr::invalid_arg(&i))));
+
+            let dir = try!(
+                items.pop()
+                    .ok_or_else(MethodErr::no_arg)
+                    .and_then(|i| i.inner::<&str>()
+                              .map_err(|_| MethodErr::invalid_arg(&i))
+                              .map(|i| i.to_owned())));
+
+            let enabled: bool = try!(
+                items.pop()
+                    .ok_or_else(MethodErr::no_arg)
+                    .and_then(
+                        |i| i.inner().map_err(|_| MethodErr::invalid_arg(&i))));
+
+            let policy = MetaBackupPolicy {
+                enabled: enabled,
+                dir: dir,
+                interval_secs: interval_secs,
+                keep: keep,
+            };
+
+            let mut froyo = froyo_closed_over.borrow_mut();
+            try!(froyo.set_meta_backup_policy(policy)
+                 .map_err(|err| get_method_err("Setting metadata backup policy", &err)));
+            Ok(vec![m.method_return()])
+        })
+            .in_arg(("enabled", "b"))
+            .in_arg(("dir", "s"))
+            .in_arg(("interval_secs", "t"))
+            .in_arg(("keep", "u")));
+
+    let auth_closed_over = auth.clone();
+    let froyo_closed_over = froyo.clone();
+    let iface = iface.add_m(
+        f.method("SetRecoveryRate", move |m,_,_| {
+            try!(auth::check(&*auth_closed_over, m, ACTION_MODIFY));
+
+            let mut items = m.get_items();
+            if items.len() < 7 {
+                return Err(MethodErr::no_arg())
+            }
+
+            let sched_max_kib: u64 = try!(
+                items.pop()
+                    .ok_or_else(MethodErr::no_arg)
+                    .and_then(
+                        |i| i.inner().map_err(|_| MethodErr::invalid_arg(&i))));
+
+            let sched_min_kib: u64 = try!(
+                items.pop()
+                    .ok_or_else(MethodErr::no_arg)
+                    .and_then(
+                        |i| i.inner().map_err(|_| MethodErr::invalid_arg(&i))));
+
+            let end_hour: u8 = try!(
+                items.pop()
+                    .ok_or_else(MethodErr::no_arg)
+                    .and_then(
+                        |i| i.inner().map_err(|_| MethodErr::invalid_arg(&i))));
+
+            let start_hour: u8 = try!(
+                items.pop()
+                    .ok_or_else(MethodErr::no_arg)
+                    .and_then(
+                        |i| i.inner().map_err(|_| MethodErr::invalid_arg(&i))));
+
+            let scheduled: bool = try!(
+                items.pop()
+                    .ok_or_else(MethodErr::no_arg)
+                    .and_then(
+                        |i| i.inner().map_err(|_| MethodErr::invalid_arg(&i))));
+
+            let max_kib: u64 = try!(
+                items.pop()
+                    .ok_or_else(MethodErr::no_arg)
+                    .and_then(
+                        |i| i.inner().map_err(|_| MethodErr::invalid_arg(&i))));
+
+            let min_kib: u64 = try!(
+                items.pop()
+                    .ok_or_else(MethodErr::no_arg)
+                    .and_then(
+                        |i| i.inner().map_err(|_| MethodErr::invalid_arg(&i))));
+
+            let policy = RecoveryRatePolicy {
+                rate: RecoveryRate {
+                    min_kib: min_kib,
+                    max_kib: max_kib,
+                },
+                schedule: if scheduled {
+                    Some(RecoverySchedule {
+                        start_hour: start_hour,
+                        end_hour: end_hour,
+                        rate: RecoveryRate {
+                            min_kib: sched_min_kib,
+                            max_kib: sched_max_kib,
+                        },
+                    })
+                } else {
+                    None
+                },
+            };
+
+            let mut froyo = froyo_closed_over.borrow_mut();
+            try!(froyo.set_recovery_rate_policy(policy)
+                 .map_err(|err| get_method_err("Setting recovery rate", &err)));
+            Ok(vec![m.method_return()])
+        })
+            .in_arg(("min_kib", "t"))
+            .in_arg(("max_kib", "t"))
+            .in_arg(("scheduled", "b"))
+            .in_arg(("start_hour", "y"))
+            .in_arg(("end_hour", "y"))
+            .in_arg(("sched_min_kib", "t"))
+            .in_arg(("sched_max_kib", "t")));
+
+    let auth_closed_over = auth.clone();
+    let froyo_closed_over = froyo.clone();
+    let jobs_closed_over = jobs.clone();
+    let iface = iface.add_m(
         f.method("Reshape", move |m,_,_| {
+            try!(auth::check(&*auth_closed_over, m, ACTION_RESHAPE));
+
+            // Fail now, rather than in the job, if it can't start
+            let target = {
+                let froyo = froyo_closed_over.borrow();
+                try!(froyo.check_reshapable()
+                     .map_err(|err| get_method_err("Reshape", &err)));
+                froyo.name.clone()
+            };
+
+            // Start the reshape, then follow it until it stops
+            let froyo = froyo_closed_over.clone();
+            let mut started = false;
+            let step: JobStep = Box::new(move || {
+                let mut froyo = froyo.borrow_mut();
+                if !started {
+                    started = true;
+                    try!(froyo.reshape());
+                }
+
+                match try!(froyo.reshape_progress()) {
+                    Some(p) => Ok(JobStatus::Waiting(*p.overall_done, *p.overall_total)),
+                    None if froyo.reshape_cancelled() => Ok(JobStatus::Cancelled),
+                    None => Ok(JobStatus::Done(String::new())),
+                }
+            });
+            let froyo = froyo_closed_over.clone();
+            let cancel: JobCancel = Box::new(move || {
+                froyo.borrow_mut().reshape_cancel()
+            });
+
+            let job_path = try!(jobs_closed_over.borrow_mut()
+                                .add("Reshape", &target, ACTION_RESHAPE,
+                                     step, Some(cancel))
+                                .map_err(|err| get_method_err("Queueing job", &err)));
+            Ok(vec![m.method_return().append(job_path)])
+        })
+            .out_arg(("job_path", "s")));
+
+    let auth_closed_over = auth.clone();
+    let froyo_closed_over = froyo.clone();
+    let iface = iface.add_m(
+        f.method("ReshapePause", move |m,_,_| {
+            try!(auth::check(&*auth_closed_over, m, ACTION_RESHAPE));
+
+            let mut froyo = froyo_closed_over.borrow_mut();
+            try!(froyo.reshape_pause()
+                 .map_err(|err| get_method_err("Pausing reshape", &err)));
+            Ok(vec![m.method_return()])
+        }));
+
+    let auth_closed_over = auth.clone();
+    let froyo_closed_over = froyo.clone();
+    let iface = iface.add_m(
+        f.method("ReshapeResume", move |m,_,_| {
+            try!(auth::check(&*auth_closed_over, m, ACTION_RESHAPE));
+
+            let mut froyo = froyo_closed_over.borrow_mut();
+            try!(froyo.reshape_resume()
+                 .map_err(|err| get_method_err("Resuming reshape", &err)));
+            Ok(vec![m.method_return()])
+        }));
+
+    let auth_closed_over = auth.clone();
+    let froyo_closed_over = froyo.clone();
+    let iface = iface.add_m(
+        f.method("ReshapeCancel", move |m,_,_| {
+            try!(auth::check(&*auth_closed_over, m, ACTION_RESHAPE));
+
             let mut froyo = froyo_closed_over.borrow_mut();
-            try!(froyo.reshape()
-                 .map_err(|err| {
-                     let msg = format!("Reshape failed: {}",
-                                       err.description());
-                     MethodErr::failed(&msg)
-                 }));
+            try!(froyo.reshape_cancel()
+                 .map_err(|err| get_method_err("Cancelling reshape", &err)));
             Ok(vec![m.method_return()])
         }));
 
+    let froyo_closed_over = froyo.clone();
+    let mut iface = iface.add_m(
+        f.method("ReshapePlan", move |m,_,_| {
+            let froyo = froyo_closed_over.borrow();
+            let blockers = froyo.reshape_blockers();
+            let plan = froyo.plan_reshape();
+
+            let blockers_msg = MessageItem::Array(
+                blockers.iter().map(|b| b.to_string().into()).collect(),
+                "s".into());
+            let steps_msg = MessageItem::Array(
+                plan.steps.iter()
+                    .map(|step| MessageItem::Struct(vec![
+                        step.kind().into(),
+                        step.to_string().into(),
+                        (*step.sectors()).into()]))
+                    .collect(),
+                "(sst)".into());
+            let error = plan.error.unwrap_or_else(String::new);
+
+            let mr = m.method_return()
+                .append(blockers.is_empty())
+                .append(blockers_msg)
+                .append(steps_msg)
+                .append(error);
+            Ok(vec![mr])
+        })
+            .out_arg(("reshapable", "b"))
+            .out_arg(("blockers", "as"))
+            .out_arg(("steps", "a(sst)"))
+            .out_arg(("error", "s")));
+
     let mut froyo = froyo.borrow_mut();;
 
     // Need to actually get values b/c I can't figure out how to
@@ -200,37 +1000,218 @@ fn froyo_interface<'a>(froyo: &Rc<RefCell<Froyo<'a>>>) -> Interface<MethodFn<'a>
     let block_devices_p = iface.add_p_ref(f.property("BlockDevices", bdev_msg));
 
     froyo.dbus_context = Some(DbusContext {
+        conn: c,
         name_prop: name_p,
         remaining_prop: rem_p,
         total_prop: tot_p,
         status_prop: status_p,
         running_status_prop: running_status_p,
         block_devices_prop: block_devices_p,
+        discard_passdown_prop: discard_passdown_p,
+        reshape_progress_prop: reshape_progress_p,
+        reshape_blockers_prop: reshape_blockers_p,
     });
 
     iface
 }
 
+// Set up a froyodev found on disk and register it, unless its name
+// is already taken by an active froyodev or volume
+fn activate_froyo<'a>(c: &'a Connection,
+                      fa: &FroyoAvailable,
+                      froyos: &Rc<RefCell<Vec<Rc<RefCell<Froyo<'a>>>>>>,
+                      tree: &Rc<RefCell<Tree<MethodFn<'a>>>>,
+                      auth: &Rc<Authorizer>,
+                      jobs: &Rc<RefCell<Jobs<'a>>>)
+                      -> FroyoResult<String> {
+    try!(check_name_free(&froyos.borrow(), &fa.name, None));
+    let froyo = try!(Froyo::find_and_setup(&fa.id));
+    register_froyo(c, froyo, froyos, tree, auth, jobs)
+}
+
+// Add an object for a froyodev that was just created or set up, and
+// manage it from now on. Returns its object path.
+fn register_froyo<'a>(c: &'a Connection,
+                      froyo: Froyo<'a>,
+                      froyos: &Rc<RefCell<Vec<Rc<RefCell<Froyo<'a>>>>>>,
+                      tree: &Rc<RefCell<Tree<MethodFn<'a>>>>,
+                      auth: &Rc<Authorizer>,
+                      jobs: &Rc<RefCell<Jobs<'a>>>)
+                      -> FroyoResult<String> {
+    let froyo = Rc::new(RefCell::new(froyo));
+
+    let f = Factory::new_fn();
+    let path = format!("{}/{}", FROYODEVS_PATH, froyo.borrow().id);
+    let obj_path = f.object_path(path.clone())
+        .introspectable()
+        .add(froyo_interface(c, &froyo, froyos, auth, jobs));
+
+    try!(froyo.borrow().update_dbus());
+    try!(c.register_object_path(&path));
+    tree.borrow_mut().add_o_ref(obj_path);
+
+    if let Some(ref dc) = froyo.borrow().dbus_context {
+        let _ = c.send(dc.get_interfaces_added_signal(&path));
+    }
+
+    froyos.borrow_mut().push(froyo);
+    Ok(path)
+}
+
+// Keep froyodevs up to date with block devices coming and going.
+// A member that comes back is re-added. A froyodev that isn't active
+// is set up once enough members are present to run degraded.
+pub fn handle_uevent<'a>(c: &'a Connection,
+                         event: &Uevent,
+                         froyos: &Rc<RefCell<Vec<Rc<RefCell<Froyo<'a>>>>>>,
+                         tree: &Rc<RefCell<Tree<MethodFn<'a>>>>,
+                         auth: &Rc<Authorizer>,
+                         jobs: &Rc<RefCell<Jobs<'a>>>)
+                         -> FroyoResult<()> {
+    match event.action {
+        UeventAction::Remove => {
+            for froyo in &*froyos.borrow() {
+                let mut froyo = froyo.borrow_mut();
+                let dev = match event.dev.or_else(|| froyo.present_member_dev(&event.path)) {
+                    Some(dev) => dev,
+                    None => continue,
+                };
+                if try!(froyo.block_device_vanished(dev)) {
+                    try!(froyo.save_state());
+                    try!(froyo.update_dbus());
+                    break
+                }
+            }
+        },
+        UeventAction::Add => {
+            // No Froyo signature, nothing to do
+            let bd = match BlockDev::setup(&event.path) {
+                Ok(bd) => bd,
+                Err(_) => return Ok(()),
+            };
+
+            let active = froyos.borrow().iter()
+                .find(|f| f.borrow().id == bd.froyodev_id)
+                .cloned();
+            match active {
+                Some(froyo) => {
+                    let mut froyo = froyo.borrow_mut();
+                    if froyo.is_absent_member(&bd) {
+                        dbgp!("{} member {} is back", froyo.name, event.path.display());
+                        try!(froyo.add_block_device(&event.path, false));
+                        try!(froyo.save_state());
+                        try!(froyo.update_dbus());
+                    }
+                },
+                None => {
+                    let available = try!(Froyo::find_available()).into_iter()
+                        .find(|fa| fa.id == bd.froyodev_id);
+                    if let Some(fa) = available {
+                        if fa.present_block_devs + REDUNDANCY >= fa.total_block_devs {
+                            let path = try!(activate_froyo(c, &fa, froyos, tree, auth, jobs));
+                            dbgp!("activated {} on hotplug", path);
+                        }
+                    }
+                },
+            }
+        },
+    }
+
+    Ok(())
+}
+
+// After uevents were lost, catch up on block devices that came or
+// went in the meantime.
+pub fn rescan_block_devs<'a>(c: &'a Connection,
+                             froyos: &Rc<RefCell<Vec<Rc<RefCell<Froyo<'a>>>>>>,
+                             tree: &Rc<RefCell<Tree<MethodFn<'a>>>>,
+                             auth: &Rc<Authorizer>,
+                             jobs: &Rc<RefCell<Jobs<'a>>>)
+                             -> FroyoResult<()> {
+    let found = try!(BlockDev::find_all());
+
+    for froyo in &*froyos.borrow() {
+        let mut froyo = froyo.borrow_mut();
+        let gone = froyo.block_devs.0.values()
+            .filter_map(|bm| match *bm {
+                BlockMember::Present(ref bd) => Some(bd.borrow().dev),
+                BlockMember::Absent(_) => None,
+            })
+            .filter(|dev| !found.iter().any(|bd| bd.dev == *dev))
+            .collect::<Vec<_>>();
+        let back = found.iter()
+            .filter(|bd| froyo.is_absent_member(bd))
+            .collect::<Vec<_>>();
+        if gone.is_empty() && back.is_empty() {
+            continue
+        }
+
+        for dev in gone {
+            try!(froyo.block_device_vanished(dev));
+        }
+        for bd in back {
+            dbgp!("{} member {} is back", froyo.name, bd.path.display());
+            try!(froyo.add_block_device(&bd.path, false));
+        }
+        try!(froyo.save_state());
+        try!(froyo.update_dbus());
+    }
+
+    let active_ids = froyos.borrow().iter()
+        .map(|f| f.borrow().id.clone())
+        .collect::<Vec<_>>();
+    for fa in try!(Froyo::find_available()) {
+        if active_ids.contains(&fa.id)
+            || fa.present_block_devs + REDUNDANCY < fa.total_block_devs {
+            continue
+        }
+        match activate_froyo(c, &fa, froyos, tree, auth, jobs) {
+            Ok(path) => dbgp!("activated {} on rescan", path),
+            Err(err) => dbgp!("Not activating froyodev {}: {}",
+                              fa.name, err.description()),
+        }
+    }
+
+    Ok(())
+}
+
 pub fn get_base_tree<'a>(
     c: &'a Connection,
     froyos: &mut Rc<RefCell<Vec<Rc<RefCell<Froyo<'a>>>>>>,
-    child_tree: &Rc<RefCell<Tree<MethodFn<'a>>>>)
+    child_tree: &Rc<RefCell<Tree<MethodFn<'a>>>>,
+    auth: &Rc<Authorizer>,
+    jobs: &Rc<RefCell<Jobs<'a>>>)
     -> FroyoResult<Tree<MethodFn<'a>>> {
-    c.register_name("org.freedesktop.Froyo1", NameFlag::ReplaceExisting as u32).unwrap();
+    // Don't take over from another daemon
+    match try!(c.register_name("org.freedesktop.Froyo1", NameFlag::DoNotQueue as u32)) {
+        RequestNameReply::PrimaryOwner | RequestNameReply::AlreadyOwner => {},
+        _ => return Err(FroyoError::Typed(FroyoErrorKind::InUse, InternalError(
+            "Another Froyo daemon owns org.freedesktop.Froyo1".into()))),
+    }
 
     let f = Factory::new_fn();
 
     let base_tree = f.tree();
 
+    let auth_closed_over = auth.clone();
     let tree_closed_over = child_tree.clone();
     let froyos_closed_over = froyos.clone();
+    let jobs_closed_over = jobs.clone();
     let create_method = f.method("Create", move |m,_,_| {
-        let f = Factory::new_fn();
+        try!(auth::check(&*auth_closed_over, m, ACTION_CREATE));
+
         let mut items = m.get_items();
         if items.len() < 3 {
             return Err(MethodErr::no_arg())
         }
 
+        // Callers that don't tune the thin pool may leave the options out
+        let options = match items.len() {
+            3 => ThinPoolOptions::default(),
+            _ => try!(items.pop().ok_or_else(MethodErr::no_arg)
+                      .and_then(|i| get_pool_options(&i))),
+        };
+
         let force: bool = try!(items.pop().ok_or_else(MethodErr::no_arg)
                                .and_then(|i| i.inner()
                                          .map_err(|_| MethodErr::invalid_arg(&i))));
@@ -247,55 +1228,63 @@ pub fn get_base_tree<'a>(
                                   .map_err(|_| MethodErr::invalid_arg(&i))
                                   .map(|i| i.to_owned())));
 
-        let froyo = match Froyo::new(&name, &blockdevs, force) {
-            Ok(x) => x,
-            Err(err) => {
-                let msg = format!("Froyo create failed: {}", err.description());
-                return Err(MethodErr::failed(&msg))
-            },
-        };
-        try!(froyo.save_state()
-             .map_err(|err| {
-                 let msg = format!("Saving state failed: {}",
-                                   err.description());
-                 MethodErr::failed(&msg)
-             }));
-
-        let froyo = Rc::new(RefCell::new(froyo));
-
-        let path = format!("/org/freedesktop/froyodevs/{}", froyo.borrow().id);
-        let obj_path = f.object_path(path.clone())
-            .introspectable()
-            .add(froyo_interface(&froyo));
-
-        try!(froyo.borrow().update_dbus()
-             .map_err(|err| {
-                 let msg = format!("Updating DBus failed: {}",
-                                   err.description());
-                 MethodErr::failed(&msg)
-             }));
-
-        try!(c.register_object_path(&path)
-             .map_err(|err| {
-                 let msg = format!("registering object path failed: {}",
-                                   err.description());
-                 MethodErr::failed(&msg)
-             }));
-        tree_closed_over.borrow_mut().add_o_ref(obj_path);
-
-        let mr = m.method_return().append(path);
-
-        froyos_closed_over.borrow_mut().push(froyo);
-        Ok(vec![mr])
+        // Fail now if we can, but check the name again when the job
+        // finishes, in case another job took it meanwhile.
+        try!(Froyo::validate_new(&name, blockdevs.len(), &options)
+             .and_then(|_| check_name_free(&froyos_closed_over.borrow(), &name, None))
+             .map_err(|err| get_method_err("Froyo create", &err)));
+
+        // Initialize each blockdev in turn, then set up the rest
+        let froyo_id = Froyo::new_id();
+        let initialized = Rc::new(RefCell::new(Vec::new()));
+        let auth = auth_closed_over.clone();
+        let tree = tree_closed_over.clone();
+        let froyos = froyos_closed_over.clone();
+        let jobs = jobs_closed_over.clone();
+        let job_name = name.clone();
+        let initialized_closed_over = initialized.clone();
+        let step: JobStep = Box::new(move || {
+            let mut initialized = initialized_closed_over.borrow_mut();
+            let total = blockdevs.len() as u64 + 1;
+            if initialized.len() < blockdevs.len() {
+                let path = &blockdevs[initialized.len()];
+                initialized.push(try!(BlockDev::new(&froyo_id, path, force)));
+                return Ok(JobStatus::Working(initialized.len() as u64, total))
+            }
+
+            try!(check_name_free(&froyos.borrow(), &job_name, None));
+            let bds = initialized.drain(..).collect();
+            let froyo = try!(Froyo::from_block_devs(&job_name, froyo_id.clone(), bds,
+                                                    options));
+            try!(froyo.save_state());
+
+            let path = try!(register_froyo(c, froyo, &froyos, &tree, &auth, &jobs));
+            Ok(JobStatus::Done(path))
+        });
+        let cancel: JobCancel = Box::new(move || {
+            for bd in initialized.borrow_mut().iter_mut() {
+                try!(bd.wipe_mda_header());
+            }
+            Ok(())
+        });
+
+        let job_path = try!(jobs_closed_over.borrow_mut()
+                            .add("Create", &name, ACTION_CREATE, step, Some(cancel))
+                            .map_err(|err| get_method_err("Queueing job", &err)));
+        Ok(vec![m.method_return().append(job_path)])
     })
         .in_arg(("name", "s"))
         .in_arg(("blockdevs", "as"))
         .in_arg(("force", "b"))
-        .out_arg(("obj_path", "s"));
+        .in_arg(("options", "a{sv}"))
+        .out_arg(("job_path", "s"));
 
+    let auth_closed_over = auth.clone();
     let tree_closed_over = child_tree.clone();
     let froyos_closed_over = froyos.clone();
     let destroy_method = f.method("Destroy", move |m,_,_| {
+        try!(auth::check(&*auth_closed_over, m, ACTION_DESTROY));
+
         let mut items = m.get_items();
         if items.len() < 1 {
             return Err(MethodErr::no_arg())
@@ -307,42 +1296,28 @@ pub fn get_base_tree<'a>(
                                   .map(|i| i.to_owned())));
 
         let mut froyos = froyos_closed_over.borrow_mut();
-        let mut froyos_to_destroy = froyos.iter().enumerate()
-            .filter_map(|(idx, f)| {
-                if f.borrow().name == name {
-                    Some((f.clone(), idx))
-                } else {
-                    None
-                }})
-            .collect::<Vec<_>>();
+        let (froyo, idx) = try!(find_froyo(&froyos, &name)
+                                .map_err(|err| get_method_err("Destroying Froyodev", &err)));
 
-        let (froyo, idx) = match froyos_to_destroy.len() {
-            0 => return Err(MethodErr::failed(&format!("Froyodev {} not found", name))),
-            1 => froyos_to_destroy.pop().unwrap(),
-            _ => return Err(MethodErr::failed(
-                &format!("Multiple Froydevs found with name: {}. \
-                          Specify froyodev uuid", name))),
-        };
-
-        let name = format!("/org/freedesktop/froyodevs/{}", froyo.borrow().id);
-        c.unregister_object_path(&name);
-        tree_closed_over.borrow_mut().remove(&name.into());
+        let path = format!("{}/{}", FROYODEVS_PATH, froyo.borrow().id);
+        c.unregister_object_path(&path);
+        tree_closed_over.borrow_mut().remove(&path.clone().into());
 
         try!(froyo.borrow_mut().destroy()
-             .map_err(|err| {
-                 let msg = format!("Destroying Froyodev failed: {}",
-                                   err.description());
-                 MethodErr::failed(&msg)}));
+             .map_err(|err| get_method_err("Destroying Froyodev", &err)));
 
         froyos.remove(idx);
 
-        Ok(vec![m.method_return()])
+        Ok(vec![m.method_return(), DbusContext::get_interfaces_removed_signal(&path)])
     })
         .in_arg(("name", "s"));
 
+    let auth_closed_over = auth.clone();
     let tree_closed_over = child_tree.clone();
     let froyos_closed_over = froyos.clone();
     let teardown_method = f.method("Teardown", move |m,_,_| {
+        try!(auth::check(&*auth_closed_over, m, ACTION_DESTROY));
+
         let mut items = m.get_items();
         if items.len() < 1 {
             return Err(MethodErr::no_arg())
@@ -354,46 +1329,168 @@ pub fn get_base_tree<'a>(
                                   .map(|i| i.to_owned())));
 
         let mut froyos = froyos_closed_over.borrow_mut();
-        let mut froyos_to_teardown = froyos.iter().enumerate()
-            .filter_map(|(idx, f)| {
-                if f.borrow().name == name {
-                    Some((f.clone(), idx))
-                } else {
-                    None
-                }})
-            .collect::<Vec<_>>();
+        let (froyo, idx) = try!(find_froyo(&froyos, &name)
+                                .map_err(|err| get_method_err("Tearing down Froyodev", &err)));
 
-        let (froyo, idx) = match froyos_to_teardown.len() {
-            0 => return Err(MethodErr::failed(&format!("Froyodev {} not found", name))),
-            1 => froyos_to_teardown.pop().unwrap(),
-            _ => return Err(MethodErr::failed(
-                &format!("Multiple Froydevs found with name: {}. \
-                          Specify froyodev uuid", name))),
-        };
-
-        let name = format!("/org/freedesktop/froyodevs/{}", froyo.borrow().id);
-        c.unregister_object_path(&name);
-        tree_closed_over.borrow_mut().remove(&name.into());
+        let path = format!("{}/{}", FROYODEVS_PATH, froyo.borrow().id);
+        c.unregister_object_path(&path);
+        tree_closed_over.borrow_mut().remove(&path.clone().into());
 
         try!(froyo.borrow_mut().teardown()
-             .map_err(|err| {
-                 let msg = format!("Tearing down Froyodev failed: {}",
-                                   err.description());
-                 MethodErr::failed(&msg)}));
+             .map_err(|err| get_method_err("Tearing down Froyodev", &err)));
 
         froyos.remove(idx);
 
-        Ok(vec![m.method_return()])
+        Ok(vec![m.method_return(), DbusContext::get_interfaces_removed_signal(&path)])
     })
         .in_arg(("name", "s"));
 
+    let auth_closed_over = auth.clone();
+    let froyos_closed_over = froyos.clone();
+    let trim_method = f.method("Trim", move |m,_,_| {
+        try!(auth::check(&*auth_closed_over, m, ACTION_MODIFY));
+
+        let mut items = m.get_items();
+        if items.len() < 1 {
+            return Err(MethodErr::no_arg())
+        }
+
+        let volume = try!(items.pop().ok_or_else(MethodErr::no_arg)
+                          .and_then(|i| i.inner::<&str>()
+                                    .map_err(|_| MethodErr::invalid_arg(&i))
+                                    .map(|i| i.to_owned())));
+
+        let froyos = froyos_closed_over.borrow();
+        let froyo = match froyos.iter().find(|f| f.borrow().has_thin_dev(&volume)) {
+            Some(f) => f,
+            None => return Err(get_not_found_err(&format!("Volume {} not found", volume))),
+        };
+
+        let mut froyo = froyo.borrow_mut();
+        let (before, after) = try!(froyo.trim(&volume)
+             .map_err(|err| get_method_err("Trimming volume", &err)));
+
+        try!(froyo.update_dbus()
+             .map_err(|err| get_method_err("Updating DBus", &err)));
+
+        let mr = m.method_return()
+            .append(*before)
+            .append(*after)
+            .append(froyo.data_block_size());
+        Ok(vec![mr])
+    })
+        .in_arg(("volume", "s"))
+        .out_arg(("used_blocks_before", "t"))
+        .out_arg(("used_blocks_after", "t"))
+        .out_arg(("block_size", "t"));
+
+    let froyos_closed_over = froyos.clone();
+    let list_available_method = f.method("ListAvailable", move |m,_,_| {
+        let available = try!(Froyo::find_available()
+                             .map_err(|err| get_method_err("Scanning for froyodevs", &err)));
+
+        let froyos = froyos_closed_over.borrow();
+        let entries = available.into_iter()
+            .map(|fa| {
+                let active = froyos.iter().any(|f| f.borrow().id == fa.id);
+                MessageItem::Struct(vec![
+                    fa.id.into(),
+                    fa.name.into(),
+                    (fa.present_block_devs as u32).into(),
+                    (fa.total_block_devs as u32).into(),
+                    active.into()])
+            })
+            .collect::<Vec<_>>();
+
+        Ok(vec![m.method_return().append(MessageItem::Array(entries, "(ssuub)".into()))])
+    })
+        .out_arg(("froyodevs", "a(ssuub)"));
+
+    let auth_closed_over = auth.clone();
+    let tree_closed_over = child_tree.clone();
+    let froyos_closed_over = froyos.clone();
+    let jobs_closed_over = jobs.clone();
+    let activate_method = f.method("Activate", move |m,_,_| {
+        try!(auth::check(&*auth_closed_over, m, ACTION_CREATE));
+
+        let mut items = m.get_items();
+        if items.len() < 1 {
+            return Err(MethodErr::no_arg())
+        }
+
+        let name = try!(items.pop().ok_or_else(MethodErr::no_arg)
+                        .and_then(|i| i.inner::<&str>()
+                                  .map_err(|_| MethodErr::invalid_arg(&i))
+                                  .map(|i| i.to_owned())));
+
+        match find_froyo(&froyos_closed_over.borrow(), &name) {
+            Ok((froyo, _)) => {
+                let msg = format!("Froyodev {} is already active", froyo.borrow().name);
+                return Err((FroyoErrorKind::InUse.dbus_name(), msg).into())
+            },
+            Err(ref err) if err.kind() == Some(FroyoErrorKind::NotFound) => {},
+            Err(err) => return Err(get_method_err("Activating Froyodev", &err)),
+        }
+
+        let mut available = try!(Froyo::find_available()
+                                 .map_err(|err| get_method_err("Activating Froyodev", &err)));
+        let idx = {
+            let names_and_ids = available.iter()
+                .map(|fa| (&*fa.name, &*fa.id))
+                .collect::<Vec<_>>();
+            try!(Froyo::resolve(&name, &names_and_ids)
+                 .map_err(|err| get_method_err("Activating Froyodev", &err)))
+        };
+        let fa = available.swap_remove(idx);
+
+        let path = try!(activate_froyo(c, &fa, &froyos_closed_over, &tree_closed_over,
+                                       &auth_closed_over, &jobs_closed_over)
+                        .map_err(|err| get_method_err("Activating Froyodev", &err)));
+
+        Ok(vec![m.method_return().append(path)])
+    })
+        .in_arg(("name", "s"))
+        .out_arg(("object_path", "s"));
+
+    let auth_closed_over = auth.clone();
+    let tree_closed_over = child_tree.clone();
+    let froyos_closed_over = froyos.clone();
+    let jobs_closed_over = jobs.clone();
+    let rescan_method = f.method("Rescan", move |m,_,_| {
+        try!(auth::check(&*auth_closed_over, m, ACTION_CREATE));
+
+        let active_ids = froyos_closed_over.borrow().iter()
+            .map(|f| f.borrow().id.clone())
+            .collect::<Vec<_>>();
+        let available = try!(Froyo::find_available()
+                             .map_err(|err| get_method_err("Scanning for froyodevs", &err)));
+
+        // Skip ones that can't be activated, like find_all() does
+        let mut paths = Vec::new();
+        for fa in available.iter().filter(|fa| !active_ids.contains(&fa.id)) {
+            match activate_froyo(c, fa, &froyos_closed_over, &tree_closed_over,
+                                 &auth_closed_over, &jobs_closed_over) {
+                Ok(path) => paths.push(path.into()),
+                Err(err) => dbgp!("Not activating froyodev {}: {}",
+                                  fa.name, err.description()),
+            }
+        }
+
+        Ok(vec![m.method_return().append(MessageItem::Array(paths, "s".into()))])
+    })
+        .out_arg(("object_paths", "as"));
+
     let obj_path = f.object_path("/org/freedesktop/froyo")
         .introspectable()
         .object_manager()
         .add(f.interface("org.freedesktop.FroyoService1")
              .add_m(create_method)
              .add_m(destroy_method)
-             .add_m(teardown_method));
+             .add_m(teardown_method)
+             .add_m(trim_method)
+             .add_m(list_available_method)
+             .add_m(activate_method)
+             .add_m(rescan_method));
 
     let base_tree = base_tree.add(obj_path);
     try!(base_tree.set_registered(c, true));
@@ -401,27 +1498,30 @@ pub fn get_base_tree<'a>(
     Ok(base_tree)
 }
 
-pub fn get_child_tree<'a>(c: &'a Connection, froyos: &[Rc<RefCell<Froyo<'a>>>])
-                       -> FroyoResult<Rc<RefCell<Tree<MethodFn<'a>>>>> {
+pub fn get_child_tree<'a>(c: &'a Connection,
+                          froyos: &Rc<RefCell<Vec<Rc<RefCell<Froyo<'a>>>>>>,
+                          auth: &Rc<Authorizer>,
+                          jobs: &Rc<RefCell<Jobs<'a>>>)
+                          -> FroyoResult<Rc<RefCell<Tree<MethodFn<'a>>>>> {
     let f = Factory::new_fn();
 
-    let tree = froyos
+    let tree = froyos.borrow()
         .iter()
         .fold(f.tree(), |tree, froyo| {
-            let path = format!("/org/freedesktop/froyodevs/{}", froyo.borrow().id);
+            let path = format!("{}/{}", FROYODEVS_PATH, froyo.borrow().id);
             let obj_path = f.object_path(path)
                 .introspectable()
-                .add(froyo_interface(froyo));
+                .add(froyo_interface(c, froyo, froyos, auth, jobs));
             tree.add(obj_path)
         });
 
-    let tree = tree.add(f.object_path("/org/freedesktop/froyodevs")
+    let tree = tree.add(f.object_path(FROYODEVS_PATH)
                         .introspectable()
                         .object_manager());
 
     try!(tree.set_registered(c, true));
 
-    for froyo in &*froyos {
+    for froyo in &*froyos.borrow() {
         try!(froyo.borrow().update_dbus());
     }
 
@@ -3,6 +3,7 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 use std::borrow::Borrow;
+use std::cmp::min;
 use std::fs::OpenOptions;
 use std::io;
 use std::io::{Write, ErrorKind};
@@ -102,10 +103,13 @@ impl DmDevice {
             Ok(x) => x,
         };
 
-        let sectors = try!(blkdev_size(&f)) / SECTOR_SIZE;
-        let buf = vec![0u8; SECTOR_SIZE as usize];
-        for _ in 0..sectors {
-            try!(f.write(&buf));
+        // Write in big chunks, a sector at a time is very slow
+        let mut remaining = try!(blkdev_size(&f));
+        let buf = vec![0u8; CLEAR_CHUNK_BYTES as usize];
+        while remaining > 0 {
+            let len = min(remaining, CLEAR_CHUNK_BYTES);
+            try!(f.write_all(&buf[..len as usize]));
+            remaining -= len;
         }
 
         Ok(())
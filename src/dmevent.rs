@@ -0,0 +1,174 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Device-mapper event notifications. DM_DEV_WAIT blocks until a
+// device's event number moves on, so each watched device gets a
+// thread that waits on it and then wakes the main loop via a pipe.
+
+use std::cmp::min;
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::fs::File;
+use std::mem;
+use std::os::unix::io::{RawFd, AsRawFd};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender, Receiver};
+use std::thread;
+
+use nix;
+use nix::errno::{EAGAIN, EINTR};
+use nix::fcntl::{O_CLOEXEC, O_NONBLOCK};
+use nix::unistd::{pipe2, read, write, close};
+use devicemapper::{DM, DevId};
+
+use types::FroyoResult;
+
+const DM_CTL_PATH: &'static str = "/dev/mapper/control";
+const DM_NAME_LEN: usize = 128;
+const DM_UUID_LEN: usize = 129;
+
+// struct dm_ioctl, from linux/dm-ioctl.h
+#[repr(C)]
+struct DmIoctl {
+    version: [u32; 3],
+    data_size: u32,
+    data_start: u32,
+    target_count: u32,
+    open_count: i32,
+    flags: u32,
+    event_nr: u32,
+    padding: u32,
+    dev: u64,
+    name: [u8; DM_NAME_LEN],
+    uuid: [u8; DM_UUID_LEN],
+    data: [u8; 7],
+}
+
+ioctl!(readwrite dm_dev_wait with 0xfd, 8; DmIoctl);
+
+// Block until the event number of dm device `name` is no longer
+// event_nr, and return the new one. devicemapper's device_wait()
+// always waits for event 0, so do the ioctl ourselves.
+fn dev_wait(ctl: &File, name: &str, event_nr: u32) -> FroyoResult<u32> {
+    let mut hdr: DmIoctl = unsafe { mem::zeroed() };
+    hdr.version = [4, 0, 0];
+    hdr.data_size = mem::size_of::<DmIoctl>() as u32;
+    hdr.data_start = hdr.data_size;
+    hdr.event_nr = event_nr;
+    let len = min(name.len(), DM_NAME_LEN - 1);
+    hdr.name[..len].clone_from_slice(&name.as_bytes()[..len]);
+
+    loop {
+        match unsafe { dm_dev_wait(ctl.as_raw_fd(), &mut hdr) } {
+            Ok(_) => return Ok(hdr.event_nr),
+            Err(nix::Error::Sys(EINTR)) => {},
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+// Closed once the main loop and all waiting threads are done with it
+struct WakePipe {
+    read_fd: RawFd,
+    write_fd: RawFd,
+}
+
+impl WakePipe {
+    fn wake(&self) {
+        // If the pipe is full, the main loop is going to wake anyway
+        let _ = write(self.write_fd, &[0]);
+    }
+}
+
+impl Drop for WakePipe {
+    fn drop(&mut self) {
+        let _ = close(self.read_fd);
+        let _ = close(self.write_fd);
+    }
+}
+
+// Runs until the device goes away
+fn wait_for_events(name: &str, wake: &WakePipe, tx: &Sender<String>) -> FroyoResult<()> {
+    let dm = try!(DM::new());
+    let ctl = try!(File::open(DM_CTL_PATH));
+
+    let mut event_nr = try!(dm.device_status(&DevId::Name(name))).event_nr();
+    loop {
+        event_nr = try!(dev_wait(&ctl, name, event_nr));
+        dbgp!("dm event {} on {}", event_nr, name);
+        if tx.send(name.to_owned()).is_err() {
+            return Ok(())
+        }
+        wake.wake();
+    }
+}
+
+pub struct DmEvents {
+    wake: Arc<WakePipe>,
+    tx: Sender<String>,
+    rx: Receiver<String>,
+    watching: Arc<Mutex<BTreeSet<String>>>,
+}
+
+impl DmEvents {
+    pub fn new() -> FroyoResult<DmEvents> {
+        let (read_fd, write_fd) = try!(pipe2(O_CLOEXEC | O_NONBLOCK));
+        let (tx, rx) = channel();
+
+        Ok(DmEvents {
+            wake: Arc::new(WakePipe { read_fd: read_fd, write_fd: write_fd }),
+            tx: tx,
+            rx: rx,
+            watching: Arc::new(Mutex::new(BTreeSet::new())),
+        })
+    }
+
+    // Readable when there are events
+    pub fn fd(&self) -> RawFd {
+        self.wake.read_fd
+    }
+
+    // Start waiting on events for any of these dm devices we aren't
+    // already. Waiting stops by itself when a device is removed.
+    pub fn watch(&self, names: &[String]) {
+        let mut watching = self.watching.lock().unwrap();
+        for name in names {
+            if !watching.insert(name.clone()) {
+                continue
+            }
+
+            let name = name.clone();
+            let wake = self.wake.clone();
+            let tx = self.tx.clone();
+            let watching = self.watching.clone();
+            thread::spawn(move || {
+                if let Err(e) = wait_for_events(&name, &wake, &tx) {
+                    dbgp!("Stopped waiting for {} events: {}", name, e.description());
+                }
+                watching.lock().unwrap().remove(&name);
+            });
+        }
+    }
+
+    // Names of dm devices that had events since last called. Doesn't
+    // wait.
+    pub fn pending(&self) -> FroyoResult<BTreeSet<String>> {
+        let mut buf = [0u8; 64];
+        loop {
+            match read(self.wake.read_fd, &mut buf) {
+                Ok(0) => break,
+                Ok(_) => {},
+                Err(nix::Error::Sys(EAGAIN)) => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        let mut names = BTreeSet::new();
+        while let Ok(name) = self.rx.try_recv() {
+            names.insert(name);
+        }
+
+        Ok(names)
+    }
+}
@@ -6,27 +6,31 @@ use std::collections::BTreeMap;
 use std::rc::Rc;
 use std::cell::RefCell;
 use std::borrow;
-use std::path::Path;
-use std::cmp::{Ordering, max};
+use std::path::{Path, PathBuf};
+use std::cmp::{Ordering, max, min};
 use std::io;
 use std::io::ErrorKind;
 use std::error::Error;
+use std::fmt;
 
 use uuid::Uuid;
-use devicemapper::DM;
+use devicemapper::{DM, DevId, Device};
 use serde_json;
 use time;
 use bytesize::ByteSize;
 
 use blockdev::{BlockDev, BlockDevs, BlockDevSave, BlockMember};
 use blockdev::LinearSegment;
-use raid::{RaidDevs, RaidDevSave, RaidSegment, RaidLinearDev, RaidStatus,
-           RaidAction, RaidMember, RaidLayer};
+use raid::{RaidDev, RaidDevs, RaidDevSave, RaidSegment, RaidLinearDev, RaidStatus,
+           RaidAction, RaidMember, RaidLayer, RecoveryRate, RecoveryRatePolicy};
 use thin::{ThinPoolDev, ThinPoolDevSave, ThinPoolStatus, ThinPoolWorkingStatus};
-use thin::{ThinDev, ThinDevSave, ThinStatus};
-use mirror::{MirrorDev, TempDev, TempDevSave, TempLayer};
-use types::{Sectors, SectorOffset, DataBlocks, FroyoError, FroyoResult, InternalError};
+use thin::{MetaBackupPolicy, ThinPoolBlockUsage, ThinPoolOptions};
+use thin::{ThinDev, ThinDevSave, ThinStatus, ThinSizePolicy};
+use mirror::{MirrorDev, MirrorDevSave, TempDev, TempDevSave, TempLayer};
+use types::{Sectors, SectorOffset, DataBlocks, FroyoError, FroyoErrorKind, FroyoResult,
+            InternalError};
 use dbus_api::DbusContext;
+use plan::{self, ReshapeModel, ReshapePlan, ReshapeSteps, ThinPoolPart};
 use util::short_id;
 use consts::*;
 
@@ -40,6 +44,123 @@ pub struct FroyoSave {
     pub thin_devs: Vec<ThinDevSave>,
     #[serde(skip_serializing_if="Option::is_none")]
     pub temp_dev: Option<TempDevSave>,
+    #[serde(default)]
+    pub meta_backup: MetaBackupPolicy,
+    #[serde(default)]
+    pub recovery_rate: RecoveryRatePolicy,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub reshape: Option<ReshapeSave>,
+}
+
+// The reshape step in progress, so we can pick it back up after a
+// restart.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ReshapeStepSave {
+    Idle,
+    CopyingToRaid(MirrorDevSave),
+    CopyingToScratch(MirrorDevSave),
+    CopyingFromScratch(MirrorDevSave),
+    SyncingRaids,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReshapeSave {
+    pub step: ReshapeStepSave,
+    pub paused: bool,
+}
+
+// A reason a reshape can't start, with the numbers involved
+#[derive(Debug, Clone)]
+pub enum ReshapeBlocker {
+    TooFewBlockDevs(usize),             // present blockdevs
+    RaidsBusy(usize),                   // raids not idle
+    NoScratchSpace(Sectors, Sectors),   // needed, available
+    TooMuchData(Sectors, Sectors),      // used, reshaped capacity
+    AlreadyReshaping,
+}
+
+impl ReshapeBlocker {
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            ReshapeBlocker::TooFewBlockDevs(_) => "TooFewBlockDevs",
+            ReshapeBlocker::RaidsBusy(_) => "RaidsBusy",
+            ReshapeBlocker::NoScratchSpace(..) => "NoScratchSpace",
+            ReshapeBlocker::TooMuchData(..) => "TooMuchData",
+            ReshapeBlocker::AlreadyReshaping => "AlreadyReshaping",
+        }
+    }
+
+    // The amount involved, and the limit it's up against
+    pub fn values(&self) -> (u64, u64) {
+        match *self {
+            ReshapeBlocker::TooFewBlockDevs(count) => (count as u64, 2),
+            ReshapeBlocker::RaidsBusy(count) => (count as u64, 0),
+            ReshapeBlocker::NoScratchSpace(needed, avail) => (*needed, *avail),
+            ReshapeBlocker::TooMuchData(used, capacity) => (*used, *capacity),
+            ReshapeBlocker::AlreadyReshaping => (0, 0),
+        }
+    }
+
+    pub fn error_kind(&self) -> FroyoErrorKind {
+        match *self {
+            ReshapeBlocker::TooFewBlockDevs(_) => FroyoErrorKind::WouldFail,
+            ReshapeBlocker::RaidsBusy(_) => FroyoErrorKind::Busy,
+            ReshapeBlocker::NoScratchSpace(..) => FroyoErrorKind::InsufficientSpace,
+            ReshapeBlocker::TooMuchData(..) => FroyoErrorKind::InsufficientSpace,
+            ReshapeBlocker::AlreadyReshaping => FroyoErrorKind::Busy,
+        }
+    }
+}
+
+impl fmt::Display for ReshapeBlocker {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bytes = |sectors: Sectors|
+            ByteSize::b((*sectors * SECTOR_SIZE) as usize).to_string(true);
+        match *self {
+            ReshapeBlocker::TooFewBlockDevs(count) =>
+                write!(f, "Only {} of the 2 block devices needed are present", count),
+            ReshapeBlocker::RaidsBusy(count) =>
+                write!(f, "{} raids are still syncing", count),
+            ReshapeBlocker::NoScratchSpace(needed, avail) =>
+                write!(f, "Not enough scratch space, {} needed but {} available",
+                       bytes(needed), bytes(avail)),
+            ReshapeBlocker::TooMuchData(used, capacity) =>
+                write!(f, "Too much data, {} used but reshaped capacity is {}",
+                       bytes(used), bytes(capacity)),
+            ReshapeBlocker::AlreadyReshaping =>
+                write!(f, "Already reshaping"),
+        }
+    }
+}
+
+// Overall sectors only count copying thin pool data between raids
+// and scratch, not raid resyncs. The total is an estimate, since a
+// segment may need copying to scratch and back again.
+#[derive(Debug, Clone)]
+pub struct ReshapeProgress {
+    pub phase: &'static str,
+    pub step_done: Sectors,
+    pub step_total: Sectors,
+    pub overall_done: Sectors,
+    pub overall_total: Sectors,
+    pub eta_secs: Option<u64>,
+}
+
+// A froyodev whose signature was found on disk, set up or not
+#[derive(Debug, Clone)]
+pub struct FroyoAvailable {
+    pub name: String,
+    pub id: String,
+    pub present_block_devs: usize,
+    pub total_block_devs: usize,
+}
+
+// What Froyo::check_thin_meta() found
+#[derive(Debug, Clone)]
+pub enum ThinMetaCheck {
+    Good,
+    // Damaged, with what thin_check said, and repaired
+    Repaired(String),
 }
 
 #[derive(Debug, Clone)]
@@ -52,9 +173,41 @@ pub struct Froyo<'a> {
     thin_devs: Vec<ThinDev>,
     throttled: bool,
     last_state: FroyoState,
+    reshape_paused: bool,
+    // Whether the last reshape was cancelled, rather than finishing
+    reshape_cancelled: bool,
+    // Sectors copied by completed steps of the current reshape, and
+    // when and at what point we started measuring its throughput
+    reshape_copied: Sectors,
+    reshape_rate_base: Option<(time::Timespec, Sectors)>,
+    meta_backup: MetaBackupPolicy,
+    last_meta_backup: time::Timespec,
+    recovery_rate: RecoveryRatePolicy,
+    meta_usage_sample: Option<(time::Timespec, u64)>,
+    meta_growth_rate: f64,
     pub dbus_context: Option<DbusContext<'a>>,
 }
 
+// A blockdev on its way into a froyodev, between
+// prepare_block_device() and add_prepared_block_device()
+#[derive(Debug)]
+pub enum PreparedBlockDev {
+    // A known member that was absent
+    Existing(BlockDev),
+    // Newly initialized for the froyodev
+    New(BlockDev),
+}
+
+impl PreparedBlockDev {
+    // Undo prepare_block_device(), if it wrote anything
+    pub fn discard(self) -> FroyoResult<()> {
+        match self {
+            PreparedBlockDev::Existing(_) => Ok(()),
+            PreparedBlockDev::New(mut bd) => bd.wipe_mda_header(),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum FroyoState {
     Initializing,
@@ -91,6 +244,39 @@ pub enum ReshapeState {
 }
 
 impl ReshapeState {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ReshapeState::Off => "Off",
+            ReshapeState::Idle => "Idle",
+            ReshapeState::CopyingToRaid(_) => "CopyingToRaid",
+            ReshapeState::CopyingToScratch(_) => "CopyingToScratch",
+            ReshapeState::CopyingFromScratch(_) => "CopyingFromScratch",
+            ReshapeState::SyncingRaids => "SyncingRaids",
+        }
+    }
+
+    pub fn to_save(&self) -> ReshapeStepSave {
+        match *self {
+            ReshapeState::Off | ReshapeState::Idle => ReshapeStepSave::Idle,
+            ReshapeState::CopyingToRaid(ref mir) =>
+                ReshapeStepSave::CopyingToRaid(mir.to_save()),
+            ReshapeState::CopyingToScratch(ref mir) =>
+                ReshapeStepSave::CopyingToScratch(mir.to_save()),
+            ReshapeState::CopyingFromScratch(ref mir) =>
+                ReshapeStepSave::CopyingFromScratch(mir.to_save()),
+            ReshapeState::SyncingRaids => ReshapeStepSave::SyncingRaids,
+        }
+    }
+
+    pub fn mirror(&self) -> Option<&MirrorDev> {
+        match *self {
+            ReshapeState::CopyingToRaid(ref mir) |
+            ReshapeState::CopyingToScratch(ref mir) |
+            ReshapeState::CopyingFromScratch(ref mir) => Some(mir),
+            _ => None,
+        }
+    }
+
     pub fn is_busy(&self) -> bool {
         match *self {
             ReshapeState::Off => panic!("should never happen"),
@@ -101,26 +287,53 @@ impl ReshapeState {
 }
 
 impl<'a> Froyo<'a> {
-    pub fn new<T>(name: &str, paths: &[T], force: bool)
+    pub fn new<T>(name: &str, paths: &[T], force: bool, options: ThinPoolOptions)
                      -> FroyoResult<Froyo<'a>>
         where T: borrow::Borrow<Path>
     {
-        if paths.len() < MIN_BLK_DEVS {
+        // Before we write anything to the blockdevs
+        try!(Froyo::validate_new(name, paths.len(), &options));
+
+        let froyo_id = Froyo::new_id();
+        let mut bds = Vec::new();
+        for path in paths {
+            bds.push(try!(BlockDev::new(&froyo_id, path.borrow(), force)));
+        }
+
+        Froyo::from_block_devs(name, froyo_id, bds, options)
+    }
+
+    // Check what we can for Froyo::new(), without touching the blockdevs
+    pub fn validate_new(name: &str, dev_count: usize, options: &ThinPoolOptions)
+                        -> FroyoResult<()> {
+        try!(Froyo::validate_name(name));
+
+        if dev_count < MIN_BLK_DEVS {
             return Err(FroyoError::Io(io::Error::new(
                 ErrorKind::InvalidInput, "At least 2 block devices must be given")))
         }
 
-        if paths.len() > MAX_BLK_DEVS {
+        if dev_count > MAX_BLK_DEVS {
             return Err(FroyoError::Io(io::Error::new(
                 ErrorKind::InvalidInput,
                 format!("Max supported devices is {}, {} given",
-                        MAX_BLK_DEVS, paths.len()))))
+                        MAX_BLK_DEVS, dev_count))))
         }
 
-        let froyo_id = Uuid::new_v4().to_simple_string();
+        options.validate()
+    }
+
+    pub fn new_id() -> String {
+        Uuid::new_v4().to_simple_string()
+    }
+
+    // The rest of Froyo::new(), once the blockdevs have been
+    // initialized with BlockDev::new().
+    pub fn from_block_devs(name: &str, froyo_id: String, bds: Vec<BlockDev>,
+                           options: ThinPoolOptions)
+                           -> FroyoResult<Froyo<'a>> {
         let mut block_devs = BlockDevs(BTreeMap::new());
-        for path in paths {
-            let bd = try!(BlockDev::new(&froyo_id, path.borrow(), force));
+        for bd in bds {
             block_devs.0.insert(bd.id.clone(),
                               BlockMember::Present(Rc::new(RefCell::new(bd))));
         }
@@ -134,16 +347,16 @@ impl<'a> Froyo<'a> {
 
         let meta_raid_segments = try!(
             raid_devs.alloc_raid_segments(meta_size)
-                .ok_or_else(||
-                            io::Error::new(io::ErrorKind::InvalidInput,
-                                           "no space for thinpool meta")));
+                .ok_or_else(|| FroyoError::Typed(
+                    FroyoErrorKind::InsufficientSpace,
+                    InternalError("no space for thinpool meta".into()))));
         let data_raid_segments = try!(
             raid_devs.alloc_raid_segments(data_size)
-                .ok_or_else(||
-                            io::Error::new(io::ErrorKind::InvalidInput,
-                                           "no space for thinpool data")));
+                .ok_or_else(|| FroyoError::Typed(
+                    FroyoErrorKind::InsufficientSpace,
+                    InternalError("no space for thinpool data".into()))));
         let thin_pool_dev = try!(ThinPoolDev::new(
-            &dm, &froyo_id, meta_raid_segments, data_raid_segments));
+            &dm, &froyo_id, meta_raid_segments, data_raid_segments, options));
 
         Ok(Froyo {
             name: name.to_owned(),
@@ -154,6 +367,15 @@ impl<'a> Froyo<'a> {
             thin_devs: Vec::new(),
             throttled: false,
             last_state: FroyoState::Initializing,
+            reshape_paused: false,
+            reshape_cancelled: false,
+            reshape_copied: Sectors(0),
+            reshape_rate_base: None,
+            meta_backup: MetaBackupPolicy::default(),
+            last_meta_backup: time::Timespec::new(0, 0),
+            recovery_rate: RecoveryRatePolicy::default(),
+            meta_usage_sample: None,
+            meta_growth_rate: 0.0,
             dbus_context: None,
         })
     }
@@ -163,13 +385,15 @@ impl<'a> Froyo<'a> {
 
         dbgp!("initial resync complete, creating thin dev");
 
-        // Create an initial 1TB thin dev
+        // Create an initial thin dev as big as the redundant space.
+        // It's extended later if the froyodev grows.
+        let size = try!(self.avail_redundant_space());
         self.thin_devs.push(try!(ThinDev::new(
             &dm,
             &self.id,
             &self.name, // 1st thindev name same as froyodev name
             0,
-            THIN_INITIAL_SECTORS,
+            size,
             &self.thin_pool_dev)));
 
         self.last_state = FroyoState::Good(FroyoRunningState::Good);
@@ -187,12 +411,29 @@ impl<'a> Froyo<'a> {
             raid_devs: self.raid_devs.raids.iter()
                 .map(|(id, rd)| (id.clone(), rd.borrow().to_save()))
                 .collect(),
-            temp_dev: self.raid_devs.temp_dev.as_ref()
-                .map(|ref td| td.borrow().to_save()),
+            // While copying back from scratch, the temp dev is the
+            // mirror's source, and the RaidLinearDev still refers to it
+            temp_dev: match self.last_state {
+                FroyoState::Good(FroyoRunningState::Reshaping(
+                    ReshapeState::CopyingFromScratch(ref mir))) =>
+                    Some(mir.src.borrow().to_save()),
+                _ => self.raid_devs.temp_dev.as_ref()
+                    .map(|ref td| td.borrow().to_save()),
+            },
             thin_pool_dev: self.thin_pool_dev.to_save(),
             thin_devs: self.thin_devs.iter()
                 .map(|x| x.to_save())
                 .collect(),
+            meta_backup: self.meta_backup.clone(),
+            recovery_rate: self.recovery_rate.clone(),
+            reshape: match self.last_state {
+                FroyoState::Good(FroyoRunningState::Reshaping(ref state)) =>
+                    Some(ReshapeSave {
+                        step: state.to_save(),
+                        paused: self.reshape_paused,
+                    }),
+                _ => None,
+            },
         }
     }
 
@@ -203,15 +444,13 @@ impl<'a> Froyo<'a> {
         Ok(())
     }
 
-    pub fn to_metadata(&self) -> FroyoResult<String> {
-        Ok(try!(serde_json::to_string(&self.to_save())))
-    }
-
     pub fn to_metadata_pretty(&self) -> FroyoResult<String> {
         Ok(try!(serde_json::to_string_pretty(&self.to_save())))
     }
 
-    pub fn find_all() -> FroyoResult<Vec<Froyo<'a>>> {
+    // Find the saved metadata and member blockdevs of all froyodevs,
+    // without setting anything up.
+    fn find_all_saves() -> FroyoResult<Vec<(FroyoSave, String, Vec<BlockDev>)>> {
         // We could have BlockDevs for multiple Froyodevs.
         // Group them by Froyo uuid.
         let mut froyo_devs = BTreeMap::new();
@@ -242,6 +481,105 @@ impl<'a> Froyo<'a> {
 
             let froyo_save = try!(serde_json::from_str::<FroyoSave>(&s));
 
+            froyos.push((froyo_save, froyo_id, bds));
+        }
+
+        Ok(froyos)
+    }
+
+    fn find_save(name: &str) -> FroyoResult<(FroyoSave, String, Vec<BlockDev>)> {
+        let mut saves = try!(Froyo::find_all_saves());
+        let idx = {
+            let names_and_ids = saves.iter()
+                .map(|&(ref froyo_save, ref froyo_id, _)| (&*froyo_save.name, &**froyo_id))
+                .collect::<Vec<_>>();
+            try!(Froyo::resolve(name, &names_and_ids))
+        };
+
+        Ok(saves.swap_remove(idx))
+    }
+
+    // Which of the given froyodevs, as (name, uuid) pairs, the user
+    // means by `key`. This is either a name or the start of a uuid,
+    // at least MIN_ID_PREFIX_LEN long, and a name is preferred if it
+    // could be both.
+    pub fn resolve(key: &str, names_and_ids: &[(&str, &str)]) -> FroyoResult<usize> {
+        let mut matches = names_and_ids.iter().enumerate()
+            .filter(|&(_, &(name, _))| name == key)
+            .map(|(idx, _)| idx)
+            .collect::<Vec<_>>();
+        if matches.is_empty() {
+            let id_prefix = key.replace("-", "").to_lowercase();
+            if id_prefix.len() >= MIN_ID_PREFIX_LEN {
+                matches = names_and_ids.iter().enumerate()
+                    .filter(|&(_, &(_, id))| id.starts_with(&id_prefix))
+                    .map(|(idx, _)| idx)
+                    .collect::<Vec<_>>();
+            }
+        }
+
+        match matches.len() {
+            0 => Err(FroyoError::Typed(FroyoErrorKind::NotFound, InternalError(
+                format!("Froyodev \"{}\" not found", key).into()))),
+            1 => Ok(matches[0]),
+            _ => {
+                let ids = matches.iter()
+                    .map(|&idx| short_id(names_and_ids[idx].1))
+                    .collect::<Vec<_>>();
+                Err(FroyoError::Froyo(InternalError(
+                    format!("\"{}\" matches more than one froyodev ({}), \
+                             give more of the uuid", key, ids.join(", ")).into())))
+            },
+        }
+    }
+
+    // The name is also used for the first volume's /dev/froyo node
+    pub fn validate_name(name: &str) -> FroyoResult<()> {
+        if name.is_empty() || name.len() > MAX_NAME_LEN {
+            return Err(FroyoError::Froyo(InternalError(
+                format!("Name must be 1-{} characters, \"{}\" given",
+                        MAX_NAME_LEN, name).into())))
+        }
+
+        if name == "." || name == ".." || name.starts_with('-') {
+            return Err(FroyoError::Froyo(InternalError(
+                format!("\"{}\" is not a valid name", name).into())))
+        }
+
+        let valid_char = |c: char| match c {
+            'a'...'z' | 'A'...'Z' | '0'...'9' | '.' | '_' | '+' | '-' => true,
+            _ => false,
+        };
+        if let Some(c) = name.chars().find(|&c| !valid_char(c)) {
+            return Err(FroyoError::Froyo(InternalError(
+                format!("Name may only contain letters, digits, '.', '_', '+' \
+                         and '-', '{}' given", c).into())))
+        }
+
+        Ok(())
+    }
+
+    // Froyodevs found on disk, without setting anything up
+    pub fn find_available() -> FroyoResult<Vec<FroyoAvailable>> {
+        Ok(try!(Froyo::find_all_saves()).into_iter()
+           .map(|(froyo_save, froyo_id, bds)| FroyoAvailable {
+               name: froyo_save.name,
+               id: froyo_id,
+               present_block_devs: bds.len(),
+               total_block_devs: froyo_save.block_devs.len(),
+           })
+           .collect())
+    }
+
+    // Set up the froyodev `name` refers to, by name or uuid prefix
+    pub fn find_and_setup(name: &str) -> FroyoResult<Froyo<'a>> {
+        let (froyo_save, froyo_id, bds) = try!(Froyo::find_save(name));
+        Froyo::setup(&froyo_save, froyo_id, bds)
+    }
+
+    pub fn find_all() -> FroyoResult<Vec<Froyo<'a>>> {
+        let mut froyos = Vec::new();
+        for (froyo_save, froyo_id, bds) in try!(Froyo::find_all_saves()) {
             match Froyo::setup(&froyo_save, froyo_id, bds) {
                 Ok(f) => froyos.push(f),
                 Err(e) => dbgp!("Error: {}", e.description()),
@@ -252,14 +590,19 @@ impl<'a> Froyo<'a> {
     }
 
     pub fn find(name: &str) -> FroyoResult<Option<Froyo>> {
-        let froyos = try!(Froyo::find_all());
-        for f in froyos {
-            if name == f.name {
-                return Ok(Some(f))
+        let mut froyos = try!(Froyo::find_all());
+        let idx = {
+            let names_and_ids = froyos.iter()
+                .map(|f| (&*f.name, &*f.id))
+                .collect::<Vec<_>>();
+            match Froyo::resolve(name, &names_and_ids) {
+                Ok(idx) => idx,
+                Err(ref e) if e.kind() == Some(FroyoErrorKind::NotFound) => return Ok(None),
+                Err(e) => return Err(e),
             }
-        }
+        };
 
-        Ok(None)
+        Ok(Some(froyos.swap_remove(idx)))
     }
 
     fn setup_blockdevs(
@@ -293,52 +636,76 @@ impl<'a> Froyo<'a> {
         Ok(BlockDevs(block_devs))
     }
 
-    fn setup_thinpool(
+    fn setup_thinpool_devs(
         dm: &DM,
         froyo_save: &FroyoSave,
         raid_devs: &RaidDevs)
-        -> FroyoResult<ThinPoolDev> {
+        -> FroyoResult<(RaidLinearDev, RaidLinearDev)> {
         let tpd = &froyo_save.thin_pool_dev;
 
         let meta_name = format!("thin-meta-{}", froyo_save.id);
-        let mut raid_segments = Vec::new();
+        let mut meta_segments = Vec::new();
         for seg in &tpd.meta_dev.segments {
             let raid_seg = try!(raid_devs.lookup_segment(
                 &seg.parent, seg.start, seg.length).ok_or_else(|| {
                     io::Error::new(io::ErrorKind::InvalidInput,
                                    "Could not find meta's parent")}));
-            raid_segments.push(raid_seg);
+            meta_segments.push(raid_seg);
         }
 
-        let meta_raid_dev = try!(RaidLinearDev::setup(
-            &dm,
-            &meta_name,
-            &tpd.meta_dev.id,
-            raid_segments));
-
         let data_name = format!("thin-data-{}", froyo_save.id);
-        let mut raid_segments = Vec::new();
+        let mut data_segments = Vec::new();
         for seg in &tpd.data_dev.segments {
             let raid_seg = try!(raid_devs.lookup_segment(
                 &seg.parent, seg.start, seg.length).ok_or_else(|| {
                     io::Error::new(io::ErrorKind::InvalidInput,
                                    "Could not find data's parent")}));
-            raid_segments.push(raid_seg);
+            data_segments.push(raid_seg);
         }
 
-        let data_raid_dev = try!(RaidLinearDev::setup(
+        let mut meta_raid_dev = try!(RaidLinearDev::setup(
+            &dm,
+            &meta_name,
+            &tpd.meta_dev.id,
+            meta_segments));
+
+        let data_raid_dev = match RaidLinearDev::setup(
             &dm,
             &data_name,
             &tpd.data_dev.id,
-            raid_segments));
+            data_segments) {
+            Ok(dev) => dev,
+            Err(e) => {
+                let _ = meta_raid_dev.teardown(dm);
+                return Err(e)
+            },
+        };
 
-        ThinPoolDev::setup(
+        Ok((meta_raid_dev, data_raid_dev))
+    }
+
+    fn setup_thinpool(
+        dm: &DM,
+        froyo_save: &FroyoSave,
+        raid_devs: &RaidDevs)
+        -> FroyoResult<ThinPoolDev> {
+        let tpd = &froyo_save.thin_pool_dev;
+        let (meta_raid_dev, data_raid_dev) = try!(
+            Froyo::setup_thinpool_devs(dm, froyo_save, raid_devs));
+
+        let mut thin_pool_dev = try!(ThinPoolDev::setup(
             dm,
             &froyo_save.id,
-            tpd.data_block_size,
             tpd.low_water_blocks,
+            tpd.options(),
             meta_raid_dev,
-            data_raid_dev)
+            data_raid_dev));
+
+        if let Some(blocks) = tpd.meta_low_water_blocks {
+            thin_pool_dev.meta_low_water_blocks = blocks;
+        }
+
+        Ok(thin_pool_dev)
     }
 
     fn setup(froyo_save: &FroyoSave, froyo_id: String, found_blockdevs: Vec<BlockDev>)
@@ -353,15 +720,21 @@ impl<'a> Froyo<'a> {
 
         let mut thin_devs = Vec::new();
         for std in &froyo_save.thin_devs {
-            thin_devs.push(try!(ThinDev::setup(
+            let mut td = try!(ThinDev::setup(
                 &dm,
                 &froyo_save.id,
                 &std.name,
                 std.thin_number,
                 std.size,
-                &thin_pool_dev)));
+                &thin_pool_dev));
+            td.pending_grow = std.pending_grow;
+            td.size_policy = std.size_policy;
+            thin_devs.push(td);
         }
 
+        // Still throttled from before we exited, if so
+        let throttled = thin_devs.iter().any(|td| td.is_throttled());
+
         let mut froyo = Froyo {
             name: froyo_save.name.to_owned(),
             id: froyo_id.to_owned(),
@@ -369,11 +742,24 @@ impl<'a> Froyo<'a> {
             raid_devs: raid_devs,
             thin_pool_dev: thin_pool_dev,
             thin_devs: thin_devs,
-            throttled: false,
+            throttled: throttled,
             last_state: FroyoState::Good(FroyoRunningState::Good),
+            reshape_paused: false,
+            reshape_cancelled: false,
+            reshape_copied: Sectors(0),
+            reshape_rate_base: None,
+            meta_backup: froyo_save.meta_backup.clone(),
+            last_meta_backup: time::Timespec::new(0, 0),
+            recovery_rate: froyo_save.recovery_rate.clone(),
+            meta_usage_sample: None,
+            meta_growth_rate: 0.0,
             dbus_context: None,
         };
 
+        if let Some(ref reshape) = froyo_save.reshape {
+            try!(froyo.restore_reshape(&dm, reshape));
+        }
+
         try!(froyo.check_state());
 
         Ok(froyo)
@@ -394,10 +780,14 @@ impl<'a> Froyo<'a> {
     }
 
     pub fn save_state(&self) -> FroyoResult<()> {
-        let metadata = try!(self.to_metadata());
+        Froyo::save_to_blockdevs(&self.to_save(), &self.block_devs)
+    }
+
+    fn save_to_blockdevs(froyo_save: &FroyoSave, block_devs: &BlockDevs) -> FroyoResult<()> {
+        let metadata = try!(serde_json::to_string(froyo_save));
         let current_time = time::now().to_timespec();
 
-        for bd in self.block_devs.0.values() {
+        for bd in block_devs.0.values() {
             if let Some(bd) = bd.present() {
                 try!(bd.borrow_mut().save_state(&current_time, metadata.as_bytes()))
             }
@@ -406,6 +796,217 @@ impl<'a> Froyo<'a> {
         Ok(())
     }
 
+    // The daemon is stopping, leaving our dm devices running. Reshape
+    // steps are only taken in check_state(), so none is half done.
+    // Work the kernel carries on with, like a mirror copy, is picked
+    // up by restore_reshape() next time.
+    pub fn shutdown(&self) -> FroyoResult<()> {
+        if let FroyoState::Good(FroyoRunningState::Reshaping(ref state)) = self.last_state {
+            dbgp!("{}: leaving reshape at {}", self.name, state.name());
+        }
+        self.save_state()
+    }
+
+    // Check an inactive froyodev's thin pool metadata. If it's bad and
+    // repair is true, thin_repair it onto newly-allocated raid space
+    // and switch the froyodev over to the repaired copy. Each step is
+    // passed to progress.
+    pub fn check_thin_meta(name: &str, repair: bool, progress: &Fn(&str))
+                           -> FroyoResult<ThinMetaCheck> {
+        let (mut froyo_save, _, bds) = try!(Froyo::find_save(name));
+
+        let dm = try!(DM::new());
+        try!(Froyo::check_inactive(&dm, &froyo_save, name));
+
+        let block_devs = try!(Froyo::setup_blockdevs(&froyo_save, bds));
+        let raid_devs = try!(RaidDevs::setup(&dm, &froyo_save, &block_devs));
+
+        // Whatever happens, leave nothing set up
+        let res = Froyo::setup_thinpool_devs(&dm, &froyo_save, &raid_devs)
+            .and_then(|(mut meta_dev, mut data_dev)| {
+                progress("Checking thin pool metadata");
+                let res = match ThinPoolDev::check_meta(&meta_dev) {
+                    Ok(_) => {
+                        ThinPoolDev::clear_needs_check(&meta_dev)
+                            .map(|_| ThinMetaCheck::Good)
+                    },
+                    Err(e) => {
+                        progress(&format!("Thin pool metadata check failed: {}",
+                                          e.description()));
+                        if repair {
+                            let length = meta_dev.length();
+                            Froyo::replace_thin_meta(
+                                &dm, &mut froyo_save, &block_devs, &raid_devs, length,
+                                progress,
+                                |new_meta_dev| {
+                                    progress("Running thin_repair");
+                                    ThinPoolDev::repair_meta(&meta_dev, new_meta_dev)
+                                })
+                                .map(|_| ThinMetaCheck::Repaired(e.description().to_owned()))
+                        } else {
+                            Err(FroyoError::Typed(FroyoErrorKind::Corrupt, InternalError(
+                                format!("Thin pool metadata is damaged ({}), use --repair \
+                                         to repair it", e.description()).into())))
+                        }
+                    },
+                };
+
+                let meta_torn_down = meta_dev.teardown(&dm);
+                let data_torn_down = data_dev.teardown(&dm);
+                res.and_then(|r| meta_torn_down.and(data_torn_down).map(|_| r))
+            });
+
+        let raids_torn_down = raid_devs.teardown(&dm);
+        res.and_then(|r| raids_torn_down.map(|_| r))
+    }
+
+    // Restore an inactive froyodev's thin pool metadata from a
+    // backup, the most recent one if none is given. Returns the
+    // backup used. Each step is passed to progress.
+    pub fn restore_thin_meta(name: &str, backup: Option<&Path>, progress: &Fn(&str))
+                             -> FroyoResult<PathBuf> {
+        let (mut froyo_save, _, bds) = try!(Froyo::find_save(name));
+
+        let backup = match backup {
+            Some(path) => path.to_owned(),
+            None => {
+                let backups = try!(froyo_save.meta_backup.list(&froyo_save.id));
+                try!(backups.last().cloned().ok_or_else(|| FroyoError::Typed(
+                    FroyoErrorKind::NotFound,
+                    InternalError(format!("No thin pool metadata backups found in {}",
+                                          froyo_save.meta_backup.froyo_dir(&froyo_save.id)
+                                          .display()).into()))))
+            },
+        };
+        progress(&format!("Restoring thin pool metadata from {}", backup.display()));
+
+        let dm = try!(DM::new());
+        try!(Froyo::check_inactive(&dm, &froyo_save, name));
+
+        let block_devs = try!(Froyo::setup_blockdevs(&froyo_save, bds));
+        let raid_devs = try!(RaidDevs::setup(&dm, &froyo_save, &block_devs));
+
+        let length: Sectors = froyo_save.thin_pool_dev.meta_dev.segments.iter()
+            .map(|x| x.length)
+            .sum();
+        let res = Froyo::replace_thin_meta(
+            &dm, &mut froyo_save, &block_devs, &raid_devs, length, progress,
+            |new_meta_dev| {
+                progress("Running thin_restore");
+                ThinPoolDev::restore_meta(&backup, new_meta_dev)
+            });
+
+        let raids_torn_down = raid_devs.teardown(&dm);
+        res.and(raids_torn_down).map(|_| backup)
+    }
+
+    // The offline thin meta operations mustn't touch a running pool
+    fn check_inactive(dm: &DM, froyo_save: &FroyoSave, name: &str) -> FroyoResult<()> {
+        let pool_name = format!("froyo-thin-pool-{}", froyo_save.id);
+        if dm.device_status(&DevId::Name(&pool_name)).is_ok() {
+            return Err(FroyoError::Typed(FroyoErrorKind::InUse, InternalError(
+                format!("Froyodev {} is active, tear it down first", name).into())))
+        }
+
+        Ok(())
+    }
+
+    // Allocate a new meta dev, fill it with fill(), and if it then
+    // passes thin_check, switch the froyodev over to it.
+    fn replace_thin_meta<F>(dm: &DM,
+                            froyo_save: &mut FroyoSave,
+                            block_devs: &BlockDevs,
+                            raid_devs: &RaidDevs,
+                            length: Sectors,
+                            progress: &Fn(&str),
+                            fill: F)
+                            -> FroyoResult<()>
+        where F: FnOnce(&RaidLinearDev) -> FroyoResult<()>
+    {
+        let new_segs = try!(
+            raid_devs.alloc_raid_segments(length)
+                .ok_or_else(|| FroyoError::Typed(
+                    FroyoErrorKind::InsufficientSpace,
+                    InternalError("no space for new thinpool meta".into()))));
+        progress(&format!("Allocated {} for new metadata",
+                          ByteSize::b((*length * SECTOR_SIZE) as usize).to_string(true)));
+
+        let mut new_meta_dev = try!(RaidLinearDev::new(
+            dm,
+            &format!("thin-meta-new-{}", froyo_save.id),
+            &Uuid::new_v4().to_simple_string(),
+            new_segs));
+
+        let res = fill(&new_meta_dev)
+            .and_then(|_| {
+                progress("Checking new metadata");
+                ThinPoolDev::check_meta(&new_meta_dev)
+            })
+            .and_then(|_| {
+                froyo_save.thin_pool_dev.meta_dev = new_meta_dev.to_save();
+                Froyo::save_to_blockdevs(froyo_save, block_devs)
+            });
+        if res.is_ok() {
+            progress(&format!("Switched {} to the new thin pool metadata", froyo_save.name));
+        }
+
+        let torn_down = new_meta_dev.teardown(dm);
+        res.and(torn_down)
+    }
+
+    pub fn set_meta_backup_policy(&mut self, policy: MetaBackupPolicy)
+                                  -> FroyoResult<()> {
+        try!(policy.validate());
+        self.meta_backup = policy;
+        self.save_state()
+    }
+
+    pub fn set_recovery_rate_policy(&mut self, policy: RecoveryRatePolicy)
+                                    -> FroyoResult<()> {
+        try!(policy.validate());
+        self.recovery_rate = policy;
+        try!(self.apply_recovery_rate());
+        self.save_state()
+    }
+
+    fn current_recovery_rate(&self) -> RecoveryRate {
+        self.recovery_rate.current(time::now().tm_hour as u8)
+    }
+
+    // Raids pick up a changed rate right away. A reshape copy keeps
+    // the rate it started with, and the next one gets the new rate.
+    fn apply_recovery_rate(&mut self) -> FroyoResult<()> {
+        let rate = self.current_recovery_rate();
+        let dm = try!(DM::new());
+        for rd in self.raid_devs.raids.values() {
+            try!(rd.borrow_mut().set_recovery_rate(&dm, rate));
+        }
+
+        Ok(())
+    }
+
+    // Back up thin pool metadata if it's been long enough since the
+    // last backup.
+    fn backup_thin_meta(&mut self) -> FroyoResult<()> {
+        if !self.meta_backup.enabled {
+            return Ok(())
+        }
+
+        let now = time::now().to_timespec();
+        let elapsed = (now - self.last_meta_backup).num_seconds();
+        if elapsed < self.meta_backup.interval_secs as i64 {
+            return Ok(())
+        }
+
+        // Don't retry failures until the next interval either
+        self.last_meta_backup = now;
+
+        let dm = try!(DM::new());
+        try!(self.thin_pool_dev.backup_meta(&dm, &self.id, &self.meta_backup));
+
+        Ok(())
+    }
+
     pub fn status(&self) -> FroyoResult<FroyoState> {
         let mut degraded = 0;
         for rd in self.raid_devs.raids.values() {
@@ -477,6 +1078,14 @@ impl<'a> Froyo<'a> {
         Ok(raid_avail + thinpool_avail)
     }
 
+    pub fn raids(&self) -> &BTreeMap<String, Rc<RefCell<RaidDev>>> {
+        &self.raid_devs.raids
+    }
+
+    pub fn thin_devs(&self) -> &[ThinDev] {
+        &self.thin_devs
+    }
+
     pub fn data_block_size(&self) -> u64 {
         self.thin_pool_dev.data_block_size()
     }
@@ -484,9 +1093,9 @@ impl<'a> Froyo<'a> {
     pub fn extend_thinpool_data_dev(&mut self, length: Sectors) -> FroyoResult<()> {
         let new_segs = try!(
             self.raid_devs.alloc_raid_segments(length)
-                .ok_or_else(||
-                            io::Error::new(io::ErrorKind::InvalidInput,
-                                           "no space for extending thinpool data")));
+                .ok_or_else(|| FroyoError::Typed(
+                    FroyoErrorKind::InsufficientSpace,
+                    InternalError("no space for extending thinpool data".into()))));
 
         dbgp!("Extending tpool data dev by {}", *length);
         try!(self.thin_pool_dev.extend_data_dev(new_segs));
@@ -497,9 +1106,9 @@ impl<'a> Froyo<'a> {
     pub fn extend_thinpool_meta_dev(&mut self, length: Sectors) -> FroyoResult<()> {
         let new_segs = try!(
             self.raid_devs.alloc_raid_segments(length)
-                .ok_or_else(||
-                            io::Error::new(io::ErrorKind::InvalidInput,
-                                           "no space for extending thinpool meta")));
+                .ok_or_else(|| FroyoError::Typed(
+                    FroyoErrorKind::InsufficientSpace,
+                    InternalError("no space for extending thinpool meta".into()))));
 
         dbgp!("Extending tpool meta dev by {}", *length);
         try!(self.thin_pool_dev.extend_meta_dev(new_segs));
@@ -508,7 +1117,60 @@ impl<'a> Froyo<'a> {
     }
 
     pub fn extend_thin_dev(&mut self, length: Sectors) -> FroyoResult<()> {
-        self.thin_devs[0].extend(length)
+        let res = self.thin_devs[0].extend(length);
+
+        // Save even if growing the fs failed, the thin dev is bigger
+        try!(self.save_state());
+
+        res
+    }
+
+    pub fn has_thin_dev(&self, thin_name: &str) -> bool {
+        self.thin_devs.iter().any(|td| td.name == thin_name)
+    }
+
+    fn used_data_blocks(&self) -> FroyoResult<DataBlocks> {
+        match try!(self.thin_pool_dev.status()) {
+            ThinPoolStatus::Good((_, usage)) => Ok(usage.used_data),
+            ThinPoolStatus::Fail => Err(FroyoError::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Can't get used blocks from a failed thin pool dev"))),
+        }
+    }
+
+    // Trim a mounted thin dev's filesystem. Returns used data blocks in
+    // the thin pool before and after.
+    pub fn trim(&mut self, thin_name: &str) -> FroyoResult<(DataBlocks, DataBlocks)> {
+        let before = try!(self.used_data_blocks());
+
+        match self.thin_devs.iter().find(|td| td.name == thin_name) {
+            Some(td) => try!(td.trim()),
+            None => return Err(FroyoError::Typed(
+                FroyoErrorKind::NotFound,
+                InternalError(format!("Volume {} not found in froyodev {}",
+                                      thin_name, self.name).into()))),
+        }
+
+        let after = try!(self.used_data_blocks());
+        dbgp!("Trim reclaimed {} of {} used data blocks",
+              *before - min(*before, *after), *before);
+
+        Ok((before, after))
+    }
+
+    pub fn set_thin_size_policy(&mut self, thin_name: &str, policy: ThinSizePolicy)
+                                -> FroyoResult<()> {
+        try!(policy.validate());
+
+        match self.thin_devs.iter_mut().find(|td| td.name == thin_name) {
+            Some(td) => td.size_policy = policy,
+            None => return Err(FroyoError::Typed(
+                FroyoErrorKind::NotFound,
+                InternalError(format!("Volume {} not found in froyodev {}",
+                                      thin_name, self.name).into()))),
+        }
+
+        self.save_state()
     }
 
     pub fn blocks_to_sectors(&self, blocks: DataBlocks) -> Sectors {
@@ -522,16 +1184,16 @@ impl<'a> Froyo<'a> {
     pub fn update_dbus(&self) -> FroyoResult<()> {
         if let Some(ref dc) = self.dbus_context {
             let remaining = try!(self.avail_redundant_space());
-            try!(DbusContext::update_one(&dc.remaining_prop, (*remaining).into()));
+            try!(dc.update_one(&dc.remaining_prop, (*remaining).into()));
             let total = self.raid_devs.total_space();
-            try!(DbusContext::update_one(&dc.total_prop, (*total).into()));
+            try!(dc.update_one(&dc.total_prop, (*total).into()));
 
 
             // TODO: self.status() is not returning all status we can
             // report via dbus, and they're also mutually exclusive,
             // but dbus is not. If we inline that in this fn, that
             // might make this easier to achieve.
-            let (status, mut r_status): (u32,u32) = match try!(self.status()) {
+            let (mut status, mut r_status): (u32,u32) = match try!(self.status()) {
                 FroyoState::RaidFailed => (0x100, 0),
                 FroyoState::ThinPoolFailed => (0x200, 0),
                 FroyoState::ThinFailed => (0x400, 0),
@@ -555,66 +1217,114 @@ impl<'a> Froyo<'a> {
             if self.throttled {
                 r_status |= 0x800; // set "throttled" bit
             }
+            if self.reshape_paused {
+                r_status |= 0x10000; // set "reshape paused" bit
+            }
+            if self.thin_devs.iter().any(|td| td.pending_grow) {
+                r_status |= 0x1000; // set "fs grow pending" bit
+            }
+            if self.thin_devs.iter().any(|td| td.grow_failed) {
+                r_status |= 0x2000; // set "fs grow failed" bit
+            }
+            if let ThinPoolStatus::Good((pool_status, _)) = try!(self.thin_pool_dev.status()) {
+                match pool_status {
+                    ThinPoolWorkingStatus::ReadOnly |
+                    ThinPoolWorkingStatus::NeedsCheck =>
+                        r_status |= 0x4000, // set "thin pool read-only" bit
+                    ThinPoolWorkingStatus::OutOfSpace =>
+                        r_status |= 0x8000, // set "thin pool out of space" bit
+                    ThinPoolWorkingStatus::Good => {},
+                }
+            }
 
-            try!(DbusContext::update_one(&dc.status_prop, status.into()));
-            try!(DbusContext::update_one(&dc.running_status_prop, r_status.into()));
+            try!(dc.update_one(&dc.status_prop, status.into()));
+            try!(dc.update_one(&dc.running_status_prop, r_status.into()));
 
            let bdev_msg = DbusContext::get_block_devices_msgitem(&self.block_devs);
-           try!(DbusContext::update_one(&dc.block_devices_prop, bdev_msg));
+           try!(dc.update_one(&dc.block_devices_prop, bdev_msg));
+
+           let passdown = try!(self.thin_pool_dev.discard_passdown());
+           try!(dc.update_one(&dc.discard_passdown_prop, passdown.into()));
+
+           let blockers_msg = DbusContext::get_reshape_blockers_msgitem(
+               &self.reshape_blockers());
+           try!(dc.update_one(&dc.reshape_blockers_prop, blockers_msg));
+
+           let progress = try!(self.reshape_progress());
+           let progress_msg = DbusContext::get_reshape_progress_msgitem(progress.as_ref());
+           try!(dc.update_one(&dc.reshape_progress_prop, progress_msg));
         }
         Ok(())
     }
 
     pub fn add_block_device(&mut self, path: &Path, force: bool) -> FroyoResult<()> {
-        let bd = {
-            match BlockDev::setup(path) {
-                Ok(found_bd) => {
-                    // Does the new blockdev's froyo id match us?
-                    if found_bd.froyodev_id == self.id {
-                        if self.block_devs.0.contains_key(&found_bd.id) {
-                            if let BlockMember::Present(_) =
-                                *self.block_devs.0.get(&found_bd.id).unwrap() {
-                                    return Err(FroyoError::Froyo(InternalError(
-                                        format!("Block member {} already present \
-                                                 in froyodev {}",
-                                                short_id(&found_bd.id), self.name).into())))
-                                }
-
-                            // TODO: treat as new if froyodev layout has changed
-                            // Known but absent
-                            let found_bd = Rc::new(RefCell::new(found_bd));
-                            try!(self.raid_devs.add_existing_block_device(
-                                &self.id, &found_bd));
-                            found_bd
-                        } else {
-                            dbgp!("Block device {} mistakenly believes \
-                                   it's part of froyodev {}, adding as new",
-                                  path.display(), self.name);
-                            let new_bd = Rc::new(RefCell::new(
-                                try!(BlockDev::new(&self.id, path, force))));
-                            try!(self.raid_devs.add_new_block_device(&self.id, &new_bd));
-                            new_bd
-                        }
+        let prepared = try!(self.prepare_block_device(path, force));
+        self.add_prepared_block_device(prepared)
+    }
+
+    // The first half of add_block_device(). Work out whether the
+    // blockdev is an absent member or new, and initialize it if new.
+    pub fn prepare_block_device(&self, path: &Path, force: bool)
+                                -> FroyoResult<PreparedBlockDev> {
+        match BlockDev::setup(path) {
+            Ok(found_bd) => {
+                // Does the new blockdev's froyo id match us?
+                if found_bd.froyodev_id == self.id {
+                    if self.block_devs.0.contains_key(&found_bd.id) {
+                        if let BlockMember::Present(_) =
+                            *self.block_devs.0.get(&found_bd.id).unwrap() {
+                                return Err(FroyoError::Typed(
+                                    FroyoErrorKind::InUse,
+                                    InternalError(format!(
+                                        "Block member {} already present \
+                                         in froyodev {}",
+                                        short_id(&found_bd.id), self.name).into())))
+                            }
+
+                        // TODO: treat as new if froyodev layout has changed
+                        // Known but absent
+                        Ok(PreparedBlockDev::Existing(found_bd))
                     } else {
-                        // A blockdev from another froyodev, bad.
-                        let buf = try!(found_bd.read_mdax());
-                        let s = String::from_utf8_lossy(&buf).into_owned();
-                        let froyo_save = try!(serde_json::from_str::<FroyoSave>(&s));
-                        return Err(FroyoError::Froyo(InternalError(
-                            format!("Block device {} is already part of froyodev \
-                                     {}, id {}", path.display(), froyo_save.name,
-                                    short_id(&froyo_save.id)).into())));
+                        dbgp!("Block device {} mistakenly believes \
+                               it's part of froyodev {}, adding as new",
+                              path.display(), self.name);
+                        Ok(PreparedBlockDev::New(try!(BlockDev::new(&self.id, path, force))))
                     }
-                },
-                Err(_) => {
-                    // setup() failed, so blockdev is not a current
-                    // froyo member disk. Initialize and add it.
-                    let bd = Rc::new(RefCell::new(
-                        try!(BlockDev::new(&self.id, path, force))));
-                    try!(self.raid_devs.add_new_block_device(&self.id, &bd));
-                    bd
+                } else {
+                    // A blockdev from another froyodev, bad.
+                    let buf = try!(found_bd.read_mdax());
+                    let s = String::from_utf8_lossy(&buf).into_owned();
+                    let froyo_save = try!(serde_json::from_str::<FroyoSave>(&s));
+                    Err(FroyoError::Typed(
+                        FroyoErrorKind::InUse,
+                        InternalError(format!(
+                            "Block device {} is already part of froyodev \
+                             {}, id {}", path.display(), froyo_save.name,
+                            short_id(&froyo_save.id)).into())))
                 }
+            },
+            Err(_) => {
+                // setup() failed, so blockdev is not a current
+                // froyo member disk. Initialize and add it.
+                Ok(PreparedBlockDev::New(try!(BlockDev::new(&self.id, path, force))))
             }
+        }
+    }
+
+    // The second half of add_block_device(), setting up raids on it
+    pub fn add_prepared_block_device(&mut self, prepared: PreparedBlockDev)
+                                     -> FroyoResult<()> {
+        let bd = match prepared {
+            PreparedBlockDev::Existing(bd) => {
+                let bd = Rc::new(RefCell::new(bd));
+                try!(self.raid_devs.add_existing_block_device(&self.id, &bd));
+                bd
+            },
+            PreparedBlockDev::New(bd) => {
+                let bd = Rc::new(RefCell::new(bd));
+                try!(self.raid_devs.add_new_block_device(&self.id, &bd));
+                bd
+            },
         };
 
         // Depending on the above, we either insert or update an
@@ -640,45 +1350,21 @@ impl<'a> Froyo<'a> {
 
         // Check this blockdev is in this froyodev
         if !self.block_devs.0.contains_key(&blockdev.id) {
-            return Err(FroyoError::Froyo(InternalError(
+            return Err(FroyoError::Typed(FroyoErrorKind::NotFound, InternalError(
                 format!("{} is not a member of {}",
                         path.display(), self.name).into())))
         }
 
-        // We could get affected lineardevs from looking at
-        // self.block_devs[x].linear_devs but there's no backlink from
-        // lineardevs to raids (and hard to add).
-        // Instead, do it top-down.
-        let raids_and_linears_using_dev = self.raid_devs.raids.iter_mut()
-            .map(|(_, rd)| rd.borrow_mut())
-            .filter_map(|rd| {
-                let mut ld_index = None;
-                for (count, rm) in rd.members.iter().enumerate() {
-                    if let Some(pres) = rm.present() {
-                        let parent_rc = pres.borrow().parent.upgrade().unwrap();
-                        if parent_rc.borrow().dev == blockdev.dev {
-                            // A raid may only have 1 lineardev on a
-                            // given blockdev
-                            ld_index = Some(count);
-                            break
-                        }
-                    }
-                }
-                match ld_index {
-                    None => None,
-                    Some(ld_index) => Some((rd, ld_index)),
-                }
-            })
-            .collect::<Vec<_>>();
+        let raids_and_linears_using_dev = self.raids_using_block_device(blockdev.dev);
 
         // Check status of each affected raid (if any)
         for &(ref raid, _) in &raids_and_linears_using_dev {
-            let (status, action) = try!(raid.status());
+            let (status, action) = try!(raid.borrow().status());
             match status {
                 RaidStatus::Good => {},
                 RaidStatus::Degraded(x) if x < REDUNDANCY => {},
                 _ => {
-                    return Err(FroyoError::Froyo(InternalError(
+                    return Err(FroyoError::Typed(FroyoErrorKind::WouldFail, InternalError(
                         format!("Cannot remove {}, a RAID would fail",
                                 path.display()).into())))
                 },
@@ -686,39 +1372,14 @@ impl<'a> Froyo<'a> {
             match action {
                 RaidAction::Idle => {},
                 x => {
-                    return Err(FroyoError::Froyo(InternalError(
+                    return Err(FroyoError::Typed(FroyoErrorKind::Busy, InternalError(
                         format!("Cannot remove {}, a RAID is in state {:?}",
                                 path.display(), x).into())))
                 }
             }
         }
 
-        if !raids_and_linears_using_dev.is_empty() {
-            let dm = try!(DM::new());
-
-            // Reconfigure affected raids w/o the lineardev
-            for (ref mut raid, ld_idx) in raids_and_linears_using_dev {
-                // Take out Present value...
-                let rm = raid.members[ld_idx].clone();
-                let ld = rm.present().expect("should be here!!!");
-
-                let new_rm = {
-                    if wipe_sb {
-                        RaidMember::Removed
-                    } else {
-                        let parent = ld.borrow().parent.upgrade().unwrap();
-                        let parent_id = parent.borrow().id.clone();
-                        RaidMember::Absent((parent_id, ld.borrow().to_save()))
-                    }
-                };
-                // ..put in Removed or Absent value.
-                raid.members[ld_idx] = new_rm;
-
-                // TODO panic if reload fails???
-                try!(raid.reload(&dm, None));
-                try!(ld.borrow().teardown(&dm));
-            }
-        }
+        try!(self.detach_raid_members(raids_and_linears_using_dev, wipe_sb));
 
         if wipe_sb {
             self.block_devs.0.remove(&blockdev.id)
@@ -732,6 +1393,221 @@ impl<'a> Froyo<'a> {
         Ok(())
     }
 
+    // A member blockdev went away without being removed, e.g. its
+    // enclosure was unplugged. Carry on degraded without it. Returns
+    // false if dev isn't one of our present members.
+    pub fn block_device_vanished(&mut self, dev: Device) -> FroyoResult<bool> {
+        let found = self.block_devs.0.iter()
+            .filter_map(|(id, bm)| match *bm {
+                BlockMember::Present(ref bd) if bd.borrow().dev == dev =>
+                    Some((id.clone(), bd.borrow().to_save())),
+                _ => None,
+            })
+            .next();
+        let (id, bd_save) = match found {
+            Some(x) => x,
+            None => return Ok(false),
+        };
+
+        dbgp!("{} member {} is gone", self.name, bd_save.path.display());
+
+        // Keep going if a raid won't reload, so nothing is left
+        // referring to the blockdev.
+        let raids_and_linears_using_dev = self.raids_using_block_device(dev);
+        let detached = self.detach_raid_members(raids_and_linears_using_dev, false);
+        self.block_devs.0.insert(id, BlockMember::Absent(bd_save));
+        try!(detached);
+
+        Ok(true)
+    }
+
+    // The device of our present member at path, if there is one
+    pub fn present_member_dev(&self, path: &Path) -> Option<Device> {
+        self.block_devs.0.values()
+            .filter_map(|bm| bm.present())
+            .find(|bd| bd.borrow().path == path)
+            .map(|bd| {
+                let dev = bd.borrow().dev;
+                dev
+            })
+    }
+
+    // Is bd one of our members that we're running without?
+    pub fn is_absent_member(&self, bd: &BlockDev) -> bool {
+        if bd.froyodev_id != self.id {
+            return false
+        }
+        match self.block_devs.0.get(&bd.id) {
+            Some(&BlockMember::Absent(_)) => true,
+            _ => false,
+        }
+    }
+
+    // Raids with a lineardev on the blockdev, and the lineardev's index
+    // in the raid's members.
+    //
+    // We could get affected lineardevs from looking at
+    // self.block_devs[x].linear_devs but there's no backlink from
+    // lineardevs to raids (and hard to add).
+    // Instead, do it top-down.
+    fn raids_using_block_device(&self, dev: Device) -> Vec<(Rc<RefCell<RaidDev>>, usize)> {
+        self.raid_devs.raids.values()
+            .filter_map(|rd| {
+                let mut ld_index = None;
+                for (count, rm) in rd.borrow().members.iter().enumerate() {
+                    if let Some(pres) = rm.present() {
+                        let parent_rc = pres.borrow().parent.upgrade().unwrap();
+                        if parent_rc.borrow().dev == dev {
+                            // A raid may only have 1 lineardev on a
+                            // given blockdev
+                            ld_index = Some(count);
+                            break
+                        }
+                    }
+                }
+                ld_index.map(|ld_index| (rd.clone(), ld_index))
+            })
+            .collect()
+    }
+
+    // Reconfigure raids w/o their lineardev on a blockdev. All raids
+    // are changed even if one fails to reload, and the first error is
+    // returned.
+    fn detach_raid_members(&self,
+                           raids_and_linears: Vec<(Rc<RefCell<RaidDev>>, usize)>,
+                           wipe_sb: bool)
+                           -> FroyoResult<()> {
+        if raids_and_linears.is_empty() {
+            return Ok(())
+        }
+
+        let dm = try!(DM::new());
+        let mut result = Ok(());
+        for (raid, ld_idx) in raids_and_linears {
+            let mut raid = raid.borrow_mut();
+
+            // Take out Present value...
+            let rm = raid.members[ld_idx].clone();
+            let ld = rm.present().expect("should be here!!!");
+
+            let new_rm = {
+                if wipe_sb {
+                    RaidMember::Removed
+                } else {
+                    let parent = ld.borrow().parent.upgrade().unwrap();
+                    let parent_id = parent.borrow().id.clone();
+                    RaidMember::Absent((parent_id, ld.borrow().to_save()))
+                }
+            };
+            // ..put in Removed or Absent value.
+            raid.members[ld_idx] = new_rm;
+
+            // TODO panic if reload fails???
+            let reloaded = raid.reload(&dm, None)
+                .and_then(|_| ld.borrow().teardown(&dm));
+            if let Err(e) = reloaded {
+                dbgp!("Reconfiguring raid {} failed: {}", raid.id, e.description());
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+
+        result
+    }
+
+    // Pick up a reshape that was in progress when we last exited.
+    fn restore_reshape(&mut self, dm: &DM, reshape: &ReshapeSave) -> FroyoResult<()> {
+        let state = match reshape.step {
+            ReshapeStepSave::Idle => ReshapeState::Idle,
+            ReshapeStepSave::SyncingRaids => ReshapeState::SyncingRaids,
+            ReshapeStepSave::CopyingToRaid(ref ms) =>
+                ReshapeState::CopyingToRaid(try!(self.setup_mirror(dm, ms, false))),
+            ReshapeStepSave::CopyingToScratch(ref ms) =>
+                ReshapeState::CopyingToScratch(try!(self.setup_mirror(dm, ms, false))),
+            ReshapeStepSave::CopyingFromScratch(ref ms) =>
+                ReshapeState::CopyingFromScratch(try!(self.setup_mirror(dm, ms, true))),
+        };
+        dbgp!("resuming reshape at {}", state.name());
+
+        self.last_state = FroyoState::Good(FroyoRunningState::Reshaping(state));
+        if reshape.paused {
+            try!(self.reshape_pause());
+        } else {
+            try!(self.reset_reshape_rate());
+        }
+
+        Ok(())
+    }
+
+    // Recreate a saved MirrorDev and splice it back into its
+    // RaidLinearDev. If the mirror's devices are still around we
+    // reattach to them, otherwise the new mirror copies from the
+    // start.
+    fn setup_mirror(&mut self, dm: &DM, ms: &MirrorDevSave, src_is_temp: bool)
+                    -> FroyoResult<MirrorDev> {
+        let linear_dev = {
+            let md = &self.thin_pool_dev.meta_dev;
+            let dd = &self.thin_pool_dev.data_dev;
+            if md.borrow().id == ms.linear_dev {
+                md.clone()
+            } else if dd.borrow().id == ms.linear_dev {
+                dd.clone()
+            } else {
+                return Err(FroyoError::Froyo(InternalError(
+                    format!("Reshape linear dev {} not found", ms.linear_dev).into())))
+            }
+        };
+
+        let src = if src_is_temp {
+            match self.raid_devs.temp_dev.as_ref().map(|td| td.borrow().id == ms.src.id) {
+                Some(true) => self.raid_devs.temp_dev.take().unwrap(),
+                _ => return Err(FroyoError::Froyo(InternalError(
+                    "Reshape temp dev not found".into()))),
+            }
+        } else {
+            Rc::new(RefCell::new(try!(TempDev::from_save(
+                dm, &self.id, &ms.src, &self.block_devs, &self.raid_devs.raids))))
+        };
+        let dest = Rc::new(RefCell::new(try!(TempDev::from_save(
+            dm, &self.id, &ms.dest, &self.block_devs, &self.raid_devs.raids))));
+
+        let length = src.borrow().length();
+        let b_linear_dev = linear_dev.borrow();
+        try!(b_linear_dev.dev.suspend(dm));
+        let mirror_dev = try!(MirrorDev::new(
+            dm, &self.id, src, dest, length, linear_dev.clone(), &ms.linear_dev_idxs,
+            self.current_recovery_rate()));
+        try!(Froyo::load_mirror_table(dm, &b_linear_dev, &mirror_dev));
+
+        Ok(mirror_dev)
+    }
+
+    // Load a suspended RaidLinearDev's table with the segments being
+    // copied switched out for the mirror, and resume it.
+    fn load_mirror_table(dm: &DM, rld: &RaidLinearDev, mirror_dev: &MirrorDev)
+                         -> FroyoResult<()> {
+        let mut table = RaidLinearDev::dm_table(&rld.segments);
+        let mut offset = SectorOffset(0);
+        for &idx in &mirror_dev.linear_dev_idxs {
+            table[idx] = (table[idx].0, table[idx].1, table[idx].2.clone(),
+                          format!("{} {}", mirror_dev.mirror.dstr(), *offset));
+            offset = offset + SectorOffset(*rld.segments[idx].length);
+        }
+
+        try!(rld.dev.table_load(dm, &table));
+        try!(rld.dev.unsuspend(dm));
+
+        Ok(())
+    }
+
+    // Save once a finished copy is spliced into its RaidLinearDev. The
+    // step is over by then, so don't save the old mirror along with it.
+    fn save_reshape_step_done(&mut self) -> FroyoResult<()> {
+        self.last_state = FroyoState::Good(FroyoRunningState::Reshaping(ReshapeState::Idle));
+        self.save_state()
+    }
+
     fn check_raidcopy(&mut self, mirror: MirrorDev)
                       -> FroyoResult<ReshapeState> {
         let dm = try!(DM::new());
@@ -764,7 +1640,7 @@ impl<'a> Froyo<'a> {
             rld.segments.extend(rld_tail);
         }
 
-        try!(self.save_state());
+        try!(self.save_reshape_step_done());
 
         let rld = mirror.linear_dev.borrow();
 
@@ -829,7 +1705,7 @@ impl<'a> Froyo<'a> {
             }
         }
 
-        try!(self.save_state());
+        try!(self.save_reshape_step_done());
 
         let rld = mirror.linear_dev.borrow();
 
@@ -913,7 +1789,7 @@ impl<'a> Froyo<'a> {
             self.raid_devs.temp_dev = None;
         }
 
-        try!(self.save_state());
+        try!(self.save_reshape_step_done());
 
         let rld = mirror.linear_dev.borrow();
 
@@ -948,55 +1824,23 @@ impl<'a> Froyo<'a> {
             ReshapeState::CopyingToScratch(mir) => self.check_copy_to_scratch(mir),
             ReshapeState::CopyingFromScratch(mir) => self.check_copy_from_scratch(mir),
             ReshapeState::SyncingRaids => self.check_resync(),
-            ReshapeState::Idle => {
-
-                let r = try!(self.recreate_empty_degraded_raids());
-                if r.is_busy() {
-                    dbgp!("recreating empty degraded raids");
-                    return Ok(r)
-                }
-
-                let md = self.thin_pool_dev.meta_dev.clone();
-                let r = try!(self.start_copy_from_scratch(md));
-                if r.is_busy() {
-                    dbgp!("reshaping meta from scratch");
-                    return Ok(r)
-                }
-
-                let dd = self.thin_pool_dev.data_dev.clone();
-                let r = try!(self.start_copy_from_scratch(dd));
-                if r.is_busy() {
-                    dbgp!("reshaping data from scratch");
-                    return Ok(r)
-                }
-
-                let r = try!(self.start_copy_to_safe_raid(&self.thin_pool_dev.meta_dev));
-                if r.is_busy() {
-                    dbgp!("reshaping meta to safe raid");
-                    return Ok(r)
-                }
-
-                let r = try!(self.start_copy_to_safe_raid(&self.thin_pool_dev.data_dev));
-                if r.is_busy() {
-                    dbgp!("reshaping data to safe raid");
-                    return Ok(r)
-                }
-
-                let r = try!(self.start_copy_to_scratch(&self.thin_pool_dev.meta_dev));
-                if r.is_busy() {
-                    dbgp!("reshaping meta to scratch");
-                    return Ok(r)
-                }
-
-                let r = try!(self.start_copy_to_scratch(&self.thin_pool_dev.data_dev));
-                if r.is_busy() {
-                    dbgp!("reshaping data to scratch");
-                    return Ok(r)
-                }
+            ReshapeState::Idle => match try!(plan::start_next_step(self)) {
+                Some((choice, r)) => {
+                    dbgp!("{}", choice);
+                    Ok(r)
+                },
+                None => {
+                    dbgp!("reshape stopping");
+                    Ok(ReshapeState::Off)
+                },
+            },
+        }
+    }
 
-                dbgp!("reshape stopping");
-                Ok(ReshapeState::Off)
-            }
+    fn thin_pool_part(&self, part: ThinPoolPart) -> Rc<RefCell<RaidLinearDev>> {
+        match part {
+            ThinPoolPart::Meta => self.thin_pool_dev.meta_dev.clone(),
+            ThinPoolPart::Data => self.thin_pool_dev.data_dev.clone(),
         }
     }
 
@@ -1005,24 +1849,234 @@ impl<'a> Froyo<'a> {
         // phase 1: get redundant
         // phase 2: use all space efficiently
         //
-        // thinpool extend needed while reshape? cancel reshape. (how?)
+        // thinpool extend needed while reshape? cancel reshape.
 
-        if !self.is_reshapable() {
+        if let Err(err) = self.check_reshapable() {
             dbgp!("cannot initiate a reshape!");
-            return Err(FroyoError::Froyo(InternalError("Cannot reshape".into())))
+            return Err(err)
         }
 
         dbgp!("starting reshaping!");
+        self.reshape_cancelled = false;
         self.last_state = match try!(self.reshape_state_machine(ReshapeState::Idle)) {
             ReshapeState::Off => FroyoState::Good(FroyoRunningState::Good),
             x => FroyoState::Good(FroyoRunningState::Reshaping(x)),
         };
+        self.reshape_copied = Sectors(0);
+        try!(self.reset_reshape_rate());
+
+        self.save_state()
+    }
+
+    fn reshape_state(&self) -> FroyoResult<&ReshapeState> {
+        match self.last_state {
+            FroyoState::Good(FroyoRunningState::Reshaping(ref state)) => Ok(state),
+            _ => Err(FroyoError::Froyo(InternalError(
+                format!("Froyodev {} is not reshaping", self.name).into()))),
+        }
+    }
+
+    // Sectors of thin pool data still on scratch space or unsafe
+    // raids, other than those the given mirror is copying.
+    fn reshape_uncopied(&self, mirror: Option<&MirrorDev>) -> Sectors {
+        let mut uncopied = Sectors(0);
+        for rld in &[&self.thin_pool_dev.meta_dev, &self.thin_pool_dev.data_dev] {
+            let rld = rld.borrow();
+            let skip_idxs = match mirror {
+                Some(mir) if mir.linear_dev.borrow().id == rld.id =>
+                    &mir.linear_dev_idxs[..],
+                _ => &[][..],
+            };
+            for (idx, rs) in rld.segments.iter().enumerate() {
+                if skip_idxs.contains(&idx) {
+                    continue
+                }
+                if rs.parent.on_temp() || !rs.parent.raid().borrow().is_safe() {
+                    uncopied = uncopied + rs.length;
+                }
+            }
+        }
+
+        uncopied
+    }
+
+    pub fn reshape_cancelled(&self) -> bool {
+        self.reshape_cancelled
+    }
+
+    pub fn reshape_progress(&self) -> FroyoResult<Option<ReshapeProgress>> {
+        let state = match self.last_state {
+            FroyoState::Good(FroyoRunningState::Reshaping(ref state)) => state,
+            _ => return Ok(None),
+        };
+
+        let dm = try!(DM::new());
+        let (step_done, step_total) = match (state.mirror(), state) {
+            (Some(mir), _) => try!(mir.sync_progress(&dm)),
+            (None, &ReshapeState::SyncingRaids) => {
+                let mut done = Sectors(0);
+                let mut total = Sectors(0);
+                for rd in self.raid_devs.raids.values() {
+                    let (rd_done, rd_total) = try!(rd.borrow().sync_progress());
+                    done = done + rd_done;
+                    total = total + rd_total;
+                }
+                (done, total)
+            },
+            (None, _) => (Sectors(0), Sectors(0)),
+        };
+
+        // Raid resyncs don't count towards overall progress
+        let (copy_done, copy_total) = match state.mirror() {
+            Some(_) => (step_done, step_total),
+            None => (Sectors(0), Sectors(0)),
+        };
+        let overall_done = self.reshape_copied + copy_done;
+        let overall_total = self.reshape_copied + copy_total
+            + self.reshape_uncopied(state.mirror());
+
+        let eta_secs = match self.reshape_rate_base {
+            Some((base_time, base_done)) if !self.reshape_paused => {
+                let elapsed = (time::now().to_timespec() - base_time).num_seconds();
+                let copied = (*overall_done).saturating_sub(*base_done);
+                if elapsed > 0 && copied > 0 {
+                    let rate = copied as f64 / elapsed as f64;
+                    Some(((*overall_total - *overall_done) as f64 / rate) as u64)
+                } else {
+                    None
+                }
+            },
+            _ => None,
+        };
+
+        Ok(Some(ReshapeProgress {
+            phase: state.name(),
+            step_done: step_done,
+            step_total: step_total,
+            overall_done: overall_done,
+            overall_total: overall_total,
+            eta_secs: eta_secs,
+        }))
+    }
+
+    // Measure reshape throughput from here, e.g. after a pause
+    fn reset_reshape_rate(&mut self) -> FroyoResult<()> {
+        self.reshape_rate_base = match try!(self.reshape_progress()) {
+            Some(progress) => Some((time::now().to_timespec(), progress.overall_done)),
+            None => None,
+        };
 
         Ok(())
     }
 
+    // Stop reshape where it is. A copy in progress is frozen, and
+    // the state machine doesn't advance until resumed.
+    pub fn reshape_pause(&mut self) -> FroyoResult<()> {
+        if self.reshape_paused {
+            return Ok(())
+        }
+
+        let dm = try!(DM::new());
+        match *try!(self.reshape_state()) {
+            ReshapeState::CopyingToRaid(ref mir) |
+            ReshapeState::CopyingToScratch(ref mir) |
+            ReshapeState::CopyingFromScratch(ref mir) => try!(mir.freeze(&dm)),
+            _ => {},
+        }
+
+        dbgp!("reshape paused");
+        self.reshape_paused = true;
+
+        self.save_state()
+    }
+
+    pub fn reshape_resume(&mut self) -> FroyoResult<()> {
+        if !self.reshape_paused {
+            return Ok(())
+        }
+
+        let dm = try!(DM::new());
+        match *try!(self.reshape_state()) {
+            ReshapeState::CopyingToRaid(ref mir) |
+            ReshapeState::CopyingToScratch(ref mir) |
+            ReshapeState::CopyingFromScratch(ref mir) => try!(mir.unfreeze(&dm)),
+            _ => {},
+        }
+
+        dbgp!("reshape resumed");
+        self.reshape_paused = false;
+        try!(self.reset_reshape_rate());
+
+        self.save_state()
+    }
+
+    // Stop reshaping. A copy in progress is abandoned, leaving the
+    // data where it was before the copy started. Completed steps are
+    // kept, so this may leave the froyodev running on scratch space;
+    // a later reshape picks up from there.
+    pub fn reshape_cancel(&mut self) -> FroyoResult<()> {
+        let state = try!(self.reshape_state()).clone();
+
+        match state {
+            ReshapeState::CopyingToRaid(mir) |
+            ReshapeState::CopyingToScratch(mir) => try!(self.abandon_copy(mir, false)),
+            ReshapeState::CopyingFromScratch(mir) => try!(self.abandon_copy(mir, true)),
+            // New raids will finish syncing on their own
+            _ => {},
+        }
+
+        dbgp!("reshape cancelled");
+        self.reshape_paused = false;
+        self.reshape_cancelled = true;
+        self.reshape_rate_base = None;
+        self.last_state = FroyoState::Good(FroyoRunningState::Good);
+
+        self.save_state()
+    }
+
+    // Point the linear dev back at its segments, which are only
+    // updated when a copy completes, and remove the mirror. If the
+    // source is the scratch temp dev, it's still in use, so keep it.
+    fn abandon_copy(&mut self, mirror: MirrorDev, src_is_temp: bool) -> FroyoResult<()> {
+        let dm = try!(DM::new());
+
+        {
+            let rld = mirror.linear_dev.borrow();
+            let table = RaidLinearDev::dm_table(&rld.segments);
+            try!(rld.dev.reload(&dm, &table));
+        }
+
+        try!(mirror.mirror.teardown(&dm));
+        try!(mirror.dest.borrow().teardown(&dm));
+        if src_is_temp {
+            self.raid_devs.temp_dev = Some(mirror.src.clone());
+        } else {
+            try!(mirror.src.borrow().teardown(&dm));
+        }
+
+        Ok(())
+    }
+
+    // DM devices whose events should trigger check_state(): the thin
+    // pool hitting its low water mark, raids changing sync state or
+    // failing, and a reshape's copy completing.
+    pub fn dm_event_names(&self) -> Vec<String> {
+        let mut names = vec![self.thin_pool_dev.dm_name().to_owned()];
+        names.extend(self.raid_devs.raids.values()
+                     .map(|rd| rd.borrow().dev.dm_name.clone()));
+        if let FroyoState::Good(FroyoRunningState::Reshaping(ref state)) = self.last_state {
+            if let Some(mir) = state.mirror() {
+                names.push(mir.mirror.dm_name.clone());
+            }
+        }
+        names
+    }
+
     pub fn check_state(&mut self) -> FroyoResult<()> {
 
+        // Follow the schedule, and cover newly created raids
+        try!(self.apply_recovery_rate());
+
         if let FroyoState::Initializing = self.last_state {
             let cur_state = try!(self.status());
 
@@ -1033,6 +2087,12 @@ impl<'a> Froyo<'a> {
             return Ok(())
         }
 
+        try!(self.grow_pending_filesystems());
+
+        if let Err(e) = self.backup_thin_meta() {
+            dbgp!("Thin pool metadata backup failed: {}", e.description());
+        }
+
         // TODO: simplify this once Rust has non-lexical closures
         // (can't set self.last_state within a match on self.last_state)
         let r_state = match self.last_state {
@@ -1042,16 +2102,59 @@ impl<'a> Froyo<'a> {
             _ => return self.handle_thinpool_usage(),
         };
 
+        // Only throttles while reshaping
+        try!(self.handle_thinpool_usage());
+
+        if self.reshape_paused {
+            return Ok(())
+        }
+
         if let Some(state) = r_state {
-            self.last_state = match try!(self.reshape_state_machine(state)) {
+            let old_name = state.name();
+            let copy_len = state.mirror().map(|mir| mir.src.borrow().length());
+            let new_state = try!(self.reshape_state_machine(state));
+            let changed = new_state.name() != old_name;
+
+            if let (true, Some(len)) = (changed, copy_len) {
+                self.reshape_copied = self.reshape_copied + len;
+            }
+            self.last_state = match new_state {
                 ReshapeState::Off => FroyoState::Good(FroyoRunningState::Good),
                 x => FroyoState::Good(FroyoRunningState::Reshaping(x)),
             };
+
+            // Record the new step, in case we need to resume it
+            if changed {
+                try!(self.save_state());
+            }
         };
 
         Ok(())
     }
 
+    // A thin dev may have been extended while its filesystem wasn't
+    // mounted. If it's mounted now, grow the filesystem.
+    fn grow_pending_filesystems(&mut self) -> FroyoResult<()> {
+        let mut changed = false;
+        for thin in self.thin_devs.iter_mut().filter(|td| td.pending_grow) {
+            match thin.grow_fs() {
+                Ok(true) => changed = true,
+                Ok(false) => {},
+                Err(e) => {
+                    dbgp!("Growing fs on thin #{} failed: {}",
+                          thin.thin_number, e.description());
+                    changed = true;
+                },
+            }
+        }
+
+        if changed {
+            try!(self.save_state());
+        }
+
+        Ok(())
+    }
+
     // We may be reshaping either to reestablish redundancy on a
     // smaller number of blockdevs (shrink), or to take advantage of
     // more blockdevs (expand).
@@ -1061,24 +2164,50 @@ impl<'a> Froyo<'a> {
     // Even to expand we need scratch space enough to make a copy of the
     // most-used raiddev's data.
     pub fn is_reshapable(&self) -> bool {
+        let blockers = self.reshape_blockers();
+        for blocker in &blockers {
+            dbgp!("can't reshape, {}", blocker);
+        }
+        if blockers.is_empty() {
+            dbgp!("can reshape");
+        }
+
+        blockers.is_empty()
+    }
+
+    // As is_reshapable(), but with an error saying why not
+    pub fn check_reshapable(&self) -> FroyoResult<()> {
+        match self.reshape_blockers().first() {
+            Some(blocker) => Err(FroyoError::Typed(
+                blocker.error_kind(),
+                InternalError(format!("Cannot reshape: {}", blocker).into()))),
+            None => Ok(()),
+        }
+    }
+
+    // Why is_reshapable() fails, if it does
+    pub fn reshape_blockers(&self) -> Vec<ReshapeBlocker> {
+        let mut blockers = Vec::new();
+
         // Just one disk, no way we can re-establish redundancy
-        if self.block_devs.0.iter()
+        let present = self.block_devs.0.iter()
             .filter_map(|(_, bd)| bd.present())
-            .count() < 2 {
-                dbgp!("can't reshape, only 1 dev");
-                return false
-            }
+            .count();
+        if present < 2 {
+            blockers.push(ReshapeBlocker::TooFewBlockDevs(present));
+        }
 
-        if !self.raid_devs.are_idle() {
-            dbgp!("can't reshape, not all idle");
-            return false
+        let busy = self.raid_devs.busy_count();
+        if busy != 0 {
+            blockers.push(ReshapeBlocker::RaidsBusy(busy));
         }
 
         // scratch space must be greater than largest used space in
         // any degraded raid (so we can make a temp copy)
-        if self.raid_devs.max_used_raid_sectors() > self.block_devs.unused_space() {
-            dbgp!("can't reshape, not enough free scratch space");
-            return false
+        let scratch_needed = self.raid_devs.max_used_raid_sectors();
+        let scratch_avail = self.block_devs.unused_space();
+        if scratch_needed > scratch_avail {
+            blockers.push(ReshapeBlocker::NoScratchSpace(scratch_needed, scratch_avail));
         }
 
         // After reshape, how much redundant space will we have?
@@ -1089,23 +2218,30 @@ impl<'a> Froyo<'a> {
                 let members_present = rd.members.iter()
                     .filter_map(|rm| rm.present())
                     .count();
-                let sz = (members_present - REDUNDANCY) * *per_member_data_size as usize;
+                let sz = members_present.saturating_sub(REDUNDANCY)
+                    * *per_member_data_size as usize;
                 Sectors(sz as u64)
             })
             .sum::<Sectors>();
 
-        if self.thin_pool_dev.used_sectors() > reshaped_tot_size {
-            dbgp!("can't reshape, too much data for reshaped froyodev");
-            return false
+        let used = self.thin_pool_dev.used_sectors();
+        if used > reshaped_tot_size {
+            blockers.push(ReshapeBlocker::TooMuchData(used, reshaped_tot_size));
         }
 
         if let FroyoState::Good(FroyoRunningState::Reshaping(_)) = self.last_state {
-            dbgp!("can't reshape, already reshaping");
-            return false
+            blockers.push(ReshapeBlocker::AlreadyReshaping);
         }
 
-        dbgp!("can reshape");
-        true
+        blockers
+    }
+
+    // What reshape() would do from the current layout, without doing it
+    pub fn plan_reshape(&self) -> ReshapePlan {
+        ReshapeModel::new(&self.block_devs,
+                          &self.raid_devs,
+                          &self.thin_pool_dev.meta_dev,
+                          &self.thin_pool_dev.data_dev).plan()
     }
 
     pub fn needs_reshape(&self) -> FroyoResult<bool> {
@@ -1204,15 +2340,10 @@ impl<'a> Froyo<'a> {
         try!(b_src.dev.suspend(&dm));
         let mirror_dev = try!(MirrorDev::new(
             &dm, &self.id, Rc::new(RefCell::new(src_dev)),
-            Rc::new(RefCell::new(dest_dev)), raid_seg.length, src.clone(), &[idx]));
-
-        // Get the dm table but switch out for our new mirror
-        let mut table = RaidLinearDev::dm_table(&b_src.segments);
-        table[idx] = (table[idx].0, table[idx].1, table[idx].2.clone(),
-                      format!("{} 0", mirror_dev.mirror.dstr()));
+            Rc::new(RefCell::new(dest_dev)), raid_seg.length, src.clone(), &[idx],
+            self.current_recovery_rate()));
 
-        try!(b_src.dev.table_load(&dm, &table));
-        try!(b_src.dev.unsuspend(&dm));
+        try!(Froyo::load_mirror_table(&dm, &b_src, &mirror_dev));
 
         Ok(ReshapeState::CopyingToRaid(mirror_dev))
     }
@@ -1268,9 +2399,10 @@ impl<'a> Froyo<'a> {
 
         let scratch_areas = try!(
             self.block_devs.get_linear_segments(spc_needed)
-                .ok_or(FroyoError::Froyo(InternalError(
-                    format!("No scratch space for raidseg {}",
-                            *spc_needed).into()))));
+                .ok_or(FroyoError::Typed(
+                    FroyoErrorKind::InsufficientSpace,
+                    InternalError(format!("No scratch space for raidseg {}",
+                                          *spc_needed).into()))));
         let scratch_areas = scratch_areas.into_iter()
             .map(|(bd, ls)| (TempLayer::Block(bd), ls))
             .collect::<Vec<_>>();
@@ -1286,19 +2418,10 @@ impl<'a> Froyo<'a> {
         try!(b_src.dev.suspend(&dm));
         let mirror_dev = try!(MirrorDev::new(
             &dm, &self.id, Rc::new(RefCell::new(src_dev)),
-            Rc::new(RefCell::new(dest_dev)), spc_needed, src.clone(), &*raid_idxs));
-
-        // Get the dm table but switch out for our new mirror
-        let mut table = RaidLinearDev::dm_table(&b_src.segments);
-        let mut offset = SectorOffset(0);
-        for &(idx, rs) in raid_segs {
-            table[idx] = (table[idx].0, table[idx].1, table[idx].2.clone(),
-                          format!("{} {}", mirror_dev.mirror.dstr(), *offset));
-            offset = offset + SectorOffset(*rs.length);
-        }
+            Rc::new(RefCell::new(dest_dev)), spc_needed, src.clone(), &*raid_idxs,
+            self.current_recovery_rate()));
 
-        try!(b_src.dev.table_load(&dm, &table));
-        try!(b_src.dev.unsuspend(&dm));
+        try!(Froyo::load_mirror_table(&dm, &b_src, &mirror_dev));
 
         Ok(ReshapeState::CopyingToScratch(mirror_dev))
     }
@@ -1318,9 +2441,9 @@ impl<'a> Froyo<'a> {
         let len = src_dev.borrow().length();
         let raid_segs = try!(
             self.raid_devs.alloc_raid_segments(len)
-                .ok_or_else(||
-                            io::Error::new(io::ErrorKind::InvalidInput,
-                                           "no space to copy back from scratch")));
+                .ok_or_else(|| FroyoError::Typed(
+                    FroyoErrorKind::InsufficientSpace,
+                    InternalError("no space to copy back from scratch".into()))));
         let idxs = dest.borrow().segments.iter().enumerate()
             .filter(|&(_, rs)| rs.parent.on_temp())
             .map(|(idx, _)| idx)
@@ -1338,23 +2461,9 @@ impl<'a> Froyo<'a> {
         try!(dest.borrow().dev.suspend(&dm));
         let mirror_dev = try!(MirrorDev::new(
             &dm, &self.id, src_dev, Rc::new(RefCell::new(dest_dev)),
-            len, dest.clone(), &idxs));
-
-        let b_dest = dest.borrow();
-
-        // Get the dm table but switch out for our new mirror
-        let mut table = RaidLinearDev::dm_table(&b_dest.segments);
-        let mut offset = SectorOffset(0);
-        for (idx, rs) in b_dest.segments.iter().enumerate()
-            .filter(|&(_, rs)| rs.parent.on_temp())
-        {
-            table[idx] = (table[idx].0, table[idx].1, table[idx].2.clone(),
-                          format!("{} {}", mirror_dev.mirror.dstr(), *offset));
-            offset = offset + SectorOffset(*rs.length);
-        }
+            len, dest.clone(), &idxs, self.current_recovery_rate()));
 
-        try!(b_dest.dev.table_load(&dm, &table));
-        try!(b_dest.dev.unsuspend(&dm));
+        try!(Froyo::load_mirror_table(&dm, &dest.borrow(), &mirror_dev));
 
         Ok(ReshapeState::CopyingFromScratch(mirror_dev))
     }
@@ -1362,49 +2471,276 @@ impl<'a> Froyo<'a> {
     pub fn handle_thinpool_usage(&mut self) -> FroyoResult<()> {
 
         if self.last_state.is_reshaping() {
-            // TODO: if we're getting low on space, start using
-            // dm-delay so we don't run out of space while we're
-            // reshaping
+            // Can't extend while reshaping, so just slow writes down
+            // if we're getting low on space. Running out entirely
+            // stops writes, so that still gets an emergency extension.
+            if let ThinPoolStatus::Good((status, usage)) = try!(self.thin_pool_dev.status()) {
+                try!(self.handle_throttling(&usage));
+                if let ThinPoolWorkingStatus::OutOfSpace = status {
+                    try!(self.emergency_extend_thinpool());
+                }
+            }
             return Ok(())
         }
 
         match try!(self.thin_pool_dev.status()) {
-            ThinPoolStatus::Fail => panic!("thinpool is failed!"),
+            // Reported via status(), nothing we can do about it here
+            ThinPoolStatus::Fail => {
+                dbgp!("Thin pool for {} has failed", self.name);
+                return Ok(())
+            },
             ThinPoolStatus::Good((status, usage)) => {
+                try!(self.handle_throttling(&usage));
                 match status {
                     ThinPoolWorkingStatus::Good => {
                         let remaining_data = usage.total_data - usage.used_data;
                         if remaining_data < self.thin_pool_dev.low_water_blocks {
-                            try!(self.extend_thinpool_data_dev(TPOOL_EXTEND_SECTORS));
-                            try!(self.save_state());
+                            if self.raid_devs.avail_space() >= TPOOL_EXTEND_SECTORS {
+                                try!(self.extend_thinpool_data_dev(TPOOL_EXTEND_SECTORS));
+                                try!(self.save_state());
+                            } else {
+                                dbgp!("Thin pool for {} low on space, can't extend",
+                                      self.name);
+                            }
                         }
 
-                        let remaining_meta = usage.total_meta - usage.used_meta;
-                        // TODO meta low-water # should probably be an independent value
-                        if remaining_meta < *self.thin_pool_dev.low_water_blocks {
-                            // Double it
-                            let meta_secs = self.thin_pool_dev.meta_dev.borrow().length();
+                        if let Some(meta_secs) = self.wanted_meta_extension(&usage) {
                             try!(self.extend_thinpool_meta_dev(meta_secs));
                             try!(self.save_state());
                         }
                     }
-                    _ => panic!(format!("bad thin check_status: {:?}", status))
+                    ThinPoolWorkingStatus::OutOfSpace => {
+                        try!(self.emergency_extend_thinpool());
+                        return Ok(())
+                    },
+                    // The kernel put the pool in read-only mode due to a
+                    // metadata error. It stays that way until the pool
+                    // is checked while offline, so all we can do is
+                    // report it.
+                    ThinPoolWorkingStatus::ReadOnly |
+                    ThinPoolWorkingStatus::NeedsCheck => {
+                        dbgp!("Thin pool for {} is read-only ({:?}), run \
+                               \"froyo dev check_thin_meta\" while it's torn down",
+                              self.name, status);
+                        return Ok(())
+                    },
                 }
             }
 
         };
 
+        self.handle_thin_usage()
+    }
+
+    // The thin pool ran out of data space and is erroring or queueing
+    // writes. Extend it with whatever raid space we have, or failing
+    // that, scratch space; the kernel switches the pool back to
+    // read-write when its data dev grows.
+    fn emergency_extend_thinpool(&mut self) -> FroyoResult<()> {
+        let avail = self.raid_devs.avail_space();
+        if avail != Sectors(0) {
+            let length = min(TPOOL_EXTEND_SECTORS, avail);
+            dbgp!("Thin pool for {} is out of space, emergency extending by {}",
+                  self.name, *length);
+            try!(self.extend_thinpool_data_dev(length));
+        } else if !try!(self.extend_thinpool_data_dev_scratch(TPOOL_EXTEND_SECTORS)) {
+            dbgp!("Thin pool for {} is out of space, none left to extend it",
+                  self.name);
+            return Ok(())
+        }
+        try!(self.save_state());
+
+        if let ThinPoolStatus::Good((ThinPoolWorkingStatus::OutOfSpace, _)) =
+            try!(self.thin_pool_dev.status()) {
+                dbgp!("Thin pool for {} still out of space after extending",
+                      self.name);
+            }
+
+        Ok(())
+    }
+
+    // Extend the thin pool data dev onto non-redundant scratch space,
+    // via a new temp dev. Once there's raid space again, the reshape
+    // copies it back like any other data on scratch. There's only one
+    // temp dev, so this can't be done if one's already in use.
+    fn extend_thinpool_data_dev_scratch(&mut self, length: Sectors) -> FroyoResult<bool> {
+        if self.raid_devs.temp_dev.is_some() {
+            return Ok(false)
+        }
+
+        let length = min(length, self.block_devs.unused_space());
+        if length == Sectors(0) {
+            return Ok(false)
+        }
+
+        let scratch_areas = match self.block_devs.get_linear_segments(length) {
+            Some(areas) => areas.into_iter()
+                .map(|(bd, ls)| (TempLayer::Block(bd), ls))
+                .collect::<Vec<_>>(),
+            None => return Ok(false),
+        };
+
+        let dm = try!(DM::new());
+        let temp_dev = Rc::new(RefCell::new(
+            try!(TempDev::new(&dm, &self.id, &scratch_areas))));
+        self.raid_devs.temp_dev = Some(temp_dev.clone());
+
+        dbgp!("Thin pool for {} is out of space, emergency extending by {} \
+               onto scratch", self.name, *length);
+        let seg = RaidSegment::new(SectorOffset(0), length, RaidLayer::Temp(temp_dev));
+        try!(self.thin_pool_dev.extend_data_dev(vec![seg]));
+
+        Ok(true)
+    }
+
+    // Throttle writes to the thin devs with dm-delay if the thin pool
+    // is low on data space and can't be extended, so it doesn't run
+    // out before the space situation is resolved.
+    fn handle_throttling(&mut self, usage: &ThinPoolBlockUsage) -> FroyoResult<()> {
+        let free = self.blocks_to_sectors(usage.total_data - usage.used_data);
+        let can_extend = !self.last_state.is_reshaping()
+            && self.raid_devs.avail_space() >= TPOOL_EXTEND_SECTORS;
+
+        let throttle = if can_extend {
+            false
+        } else if self.throttled {
+            free < Sectors(*TPOOL_THROTTLE_SECTORS * 2)
+        } else {
+            free < TPOOL_THROTTLE_SECTORS
+        };
+
+        if throttle == self.throttled {
+            return Ok(())
+        }
+
+        dbgp!("{} thin devs, {} sectors free in thin pool",
+              if throttle { "Throttling" } else { "Unthrottling" }, *free);
+
+        let dm = try!(DM::new());
+        for thin in &mut self.thin_devs {
+            if throttle {
+                try!(thin.throttle(&dm, TPOOL_THROTTLE_DELAY_MS));
+            } else {
+                try!(thin.unthrottle(&dm));
+            }
+        }
+        self.throttled = throttle;
+
         Ok(())
+    }
+
+    // Update the smoothed metadata growth rate, in meta blocks per
+    // second, from a new usage sample.
+    fn sample_meta_usage(&mut self, used_meta: u64) {
+        let now = time::now().to_timespec();
+
+        match self.meta_usage_sample {
+            None => self.meta_usage_sample = Some((now, used_meta)),
+            Some((then, prev_used)) => {
+                let elapsed = (now - then).num_seconds();
+                if elapsed < TPOOL_META_SAMPLE_SECS {
+                    return
+                }
+
+                // Usage going down (e.g. after deleting a thin dev)
+                // counts as no growth
+                let rate = used_meta.saturating_sub(prev_used) as f64 / elapsed as f64;
+                self.meta_growth_rate = 0.7 * self.meta_growth_rate + 0.3 * rate;
+                self.meta_usage_sample = Some((now, used_meta));
+            }
+        }
+    }
+
+    // How much to extend the thin pool meta dev by, if it's below its
+    // low-water mark or predicted to fill soon. Size the extension to
+    // cover both the growth rate and the metadata needed to map the
+    // rest of the data area, within the kernel's metadata size limit.
+    fn wanted_meta_extension(&mut self, usage: &ThinPoolBlockUsage) -> Option<Sectors> {
+        self.sample_meta_usage(usage.used_meta);
+
+        let remaining_meta = usage.total_meta - usage.used_meta;
+        let predicted_use = (self.meta_growth_rate * TPOOL_META_PREDICT_SECS as f64) as u64;
+        let low_water = self.thin_pool_dev.meta_low_water_blocks;
+
+        if remaining_meta >= low_water && remaining_meta >= predicted_use {
+            return None
+        }
+
+        // Meta blocks per data block so far, applied to the rest of the
+        // data area
+        let for_data = if *usage.used_data > 0 {
+            let unmapped = *usage.total_data - *usage.used_data;
+            (usage.used_meta as f64 / *usage.used_data as f64 * unmapped as f64) as u64
+        } else {
+            0
+        };
+
+        let wanted_blocks = *[2 * low_water, predicted_use, for_data]
+            .iter().max().unwrap();
+        let wanted = Sectors(wanted_blocks.saturating_sub(remaining_meta)
+                             * *TPOOL_META_BLOCK_SECTORS);
+
+        let current = self.thin_pool_dev.meta_dev.borrow().length();
+        if current >= TPOOL_MAX_META_SECTORS {
+            dbgp!("Thin pool meta low, but already at its maximum size");
+            return None
+        }
+        let wanted = min(max(wanted, Sectors(low_water * *TPOOL_META_BLOCK_SECTORS)),
+                         TPOOL_MAX_META_SECTORS - current);
+
+        dbgp!("Thin pool meta has {} blocks left, growing {}/s, extending by {}",
+              remaining_meta, self.meta_growth_rate, *wanted);
+        Some(wanted)
+    }
+
+    // Extend thin devs that are nearing their virtual size, per each
+    // one's size policy. A thin dev can never map more than what it
+    // has now plus the redundant space left, so don't extend past that.
+    fn handle_thin_usage(&mut self) -> FroyoResult<()> {
+        // Shared by all the thin devs, so each extension uses some up
+        let mut avail = try!(self.avail_redundant_space());
+
+        let mut changed = false;
+        for thin in &mut self.thin_devs {
+            let mapped = match try!(thin.status()) {
+                ThinStatus::Fail => {
+                    dbgp!("thin #{} failed", thin.thin_number);
+                    continue
+                },
+                ThinStatus::Good(sectors) => sectors,
+            };
+
+            let extend = match thin.wanted_extension(mapped) {
+                Some(x) => x,
+                None => continue,
+            };
+
+            let ceiling = mapped + avail;
+            if thin.size >= ceiling {
+                dbgp!("thin #{} nearly full, but no space to extend it",
+                      thin.thin_number);
+                continue
+            }
+            let extend = min(extend, ceiling - thin.size);
 
-        // TODO check & extend thin devs
-        // for thin in &self.thin_devs {
-        //     match try!(thin.status()) {
-        //         ThinStatus::Fail => dbgp!("thin #{} failed", thin.thin_number),
-        //         ThinStatus::Good(sectors) => dbgp!("thin #{} using {} sectors",
-        //                                            thin.thin_number,
-        //                                            *sectors),
-        //     }
-        // }
+            dbgp!("Extending thin #{} by {}", thin.thin_number, *extend);
+            let old_size = thin.size;
+            if let Err(e) = thin.extend(extend) {
+                dbgp!("Extending thin #{} failed: {}",
+                      thin.thin_number, e.description());
+            }
+            // Growing the fs can fail after the thin dev was extended
+            if thin.size != old_size {
+                avail = avail - (thin.size - old_size);
+                changed = true;
+            }
+        }
+
+        if changed {
+            try!(self.save_state());
+        }
+
+        Ok(())
     }
 
     #[allow(cyclomatic_complexity)]
@@ -1472,3 +2808,102 @@ impl<'a> Froyo<'a> {
         Ok(())
     }
 }
+
+// The reshape state machine's Idle steps, as chosen by
+// plan::start_next_step()
+impl<'a> ReshapeSteps for Froyo<'a> {
+    type State = ReshapeState;
+    type Error = FroyoError;
+
+    fn recreate_raids(&mut self) -> FroyoResult<Option<ReshapeState>> {
+        let r = try!(self.recreate_empty_degraded_raids());
+        Ok(if r.is_busy() { Some(r) } else { None })
+    }
+
+    fn copy_from_scratch(&mut self, part: ThinPoolPart) -> FroyoResult<Option<ReshapeState>> {
+        let dev = self.thin_pool_part(part);
+        let r = try!(self.start_copy_from_scratch(dev));
+        Ok(if r.is_busy() { Some(r) } else { None })
+    }
+
+    fn copy_to_safe_raid(&mut self, part: ThinPoolPart) -> FroyoResult<Option<ReshapeState>> {
+        let dev = self.thin_pool_part(part);
+        let r = try!(self.start_copy_to_safe_raid(&dev));
+        Ok(if r.is_busy() { Some(r) } else { None })
+    }
+
+    fn copy_to_scratch(&mut self, part: ThinPoolPart) -> FroyoResult<Option<ReshapeState>> {
+        let dev = self.thin_pool_part(part);
+        let r = try!(self.start_copy_to_scratch(&dev));
+        Ok(if r.is_busy() { Some(r) } else { None })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::iter::repeat;
+
+    use types::FroyoErrorKind;
+    use consts::MAX_NAME_LEN;
+    use super::Froyo;
+
+    const FROYOS: &'static [(&'static str, &'static str)] = &[
+        ("dev", "6d9c2b3e0f1a4c5d8e7f6a5b4c3d2e1f"),
+        ("backup", "de4417a09b8c4d2e9f0a1b2c3d4e5f60"),
+        ("6d9c2b3e", "de44b7c19b8c4d2e9f0a1b2c3d4e5f60"),
+    ];
+
+    #[test]
+    fn resolve_name() {
+        assert_eq!(Froyo::resolve("dev", FROYOS).unwrap(), 0);
+        assert_eq!(Froyo::resolve("backup", FROYOS).unwrap(), 1);
+    }
+
+    #[test]
+    fn resolve_prefers_name() {
+        assert_eq!(Froyo::resolve("6d9c2b3e", FROYOS).unwrap(), 2);
+    }
+
+    #[test]
+    fn resolve_id_prefix() {
+        assert_eq!(Froyo::resolve("6d9c2b3e0f", FROYOS).unwrap(), 0);
+        assert_eq!(Froyo::resolve("DE4417A0", FROYOS).unwrap(), 1);
+        assert_eq!(Froyo::resolve("de4417a0-9b8c", FROYOS).unwrap(), 1);
+    }
+
+    #[test]
+    fn resolve_short_prefix_not_found() {
+        for key in &["", "d", "de", "de4417a"] {
+            let err = Froyo::resolve(key, FROYOS).unwrap_err();
+            assert_eq!(err.kind(), Some(FroyoErrorKind::NotFound));
+        }
+    }
+
+    #[test]
+    fn resolve_ambiguous() {
+        let froyos = [("a", "de4417a09b8c"), ("b", "de4417a0ffff")];
+        let err = Froyo::resolve("de4417a0", &froyos).unwrap_err();
+        assert_eq!(err.kind(), None);
+        assert_eq!(Froyo::resolve("de4417a0f", &froyos).unwrap(), 1);
+    }
+
+    fn name_of_len(len: usize) -> String {
+        repeat('x').take(len).collect()
+    }
+
+    #[test]
+    fn validate_name_good() {
+        for name in &["dev", "a", "my-froyo_2.0+x", "..."] {
+            assert!(Froyo::validate_name(name).is_ok(), "{} should be valid", name);
+        }
+        assert!(Froyo::validate_name(&name_of_len(MAX_NAME_LEN)).is_ok());
+    }
+
+    #[test]
+    fn validate_name_bad() {
+        for name in &["", ".", "..", "-dev", "a/b", "a b", "caf\u{e9}"] {
+            assert!(Froyo::validate_name(name).is_err(), "{} should be invalid", name);
+        }
+        assert!(Froyo::validate_name(&name_of_len(MAX_NAME_LEN + 1)).is_err());
+    }
+}
@@ -0,0 +1,329 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Long-running D-Bus methods return a job object at once, instead of
+// blocking until the caller times out. The work is done in short
+// steps, run from the main loop between handling other messages.
+
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::sync::Arc;
+use std::error::Error;
+
+use dbus::{Connection, Message, MessageItem};
+use dbus::tree::{Factory, Tree, Property, Signal, MethodFn, EmitsChangedSignal};
+use time::{self, Timespec, Duration};
+
+use types::{FroyoResult, FroyoError, InternalError};
+use consts::JOB_KEEP_SECS;
+use dbus_api::{set_property, get_method_err};
+use auth::{self, Authorizer};
+
+const JOBS_PATH: &'static str = "/org/freedesktop/froyo/jobs";
+const JOB_IFACE: &'static str = "org.freedesktop.FroyoJob1";
+const JOB_FAILED_ERROR: &'static str = "org.freedesktop.DBus.Error.Failed";
+
+// What a step of a job's work reports
+pub enum JobStatus {
+    // There's more to do right away. Work done so far, and the total.
+    Working(u64, u64),
+    // Waiting for something else, such as a reshape, to get on. The
+    // next step runs when the main loop next checks on froyodevs.
+    Waiting(u64, u64),
+    // Done, with the job's result
+    Done(String),
+    // The work was stopped other than by the job's Cancel method
+    Cancelled,
+}
+
+// Does the next step of the job's work. Nothing else is handled while
+// a step runs, so each should be brief.
+pub type JobStep<'a> = Box<FnMut() -> FroyoResult<JobStatus> + 'a>;
+// Stops or undoes work that has started
+pub type JobCancel<'a> = Box<Fn() -> FroyoResult<()> + 'a>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JobState {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+impl JobState {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            JobState::Queued => "queued",
+            JobState::Running => "running",
+            JobState::Succeeded => "succeeded",
+            JobState::Failed => "failed",
+            JobState::Cancelled => "cancelled",
+        }
+    }
+}
+
+fn get_progress_msgitem(done: u64, total: u64) -> MessageItem {
+    MessageItem::Struct(vec![done.into(), total.into()])
+}
+
+pub struct Job<'a> {
+    conn: &'a Connection,
+    pub path: String,
+    pub state: JobState,
+    step: Option<JobStep<'a>>,
+    cancel: Option<JobCancel<'a>>,
+    waiting: bool,
+    result: String,
+    progress: (u64, u64),
+    finished: Option<Timespec>,
+    state_prop: Arc<Property<MethodFn<'a>>>,
+    progress_prop: Arc<Property<MethodFn<'a>>>,
+    result_prop: Arc<Property<MethodFn<'a>>>,
+    error_prop: Arc<Property<MethodFn<'a>>>,
+    error_name_prop: Arc<Property<MethodFn<'a>>>,
+    completed_signal: Arc<Signal>,
+}
+
+impl<'a> Job<'a> {
+    fn set_state(&mut self, state: JobState) -> FroyoResult<()> {
+        self.state = state;
+        set_property(self.conn, &self.state_prop, state.name().into())
+    }
+
+    fn set_progress(&mut self, done: u64, total: u64) -> FroyoResult<()> {
+        if self.progress == (done, total) {
+            return Ok(())
+        }
+        self.progress = (done, total);
+        set_property(self.conn, &self.progress_prop, get_progress_msgitem(done, total))
+    }
+
+    fn finish(&mut self, state: JobState, err: Option<&FroyoError>) -> FroyoResult<()> {
+        let (error, error_name) = match err {
+            Some(err) => (err.description().to_owned(),
+                          err.kind().map_or_else(|| JOB_FAILED_ERROR.to_owned(),
+                                                 |kind| kind.dbus_name())),
+            None => (String::new(), String::new()),
+        };
+
+        if state == JobState::Succeeded {
+            let total = match self.progress.1 {
+                0 => 1,
+                x => x,
+            };
+            try!(self.set_progress(total, total));
+        }
+        try!(set_property(self.conn, &self.result_prop, self.result.clone().into()));
+        try!(set_property(self.conn, &self.error_prop, error.clone().into()));
+        try!(set_property(self.conn, &self.error_name_prop, error_name.into()));
+        try!(self.set_state(state));
+        self.finished = Some(time::now().to_timespec());
+        self.step = None;
+        self.cancel = None;
+
+        let signal = self.completed_signal.emit(&[
+            state.name().into(), self.result.clone().into(), error.into()]);
+        let _ = self.conn.send(signal);
+
+        dbgp!("job {} {}", self.path, state.name());
+        Ok(())
+    }
+
+    // Has work to do right away
+    fn is_ready(&self) -> bool {
+        self.step.is_some() && !self.waiting
+    }
+
+    fn is_waiting(&self) -> bool {
+        self.step.is_some() && self.waiting
+    }
+
+    // Do the next step of the work
+    fn run_step(&mut self) -> FroyoResult<()> {
+        if self.state == JobState::Queued {
+            try!(self.set_state(JobState::Running));
+        }
+
+        let status = match self.step {
+            Some(ref mut step) => step(),
+            None => return Ok(()),
+        };
+        match status {
+            Ok(JobStatus::Working(done, total)) => {
+                self.waiting = false;
+                self.set_progress(done, total)
+            },
+            Ok(JobStatus::Waiting(done, total)) => {
+                self.waiting = true;
+                self.set_progress(done, total)
+            },
+            Ok(JobStatus::Done(result)) => {
+                self.result = result;
+                self.finish(JobState::Succeeded, None)
+            },
+            Ok(JobStatus::Cancelled) => self.finish(JobState::Cancelled, None),
+            Err(err) => self.finish(JobState::Failed, Some(&err)),
+        }
+    }
+
+    // Queued jobs haven't done anything yet. Running ones only stop
+    // between steps, so just need their cancel, if any, to undo the
+    // steps so far.
+    fn cancel(&mut self) -> FroyoResult<()> {
+        if self.step.is_none() {
+            return Err(FroyoError::Froyo(InternalError(
+                "Job has already finished".into())))
+        }
+
+        if self.state == JobState::Running {
+            if let Some(ref cancel) = self.cancel {
+                try!(cancel());
+            }
+        }
+
+        self.finish(JobState::Cancelled, None)
+    }
+}
+
+// Jobs have their own tree, so that methods in the other trees can
+// add to it.
+pub struct Jobs<'a> {
+    conn: &'a Connection,
+    auth: Rc<Authorizer>,
+    next_id: u64,
+    tree: Tree<MethodFn<'a>>,
+    jobs: BTreeMap<u64, Rc<RefCell<Job<'a>>>>,
+}
+
+impl<'a> Jobs<'a> {
+    pub fn new(c: &'a Connection, auth: &Rc<Authorizer>) -> Jobs<'a> {
+        Jobs {
+            conn: c,
+            auth: auth.clone(),
+            next_id: 0,
+            tree: Factory::new_fn().tree(),
+            jobs: BTreeMap::new(),
+        }
+    }
+
+    // Queue work, returning the job's object path. Cancelling the job
+    // needs the same authorization as the action that started it.
+    pub fn add(&mut self,
+               kind: &str,
+               target: &str,
+               action: &'static str,
+               step: JobStep<'a>,
+               cancel: Option<JobCancel<'a>>)
+               -> FroyoResult<String> {
+        let path = format!("{}/{}", JOBS_PATH, self.next_id);
+
+        let f = Factory::new_fn();
+        let mut iface = f.interface(JOB_IFACE);
+        iface = iface.add_p(f.property("Kind", kind)
+                            .emits_changed(EmitsChangedSignal::Const));
+        iface = iface.add_p(f.property("Target", target)
+                            .emits_changed(EmitsChangedSignal::Const));
+        let state_p = iface.add_p_ref(f.property("State", JobState::Queued.name()));
+        let progress_p = iface.add_p_ref(f.property(
+            "Progress", get_progress_msgitem(0, 0)));
+        let result_p = iface.add_p_ref(f.property("Result", ""));
+        let error_p = iface.add_p_ref(f.property("Error", ""));
+        let error_name_p = iface.add_p_ref(f.property("ErrorName", ""));
+        let completed_s = iface.add_s_ref(f.signal("Completed")
+                                          .arg(("state", "s"))
+                                          .arg(("result", "s"))
+                                          .arg(("error", "s")));
+
+        let job = Rc::new(RefCell::new(Job {
+            conn: self.conn,
+            path: path.clone(),
+            state: JobState::Queued,
+            step: Some(step),
+            cancel: cancel,
+            waiting: false,
+            result: String::new(),
+            progress: (0, 0),
+            finished: None,
+            state_prop: state_p,
+            progress_prop: progress_p,
+            result_prop: result_p,
+            error_prop: error_p,
+            error_name_prop: error_name_p,
+            completed_signal: completed_s,
+        }));
+
+        let auth_closed_over = self.auth.clone();
+        let job_closed_over = job.clone();
+        let iface = iface.add_m(
+            f.method("Cancel", move |m,_,_| {
+                try!(auth::check(&*auth_closed_over, m, action));
+
+                try!(job_closed_over.borrow_mut().cancel()
+                     .map_err(|err| get_method_err("Cancelling job", &err)));
+                Ok(vec![m.method_return()])
+            }));
+
+        let obj_path = f.object_path(path.clone())
+            .introspectable()
+            .add(iface);
+        try!(self.conn.register_object_path(&path));
+        self.tree.add_o_ref(obj_path);
+
+        self.jobs.insert(self.next_id, job);
+        self.next_id += 1;
+
+        dbgp!("job {} queued: {} {}", path, kind, target);
+        Ok(path)
+    }
+
+    pub fn handle(&self, m: &Message) -> Option<Vec<Message>> {
+        self.tree.handle(m)
+    }
+
+    // Are there steps to run right away?
+    pub fn has_ready(&self) -> bool {
+        self.jobs.values().any(|job| job.borrow().is_ready())
+    }
+
+    // Run the next step of each job with work to do now, oldest
+    // first. Returns whether any ran.
+    pub fn run_ready(&self) -> FroyoResult<bool> {
+        let mut ran = false;
+        for job in self.jobs.values() {
+            if job.borrow().is_ready() {
+                try!(job.borrow_mut().run_step());
+                ran = true;
+            }
+        }
+        Ok(ran)
+    }
+
+    // Check on waiting jobs, and forget ones that finished a while ago
+    pub fn poll(&mut self) -> FroyoResult<()> {
+        for job in self.jobs.values() {
+            if job.borrow().is_waiting() {
+                try!(job.borrow_mut().run_step());
+            }
+        }
+
+        let now = time::now().to_timespec();
+        let expired = self.jobs.iter()
+            .filter(|&(_, job)| match job.borrow().finished {
+                Some(finished) => finished + Duration::seconds(JOB_KEEP_SECS) < now,
+                None => false,
+            })
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in expired {
+            let job = self.jobs.remove(&id).unwrap();
+            let path = job.borrow().path.clone();
+            self.conn.unregister_object_path(&path);
+            self.tree.remove(&path.into());
+        }
+
+        Ok(())
+    }
+}
@@ -46,28 +46,52 @@ mod froyo;
 mod blockdev;
 mod raid;
 mod mirror;
+mod plan;
 mod dmdevice;
 mod thin;
 mod util;
 mod dbus_api;
+mod auth;
+mod jobs;
+mod uevent;
+mod dmevent;
 
+use std::io;
 use std::io::Write;
+use std::thread;
 use std::error::Error;
 use std::process::exit;
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use std::cell::RefCell;
 use std::borrow::Cow;
+use std::cmp::{max, min};
+use std::collections::BTreeSet;
+use std::fs::OpenOptions;
+use std::os::unix::io::AsRawFd;
 
 use clap::{App, Arg, SubCommand, ArgMatches};
 use bytesize::ByteSize;
 use dbus::{Connection, BusType, Message, MessageItem, FromMessageItem, Props};
-use dbus::{ConnectionItem, MessageType};
+use dbus::{ConnectionItem, MessageType, WatchEvent};
+use dbus::tree::{Tree, MethodFn};
 use time::{Timespec, Duration};
-
-use types::{FroyoResult, FroyoError, InternalError};
-use consts::{SECTOR_SIZE, DBUS_TIMEOUT};
-use froyo::Froyo;
+use nix::poll::{poll, PollFd, EventFlags, POLLIN, POLLOUT};
+use nix::errno::{EAGAIN, EINTR};
+use nix::fcntl::{flock, FlockArg};
+use nix::sys::signal::{SIGTERM, SIGINT};
+use nix::sys::signalfd::{SignalFd, SigSet, SFD_NONBLOCK, SFD_CLOEXEC};
+
+use types::{FroyoResult, FroyoError, FroyoErrorKind, InternalError};
+use consts::{SECTOR_SIZE, DBUS_TIMEOUT, THIN_EXTEND_THRESHOLD_PCT, THIN_EXTEND_PCT};
+use consts::{JOB_POLL_MS, STATE_CHECK_SECS, DAEMON_LOCK_PATH};
+use consts::{THIN_META_BACKUP_DIR, THIN_META_BACKUP_INTERVAL_SECS, THIN_META_BACKUP_KEEP};
+use froyo::{Froyo, ThinMetaCheck};
+use util::short_id;
+use auth::{Authorizer, UidAuthorizer, PolkitAuthorizer};
+use jobs::Jobs;
+use uevent::UeventSocket;
+use dmevent::DmEvents;
 
 
 // We are given BlockDevs to start.
@@ -85,7 +109,7 @@ trait FroyoDbusConnection {
 
 impl FroyoDbusConnection for Connection {
     fn froyo_connect() -> FroyoResult<Connection> {
-        let c = try!(Connection::get_private(BusType::Session));
+        let c = try!(Connection::get_private(BusType::System));
         Ok(c)
     }
     fn froyo_paths(&self) -> FroyoResult<Vec<String>> {
@@ -100,18 +124,29 @@ impl FroyoDbusConnection for Connection {
         let mut froyos = Vec::new();
         let array: &Vec<MessageItem> = FromMessageItem::from(&reply[0]).unwrap();
         for item in array {
-            let (k, _) = FromMessageItem::from(item).unwrap();
+            let (k, v): (&MessageItem, &MessageItem) = FromMessageItem::from(item).unwrap();
             let kstr: &str = FromMessageItem::from(k).unwrap();
-            if kstr != "/org/freedesktop/froyodevs" {
+
+            // Skip the froyodevs object, and each froyodev's
+            // blockdev, raid and volume objects
+            let ifaces: &Vec<MessageItem> = FromMessageItem::from(v).unwrap();
+            let is_froyodev = ifaces.iter().any(|i| {
+                let (iname, _): (&MessageItem, &MessageItem) = FromMessageItem::from(i).unwrap();
+                let iname: &str = FromMessageItem::from(iname).unwrap();
+                iname == "org.freedesktop.FroyoDevice1"
+            });
+            if is_froyodev {
                 froyos.push(kstr.to_owned());
             }
         }
         Ok(froyos)
     }
 
+    // Find a froyodev by name or uuid prefix
     fn froyo_path(&self, name: &str) -> FroyoResult<String> {
         let froyos = try!(self.froyo_paths());
 
+        let mut names = Vec::new();
         for fpath in &froyos {
             let p = Props::new(
                 self,
@@ -121,13 +156,18 @@ impl FroyoDbusConnection for Connection {
                 DBUS_TIMEOUT);
             let item = p.get("Name").unwrap();
             let froyo_name: &str = FromMessageItem::from(&item).unwrap();
-            if name == froyo_name {
-                return Ok(fpath.to_owned());
-            }
+            names.push(froyo_name.to_owned());
         }
 
-        Err(FroyoError::Froyo(InternalError(
-            format!("Froyodev \"{}\" not found", name).into())))
+        // The last path element is the froyodev's uuid
+        let names_and_ids = froyos.iter().zip(names.iter())
+            .map(|(fpath, froyo_name)| {
+                (&**froyo_name, fpath.rsplit('/').next().unwrap_or(""))
+            })
+            .collect::<Vec<_>>();
+        let idx = try!(Froyo::resolve(name, &names_and_ids));
+
+        Ok(froyos[idx].clone())
     }
 }
 
@@ -195,7 +235,12 @@ fn status(args: &ArgMatches) -> FroyoResult<()> {
             }
             if 0x400 & r_status != 0 { stats.push("Reshaping".into()) }
             if 0x800 & r_status != 0 { stats.push("Throttled".into()) }
-            if 0xfffff000 & r_status != 0 { stats.push(
+            if 0x1000 & r_status != 0 { stats.push("Filesystem grow pending".into()) }
+            if 0x2000 & r_status != 0 { stats.push("Filesystem grow failed".into()) }
+            if 0x4000 & r_status != 0 { stats.push("Thin pool read-only".into()) }
+            if 0x8000 & r_status != 0 { stats.push("Thin pool out of space".into()) }
+            if 0x10000 & r_status != 0 { stats.push("Reshape paused".into()) }
+            if 0xfffe0000 & r_status != 0 { stats.push(
                 format!("Unenumerated issue: {:x}", r_status).into())
             }
             stats.join(", ").into()
@@ -220,6 +265,60 @@ fn status(args: &ArgMatches) -> FroyoResult<()> {
              ByteSize::b(space as usize).to_string(true),
              ByteSize::b(total as usize).to_string(true));
 
+    let err_msg = "Unexpected format of ReshapeProgress property";
+    let progress = try!(p.get("ReshapeProgress"));
+    let progress_vals: &Vec<_> = try!(
+        progress.inner()
+            .map_err(|_| FroyoError::Froyo(InternalError(err_msg.into()))));
+    // Phase, step done and total, overall done and total, ETA
+    if progress_vals.len() != 6 {
+        return Err(FroyoError::Froyo(InternalError(err_msg.into())))
+    }
+    let phase: &str = try!(
+        progress_vals[0].inner()
+            .map_err(|_| FroyoError::Froyo(InternalError(err_msg.into()))));
+    if !phase.is_empty() {
+        let mut sectors = Vec::new();
+        for val in &progress_vals[1..] {
+            let sects: u64 = try!(
+                val.inner()
+                    .map_err(|_| FroyoError::Froyo(InternalError(err_msg.into()))));
+            sectors.push(sects);
+        }
+        let pct = |done: u64, total: u64| if total == 0 { 100 } else { done * 100 / total };
+        let eta_str: Cow<str> = match sectors[4] {
+            0 => "unknown".into(),
+            secs => format!("{}h{:02}m", secs / 3600, (secs % 3600) / 60).into(),
+        };
+        println!("Reshape: {}, step {}% done, overall {}% done ({} of {}), ETA {}",
+                 phase,
+                 pct(sectors[0], sectors[1]),
+                 pct(sectors[2], sectors[3]),
+                 ByteSize::b((sectors[2] * SECTOR_SIZE) as usize).to_string(true),
+                 ByteSize::b((sectors[3] * SECTOR_SIZE) as usize).to_string(true),
+                 eta_str);
+    }
+
+    let err_msg = "Unexpected format of ReshapeBlockers property";
+    let blockers = try!(p.get("ReshapeBlockers"));
+    let blocker_vec: &Vec<_> = try!(
+        blockers.inner()
+            .map_err(|_| FroyoError::Froyo(InternalError(err_msg.into()))));
+    if !blocker_vec.is_empty() {
+        println!("Cannot reshape because:");
+    }
+    for blocker in blocker_vec {
+        let inner_vals: &Vec<_> = try!(
+            blocker.inner().map_err(|_| FroyoError::Froyo(InternalError(err_msg.into()))));
+        if inner_vals.len() != 4 {
+            return Err(FroyoError::Froyo(InternalError(err_msg.into())))
+        }
+        let desc: &str = try!(
+            inner_vals[3].inner()
+                .map_err(|_| FroyoError::Froyo(InternalError(err_msg.into()))));
+        println!("  {}", desc);
+    }
+
     let err_msg = "Unexpected format of BlockDevices property";
     let bdevs = try!(p.get("BlockDevices"));
     let bdev_vec: &Vec<_> = try!(
@@ -269,7 +368,8 @@ fn add(args: &ArgMatches) -> FroyoResult<()> {
             "org.freedesktop.FroyoDevice1",
             "AddBlockDevice").unwrap();
         m.append_items(&[path.to_string_lossy().into_owned().into(), force.into()]);
-        try!(c.send_with_reply_and_block(m, DBUS_TIMEOUT));
+        let r = try!(c.send_with_reply_and_block(m, DBUS_TIMEOUT));
+        try!(wait_for_job(&c, &try!(job_path(&r))));
     }
 
     Ok(())
@@ -313,6 +413,26 @@ fn create(args: &ArgMatches) -> FroyoResult<()> {
         .collect();
     let force = args.is_present("force");
 
+    // Only send the thin pool options given, the rest default
+    let mut options: Vec<(&str, MessageItem)> = Vec::new();
+    if let Some(x) = args.value_of("block-size") {
+        options.push(("DataBlockSectors", (try!(parse_size(x)) / SECTOR_SIZE).into()));
+    }
+    if args.is_present("zero") {
+        options.push(("ZeroBlocks", true.into()));
+    }
+    if let Some(discard) = args.value_of("discard") {
+        options.push(("Discard", discard.into()));
+    }
+    if args.is_present("error-if-no-space") {
+        options.push(("ErrorIfNoSpace", true.into()));
+    }
+    let options = options.into_iter()
+        .map(|(key, value)| MessageItem::DictEntry(
+            Box::new(key.into()),
+            Box::new(MessageItem::Variant(Box::new(value)))))
+        .collect();
+
     let c = try!(Connection::froyo_connect());
 
     let mut m = Message::new_method_call(
@@ -323,8 +443,10 @@ fn create(args: &ArgMatches) -> FroyoResult<()> {
     m.append_items(&[
         name.into(),
         MessageItem::new_array(dev_paths).unwrap(),
-        force.into()]);
-    try!(c.send_with_reply_and_block(m, DBUS_TIMEOUT));
+        force.into(),
+        MessageItem::Array(options, "{sv}".into())]);
+    let r = try!(c.send_with_reply_and_block(m, DBUS_TIMEOUT));
+    try!(wait_for_job(&c, &try!(job_path(&r))));
 
     dbgp!("Froyodev {} created", name);
 
@@ -351,8 +473,70 @@ fn rename(args: &ArgMatches) -> FroyoResult<()> {
     Ok(())
 }
 
+fn reshape_dry_run(name: &str) -> FroyoResult<()> {
+    let c = try!(Connection::froyo_connect());
+    let fpath = try!(c.froyo_path(name));
+
+    let m = Message::new_method_call(
+        "org.freedesktop.Froyo1",
+        &fpath,
+        "org.freedesktop.FroyoDevice1",
+        "ReshapePlan").unwrap();
+    let r = try!(c.send_with_reply_and_block(m, DBUS_TIMEOUT));
+
+    let err = || FroyoError::Froyo(InternalError("Unexpected reply from ReshapePlan".into()));
+    let reply = r.get_items();
+    if reply.len() < 4 {
+        return Err(err())
+    }
+    let reshapable: bool = try!(reply[0].inner().map_err(|_| err()));
+    let blockers: &Vec<_> = try!(reply[1].inner().map_err(|_| err()));
+    let steps: &Vec<_> = try!(reply[2].inner().map_err(|_| err()));
+    let error: &str = try!(reply[3].inner().map_err(|_| err()));
+
+    if reshapable {
+        println!("Froyodev {} can reshape", name);
+    } else {
+        println!("Froyodev {} cannot reshape:", name);
+        for blocker in blockers {
+            let blocker: &str = try!(blocker.inner().map_err(|_| err()));
+            println!("  {}", blocker);
+        }
+    }
+
+    if steps.is_empty() {
+        println!("Reshape would have nothing to do");
+    } else {
+        println!("Reshape would:");
+    }
+    for (num, step) in steps.iter().enumerate() {
+        let vals: &Vec<_> = try!(step.inner().map_err(|_| err()));
+        let desc: &str = try!(vals[1].inner().map_err(|_| err()));
+        println!("  {}. {}", num + 1, desc);
+    }
+
+    if !error.is_empty() {
+        println!("Then fail: {}", error);
+    }
+
+    Ok(())
+}
+
 fn reshape(args: &ArgMatches) -> FroyoResult<()> {
     let name = args.value_of("froyodev").unwrap();
+    if args.is_present("dry-run") {
+        return reshape_dry_run(name)
+    }
+
+    let (method, action) = if args.is_present("pause") {
+        ("ReshapePause", "pausing")
+    } else if args.is_present("resume") {
+        ("ReshapeResume", "resuming")
+    } else if args.is_present("cancel") {
+        ("ReshapeCancel", "cancelling")
+    } else {
+        ("Reshape", "starting")
+    };
 
     let c = try!(Connection::froyo_connect());
     let fpath = try!(c.froyo_path(name));
@@ -361,10 +545,260 @@ fn reshape(args: &ArgMatches) -> FroyoResult<()> {
         "org.freedesktop.Froyo1",
         &fpath,
         "org.freedesktop.FroyoDevice1",
-        "Reshape").unwrap();
+        method).unwrap();
+    let r = try!(c.send_with_reply_and_block(m, DBUS_TIMEOUT));
+
+    dbgp!("Froyodev {} {} reshape", name, action);
+
+    // Only starting a reshape makes a job
+    if method == "Reshape" {
+        let job = try!(job_path(&r));
+        if args.is_present("no-wait") {
+            println!("{}", job);
+        } else {
+            try!(wait_for_job(&c, &job));
+        }
+    }
+
+    Ok(())
+}
+
+// The job path a method returned
+fn job_path(reply: &Message) -> FroyoResult<String> {
+    reply.get_items().get(0)
+        .and_then(|i| i.inner::<&str>().ok())
+        .map(|p| p.to_owned())
+        .ok_or_else(|| FroyoError::Froyo(InternalError(
+            "Expected a job path in reply".into())))
+}
+
+// Show a job's progress until it finishes, returning its result
+fn wait_for_job(c: &Connection, job_path: &str) -> FroyoResult<String> {
+    let p = Props::new(
+        c,
+        "org.freedesktop.Froyo1",
+        job_path,
+        "org.freedesktop.FroyoJob1",
+        DBUS_TIMEOUT);
+    let err = || FroyoError::Froyo(InternalError("Unexpected job property".into()));
+
+    let kind_msg = try!(p.get("Kind"));
+    let kind = try!(kind_msg.inner::<&str>().map_err(|_| err()));
+    let mut shown = false;
+
+    loop {
+        let props = try!(p.get_all());
+        let prop_str = |name: &str| -> FroyoResult<String> {
+            props.get(name)
+                .and_then(|i| i.inner::<&str>().ok())
+                .map(|s| s.to_owned())
+                .ok_or_else(&err)
+        };
+        let state = try!(prop_str("State"));
+
+        if let Some(&MessageItem::Struct(ref progress)) = props.get("Progress") {
+            let done: u64 = try!(progress.get(0).and_then(|i| i.inner().ok())
+                                 .ok_or_else(&err));
+            let total: u64 = try!(progress.get(1).and_then(|i| i.inner().ok())
+                                  .ok_or_else(&err));
+            if state == "running" && total != 0 {
+                print!("\r{}: {}%", kind, done * 100 / total);
+                try!(io::stdout().flush());
+                shown = true;
+            }
+        }
+
+        match &*state {
+            "queued" | "running" => {},
+            "succeeded" => {
+                if shown {
+                    println!("\r{}: done", kind);
+                }
+                return prop_str("Result")
+            },
+            "cancelled" => {
+                if shown {
+                    println!("");
+                }
+                return Err(FroyoError::Froyo(InternalError(
+                    format!("{} was cancelled", kind).into())))
+            },
+            _ => {
+                if shown {
+                    println!("");
+                }
+                let msg = format!("{} failed: {}", kind, try!(prop_str("Error")));
+                let error_kind = props.get("ErrorName")
+                    .and_then(|i| i.inner::<&str>().ok())
+                    .and_then(FroyoErrorKind::from_dbus_name);
+                return Err(match error_kind {
+                    Some(error_kind) => FroyoError::Typed(error_kind, InternalError(msg.into())),
+                    None => FroyoError::Froyo(InternalError(msg.into())),
+                })
+            },
+        }
+
+        thread::sleep(Duration::milliseconds(JOB_POLL_MS).to_std().unwrap());
+    }
+}
+
+// Parse a size like "512", "64K" or "2T" into bytes
+fn parse_size(size: &str) -> FroyoResult<u64> {
+    let (num, mult) = match size.chars().last() {
+        Some('K') | Some('k') => (&size[..size.len()-1], 1024),
+        Some('M') | Some('m') => (&size[..size.len()-1], 1024 * 1024),
+        Some('G') | Some('g') => (&size[..size.len()-1], 1024 * 1024 * 1024),
+        Some('T') | Some('t') => (&size[..size.len()-1], 1024 * 1024 * 1024 * 1024),
+        _ => (size, 1),
+    };
+
+    num.parse::<u64>().ok()
+        .and_then(|n| n.checked_mul(mult))
+        .ok_or_else(|| FroyoError::Froyo(InternalError(
+            format!("Invalid size \"{}\"", size).into())))
+}
+
+fn volume_policy(args: &ArgMatches) -> FroyoResult<()> {
+    let name = args.value_of("froyodev").unwrap();
+    let volume = args.value_of("volume").unwrap();
+    let auto_extend = !args.is_present("off");
+    let threshold = match args.value_of("threshold") {
+        Some(x) => try!(x.parse::<u8>().map_err(|_| FroyoError::Froyo(InternalError(
+            format!("Invalid threshold \"{}\"", x).into())))),
+        None => THIN_EXTEND_THRESHOLD_PCT,
+    };
+    let extend = match args.value_of("extend") {
+        Some(x) => try!(x.parse::<u8>().map_err(|_| FroyoError::Froyo(InternalError(
+            format!("Invalid extend percentage \"{}\"", x).into())))),
+        None => THIN_EXTEND_PCT,
+    };
+    let max_sectors = match args.value_of("max") {
+        Some(x) => try!(parse_size(x)) / SECTOR_SIZE,
+        None => 0,
+    };
+
+    let c = try!(Connection::froyo_connect());
+    let fpath = try!(c.froyo_path(name));
+
+    let mut m = Message::new_method_call(
+        "org.freedesktop.Froyo1",
+        &fpath,
+        "org.freedesktop.FroyoDevice1",
+        "SetVolumeSizePolicy").unwrap();
+    m.append_items(&[volume.into(), auto_extend.into(), threshold.into(),
+                     extend.into(), max_sectors.into()]);
     try!(c.send_with_reply_and_block(m, DBUS_TIMEOUT));
 
-    dbgp!("Froyodev {} starting reshape", name);
+    dbgp!("Volume {} size policy set", volume);
+
+    Ok(())
+}
+
+fn meta_backup(args: &ArgMatches) -> FroyoResult<()> {
+    let name = args.value_of("froyodev").unwrap();
+    let enabled = !args.is_present("off");
+    let dir = args.value_of("dir").unwrap_or(THIN_META_BACKUP_DIR);
+    let interval = match args.value_of("interval") {
+        Some(x) => try!(x.parse::<u64>().map_err(|_| FroyoError::Froyo(InternalError(
+            format!("Invalid interval \"{}\"", x).into())))),
+        None => THIN_META_BACKUP_INTERVAL_SECS,
+    };
+    let keep = match args.value_of("keep") {
+        Some(x) => try!(x.parse::<u32>().map_err(|_| FroyoError::Froyo(InternalError(
+            format!("Invalid backup count \"{}\"", x).into())))),
+        None => THIN_META_BACKUP_KEEP,
+    };
+
+    let c = try!(Connection::froyo_connect());
+    let fpath = try!(c.froyo_path(name));
+
+    let mut m = Message::new_method_call(
+        "org.freedesktop.Froyo1",
+        &fpath,
+        "org.freedesktop.FroyoDevice1",
+        "SetMetaBackupPolicy").unwrap();
+    m.append_items(&[enabled.into(), dir.into(), interval.into(), keep.into()]);
+    try!(c.send_with_reply_and_block(m, DBUS_TIMEOUT));
+
+    dbgp!("Froyodev {} metadata backup policy set", name);
+
+    Ok(())
+}
+
+fn recovery_rate(args: &ArgMatches) -> FroyoResult<()> {
+    let name = args.value_of("froyodev").unwrap();
+    let parse_kib = |arg: &str| -> FroyoResult<u64> {
+        match args.value_of(arg) {
+            Some(x) => x.parse::<u64>().map_err(|_| FroyoError::Froyo(InternalError(
+                format!("Invalid rate \"{}\"", x).into()))),
+            None => Ok(0),
+        }
+    };
+    let min_kib = try!(parse_kib("min"));
+    let max_kib = try!(parse_kib("max"));
+    let sched_min_kib = try!(parse_kib("schedule-min"));
+    let sched_max_kib = try!(parse_kib("schedule-max"));
+
+    let (scheduled, start_hour, end_hour) = match args.value_of("schedule") {
+        Some(x) => {
+            let hours = x.split('-')
+                .map(|h| h.parse::<u8>())
+                .collect::<Result<Vec<_>, _>>();
+            match hours {
+                Ok(ref h) if h.len() == 2 => (true, h[0], h[1]),
+                _ => return Err(FroyoError::Froyo(InternalError(
+                    format!("Invalid schedule \"{}\", expected e.g. 22-6", x).into()))),
+            }
+        },
+        None => (false, 0u8, 0u8),
+    };
+
+    let c = try!(Connection::froyo_connect());
+    let fpath = try!(c.froyo_path(name));
+
+    let mut m = Message::new_method_call(
+        "org.freedesktop.Froyo1",
+        &fpath,
+        "org.freedesktop.FroyoDevice1",
+        "SetRecoveryRate").unwrap();
+    m.append_items(&[min_kib.into(), max_kib.into(), scheduled.into(),
+                     start_hour.into(), end_hour.into(),
+                     sched_min_kib.into(), sched_max_kib.into()]);
+    try!(c.send_with_reply_and_block(m, DBUS_TIMEOUT));
+
+    dbgp!("Froyodev {} recovery rate set", name);
+
+    Ok(())
+}
+
+fn trim(args: &ArgMatches) -> FroyoResult<()> {
+    let volume = args.value_of("volume").unwrap();
+
+    let c = try!(Connection::froyo_connect());
+
+    let mut m = Message::new_method_call(
+        "org.freedesktop.Froyo1",
+        "/org/freedesktop/froyo",
+        "org.freedesktop.FroyoService1",
+        "Trim").unwrap();
+    m.append_items(&[volume.into()]);
+    let r = try!(c.send_with_reply_and_block(m, DBUS_TIMEOUT));
+
+    let err_msg = "Unexpected reply from Trim";
+    let reply = r.get_items();
+    if reply.len() < 3 {
+        return Err(FroyoError::Froyo(InternalError(err_msg.into())))
+    }
+    let vals = try!(reply.iter()
+                    .map(|i| i.inner::<u64>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|_| FroyoError::Froyo(InternalError(err_msg.into()))));
+    let (before, after, block_size) = (vals[0], vals[1], vals[2]);
+    let reclaimed = before.saturating_sub(after);
+
+    println!("Reclaimed {} ({} of {} used blocks)",
+             ByteSize::b((reclaimed * block_size) as usize).to_string(true),
+             reclaimed, before);
 
     Ok(())
 }
@@ -405,68 +839,377 @@ fn teardown(args: &ArgMatches) -> FroyoResult<()> {
     Ok(())
 }
 
+fn available(_args: &ArgMatches) -> FroyoResult<()> {
+    let c = try!(Connection::froyo_connect());
+
+    let m = Message::new_method_call(
+        "org.freedesktop.Froyo1",
+        "/org/freedesktop/froyo",
+        "org.freedesktop.FroyoService1",
+        "ListAvailable").unwrap();
+    let r = try!(c.send_with_reply_and_block(m, DBUS_TIMEOUT));
+
+    let err = || FroyoError::Froyo(InternalError("Unexpected reply from ListAvailable".into()));
+    let reply = r.get_items();
+    let entries: &Vec<MessageItem> = try!(
+        reply.get(0).and_then(|i| i.inner().ok()).ok_or_else(&err));
+
+    for entry in entries {
+        let vals: &Vec<MessageItem> = try!(entry.inner().map_err(|_| err()));
+        if vals.len() < 5 {
+            return Err(err())
+        }
+        let id: &str = try!(vals[0].inner().map_err(|_| err()));
+        let name: &str = try!(vals[1].inner().map_err(|_| err()));
+        let present: u32 = try!(vals[2].inner().map_err(|_| err()));
+        let total: u32 = try!(vals[3].inner().map_err(|_| err()));
+        let active: bool = try!(vals[4].inner().map_err(|_| err()));
+        println!("{} {} {}/{} block devices, {}",
+                 short_id(id), name, present, total,
+                 if active { "active" } else { "inactive" });
+    }
+
+    Ok(())
+}
+
+fn activate(args: &ArgMatches) -> FroyoResult<()> {
+    let name = args.value_of("froyodev").unwrap();
+
+    let c = try!(Connection::froyo_connect());
+
+    let mut m = Message::new_method_call(
+        "org.freedesktop.Froyo1",
+        "/org/freedesktop/froyo",
+        "org.freedesktop.FroyoService1",
+        "Activate").unwrap();
+    m.append_items(&[name.into()]);
+    try!(c.send_with_reply_and_block(m, DBUS_TIMEOUT));
+
+    dbgp!("Froyodev {} activated", name);
+
+    Ok(())
+}
+
+fn rescan(_args: &ArgMatches) -> FroyoResult<()> {
+    let c = try!(Connection::froyo_connect());
+
+    let m = Message::new_method_call(
+        "org.freedesktop.Froyo1",
+        "/org/freedesktop/froyo",
+        "org.freedesktop.FroyoService1",
+        "Rescan").unwrap();
+    let r = try!(c.send_with_reply_and_block(m, DBUS_TIMEOUT));
+
+    let reply = r.get_items();
+    let paths: &Vec<MessageItem> = try!(
+        reply.get(0).and_then(|i| i.inner().ok())
+            .ok_or_else(|| FroyoError::Froyo(InternalError(
+                "Unexpected reply from Rescan".into()))));
+    for path in paths {
+        let path: &str = try!(path.inner().map_err(|_| FroyoError::Froyo(InternalError(
+            "Unexpected reply from Rescan".into()))));
+        let p = Props::new(
+            &c,
+            "org.freedesktop.Froyo1",
+            path,
+            "org.freedesktop.FroyoDevice1",
+            DBUS_TIMEOUT);
+        let item = try!(p.get("Name"));
+        let name: &str = FromMessageItem::from(&item).unwrap();
+        println!("Activated {}", name);
+    }
+
+    Ok(())
+}
+
 fn dump_meta(args: &ArgMatches) -> FroyoResult<()> {
     let name = args.value_of("froyodevname").unwrap();
     match try!(Froyo::find(&name)) {
         Some(f) =>
             println!("{}", try!(f.to_metadata_pretty())),
-        None => return Err(FroyoError::Froyo(InternalError(
+        None => return Err(FroyoError::Typed(FroyoErrorKind::NotFound, InternalError(
             format!("Froyodev \"{}\" not found", name).into()))),
     }
 
     Ok(())
 }
 
-fn dbus_server(_args: &ArgMatches) -> FroyoResult<()> {
+fn check_thin_meta(args: &ArgMatches) -> FroyoResult<()> {
+    let name = args.value_of("froyodevname").unwrap();
+    let repair = args.is_present("repair");
+
+    match try!(Froyo::check_thin_meta(name, repair, &|msg: &str| println!("{}", msg))) {
+        ThinMetaCheck::Good =>
+            println!("Thin pool metadata for {} is good", name),
+        ThinMetaCheck::Repaired(_) =>
+            println!("Switched {} to repaired thin pool metadata", name),
+    }
+
+    Ok(())
+}
+
+fn restore_thin_meta(args: &ArgMatches) -> FroyoResult<()> {
+    let name = args.value_of("froyodevname").unwrap();
+    let backup = args.value_of("backup").map(Path::new);
+
+    let backup = try!(Froyo::restore_thin_meta(name, backup, &|msg: &str| println!("{}", msg)));
+    println!("Switched {} to thin pool metadata restored from {}",
+             name, backup.display());
+
+    Ok(())
+}
+
+fn dbus_server(args: &ArgMatches) -> FroyoResult<()> {
+    let auth: Rc<Authorizer> = match args.value_of("auth").unwrap_or("uid") {
+        "polkit" => Rc::new(try!(PolkitAuthorizer::new())),
+        _ => {
+            let mut uids = Vec::new();
+            for uid in args.values_of("allow-uid").unwrap_or_else(Vec::new) {
+                uids.push(try!(uid.parse::<u32>().map_err(|_| FroyoError::Froyo(
+                    InternalError(format!("Invalid uid: {}", uid).into())))));
+            }
+            Rc::new(try!(UidAuthorizer::new(&uids)))
+        },
+    };
+
+    // Only one daemon may manage the disks. The lock goes away when
+    // we exit, however that happens.
+    let lock_file = try!(OpenOptions::new().write(true).create(true).open(DAEMON_LOCK_PATH));
+    match flock(lock_file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+        Ok(_) => {},
+        Err(nix::Error::Sys(EAGAIN)) => return Err(FroyoError::Typed(
+            FroyoErrorKind::InUse, InternalError(
+                "Another froyo dbus_server is already running".into()))),
+        Err(e) => return Err(e.into()),
+    }
+
+    // SIGTERM and SIGINT are handled from the main loop. Block them
+    // before starting any threads, which inherit the mask.
+    let mut sigs = SigSet::empty();
+    try!(sigs.add(SIGTERM));
+    try!(sigs.add(SIGINT));
+    try!(sigs.thread_block());
+    let mut signals = try!(SignalFd::with_flags(&sigs, SFD_NONBLOCK | SFD_CLOEXEC));
+
     let c = try!(Connection::froyo_connect());
+    // Block devices coming and going
+    let uevents = try!(UeventSocket::new());
+    // The thin pool, raids and reshape mirrors hitting thresholds,
+    // finishing syncing or failing
+    let dm_events = try!(DmEvents::new());
+
     let froyos = try!(Froyo::find_all());
     let froyos = froyos.into_iter()
         .map(|f| Rc::new(RefCell::new(f)))
         .collect::<Vec<_>>();
     let mut froyos = Rc::new(RefCell::new(froyos));
 
+    // However serving ends, save every froyodev's state on the way out
+    let result = serve(&c, &mut froyos, &auth, &uevents, &dm_events, &mut signals);
+
+    for froyo in &*froyos.borrow() {
+        let froyo = froyo.borrow();
+        if let Err(e) = froyo.shutdown() {
+            log_err(&froyo.name, &e);
+        }
+    }
+
+    result
+}
+
+fn serve<'a>(c: &'a Connection,
+             froyos: &mut Rc<RefCell<Vec<Rc<RefCell<Froyo<'a>>>>>>,
+             auth: &Rc<Authorizer>,
+             uevents: &UeventSocket,
+             dm_events: &DmEvents,
+             signals: &mut SignalFd)
+             -> FroyoResult<()> {
     // We can't change a tree from within the tree. So instead
     // register two trees, one with Create and Destroy and another for
-    // querying/changing active froyodevs/
-    let child_tree = try!(dbus_api::get_child_tree(&c, &froyos.borrow()));
-    let base_tree = try!(dbus_api::get_base_tree(&c, &mut froyos, &child_tree));
+    // querying/changing active froyodevs/. Jobs started by methods in
+    // either have a third.
+    let jobs = Rc::new(RefCell::new(Jobs::new(c, auth)));
+    let child_tree = try!(dbus_api::get_child_tree(c, froyos, auth, &jobs));
+    let base_tree = try!(dbus_api::get_base_tree(c, froyos, &child_tree,
+                                                 auth, &jobs));
+
+    // Objects for each froyodev's blockdevs, raids and volumes also
+    // live in the child tree, but are kept up to date from here.
+    let mut components = dbus_api::DbusComponents::new();
+    try!(components.update(c, &child_tree, &froyos.borrow()));
 
-    // TODO: event loop needs to handle dbus and also dm events (or polling)
-    // so we can extend/reshape/delay/whatever in a timely fashion
     let mut last_time = Timespec::new(0, 0);
-    for c_item in c.iter(10000) {
-        if let ConnectionItem::MethodCall(ref msg) = c_item {
-            if msg.msg_type() != MessageType::MethodCall {
-                continue
+    loop {
+        for froyo in &*froyos.borrow() {
+            dm_events.watch(&froyo.borrow().dm_event_names());
+        }
+
+        // Messages libdbus has already read, e.g. while waiting for a
+        // polkit reply, won't wake poll(), so dispatch those first.
+        let mut items = c.watch_handle(-1, 0).collect::<Vec<_>>();
+        if items.is_empty() {
+            let watches = c.watch_fds();
+            let mut fds = watches.iter()
+                .map(|w| {
+                    let mut events = EventFlags::empty();
+                    if w.readable() {
+                        events.insert(POLLIN);
+                    }
+                    if w.writable() {
+                        events.insert(POLLOUT);
+                    }
+                    PollFd { fd: w.fd(), events: events, revents: EventFlags::empty() }
+                })
+                .collect::<Vec<_>>();
+            for fd in &[uevents.fd(), dm_events.fd(), signals.as_raw_fd()] {
+                fds.push(PollFd { fd: *fd, events: POLLIN, revents: EventFlags::empty() });
+            }
+
+            // Until the next periodic check is due, or not at all if
+            // jobs have steps to run
+            let now = time::now().to_timespec();
+            let timeout = (last_time + Duration::seconds(STATE_CHECK_SECS) - now)
+                .num_milliseconds();
+            let timeout = match jobs.borrow().has_ready() {
+                true => 0,
+                false => max(0, min(timeout, STATE_CHECK_SECS * 1000)) as i32,
+            };
+            match poll(&mut fds, timeout) {
+                Ok(_) | Err(nix::Error::Sys(EINTR)) => {},
+                Err(e) => return Err(e.into()),
+            }
+
+            for (w, pfd) in watches.iter().zip(&fds) {
+                if !pfd.revents.is_empty() {
+                    let flags = WatchEvent::from_revents(pfd.revents.bits());
+                    items.extend(c.watch_handle(w.fd(), flags));
+                }
             }
+        }
 
-            if let Some(v) = base_tree.handle(msg) {
-                // Probably the wisest is to ignore any send errors here -
-                // maybe the remote has disconnected during our processing.
-                for m in v { let _ = c.send(m); };
-            } else if let Some(v) = child_tree.borrow().handle(msg) {
-                for m in v { let _ = c.send(m); };
+        for c_item in items {
+            if let ConnectionItem::MethodCall(ref msg) = c_item {
+                if msg.msg_type() != MessageType::MethodCall {
+                    continue
+                }
+
+                if let Some(v) = base_tree.handle(msg) {
+                    // Probably the wisest is to ignore any send errors here -
+                    // maybe the remote has disconnected during our processing.
+                    for m in v { let _ = c.send(m); };
+                } else if let Some(v) = child_tree.borrow().handle(msg) {
+                    for m in v { let _ = c.send(m); };
+                } else if let Some(v) = jobs.borrow().handle(msg) {
+                    for m in v { let _ = c.send(m); };
+                }
+
+                // Reading properties doesn't change anything
+                let is_read = msg.interface().map_or(false, |i| {
+                    i.starts_with("org.freedesktop.DBus.")
+                });
+                if !is_read {
+                    update_components(&mut components, c, &child_tree, froyos);
+                }
             }
         }
 
+        // Now that callers have their replies, get on with the work
+        // they queued, a step at a time.
+        match jobs.borrow().run_ready() {
+            Ok(true) => update_components(&mut components, c, &child_tree, froyos),
+            Ok(false) => {},
+            Err(e) => log_err("Running jobs", &e),
+        }
+
+        let (events, lost) = match uevents.pending() {
+            Ok(pending) => pending,
+            Err(e) => {
+                log_err("Reading uevents", &e);
+                (Vec::new(), false)
+            },
+        };
+        for event in &events {
+            if let Err(e) = dbus_api::handle_uevent(c, event, froyos, &child_tree,
+                                                    auth, &jobs) {
+                dbgp!("Handling {} of {} failed: {}",
+                      event.action.name(), event.path.display(), e.description());
+            }
+        }
+        if lost {
+            if let Err(e) = dbus_api::rescan_block_devs(c, froyos, &child_tree,
+                                                        auth, &jobs) {
+                log_err("Rescanning block devices", &e);
+            }
+        }
+        if !events.is_empty() || lost {
+            update_components(&mut components, c, &child_tree, froyos);
+        }
+
+        if let Some(sig) = try!(signals.read_signal()) {
+            dbgp!("Got signal {}, shutting down", sig.ssi_signo);
+            break
+        }
+
+        // Check froyodevs with dm events now, and all of them
+        // periodically, for things like recovery rate schedules.
+        let dm_changed = match dm_events.pending() {
+            Ok(changed) => changed,
+            Err(e) => {
+                log_err("Reading dm events", &e);
+                BTreeSet::new()
+            },
+        };
         let now = time::now().to_timespec();
-        if now < last_time + Duration::seconds(30) {
+        let periodic = now >= last_time + Duration::seconds(STATE_CHECK_SECS);
+        if !periodic && dm_changed.is_empty() {
             continue
         }
 
-        last_time = now;
+        if periodic {
+            last_time = now;
+        }
 
         for froyo in &*froyos.borrow() {
             let mut froyo = froyo.borrow_mut();
-            try!(froyo.check_state());
-            try!(froyo.update_dbus());
-            try!(froyo.dump_status());
+            if !periodic && !froyo.dm_event_names().iter().any(|n| dm_changed.contains(n)) {
+                continue
+            }
+            // Carry on managing the others if one froyodev has problems
+            if let Err(e) = check_froyo(&mut froyo) {
+                log_err(&froyo.name, &e);
+            }
         }
+        if let Err(e) = jobs.borrow_mut().poll() {
+            log_err("Checking jobs", &e);
+        }
+        update_components(&mut components, c, &child_tree, froyos);
     }
 
     Ok(())
 }
 
+// Like check_froyo(), a failure here shouldn't stop the daemon. The
+// objects are brought up to date on the next try.
+fn update_components<'a>(components: &mut dbus_api::DbusComponents<'a>,
+                         c: &'a Connection,
+                         tree: &Rc<RefCell<Tree<MethodFn<'a>>>>,
+                         froyos: &Rc<RefCell<Vec<Rc<RefCell<Froyo<'a>>>>>>) {
+    if let Err(e) = components.update(c, tree, &froyos.borrow()) {
+        log_err("Updating D-Bus objects", &e);
+    }
+}
+
+fn check_froyo(froyo: &mut Froyo) -> FroyoResult<()> {
+    try!(froyo.check_state());
+    try!(froyo.update_dbus());
+    froyo.dump_status()
+}
+
+// For errors the daemon carries on after
+fn log_err(what: &str, err: &FroyoError) {
+    let _ = writeln!(io::stderr(), "{}: {}", what, err.description());
+}
+
 fn write_err(err: FroyoError) -> FroyoResult<()> {
     let mut out = term::stderr().expect("could not get stderr");
 
@@ -546,6 +1289,25 @@ fn main() {
                          .long("force")
                          .help("Force")
                          )
+                    .arg(Arg::with_name("block-size")
+                         .long("block-size")
+                         .takes_value(true)
+                         .help("Thin pool data block size, e.g. 64K")
+                         )
+                    .arg(Arg::with_name("zero")
+                         .long("zero")
+                         .help("Zero thin pool blocks before first use")
+                         )
+                    .arg(Arg::with_name("discard")
+                         .long("discard")
+                         .takes_value(true)
+                         .possible_values(&["passdown", "nopassdown", "ignore"])
+                         .help("How the thin pool handles discards")
+                         )
+                    .arg(Arg::with_name("error-if-no-space")
+                         .long("error-if-no-space")
+                         .help("Fail writes instead of queueing them when out of space")
+                         )
                     .arg(Arg::with_name("froyodevname")
                          .help("Name of the new froyodev")
                          .required(true)
@@ -561,7 +1323,7 @@ fn main() {
         .subcommand(SubCommand::with_name("rename")
                     .about("Rename a froyodev")
                     .arg(Arg::with_name("froyodev_old_name")
-                         .help("Old name of froyodev")
+                         .help("Name or uuid prefix of the froyodev to rename")
                          .required(true)
                          .index(1)
                     )
@@ -580,33 +1342,210 @@ fn main() {
                     )
         )
         .subcommand(SubCommand::with_name("reshape")
-                    .about("Manually start a reshape")
+                    .about("Manually start, pause, resume or cancel a reshape")
+                    .arg(Arg::with_name("dry-run")
+                         .long("dry-run")
+                         .conflicts_with_all(&["pause", "resume", "cancel"])
+                         .help("Show what a reshape would do, without starting it")
+                    )
+                    .arg(Arg::with_name("pause")
+                         .long("pause")
+                         .conflicts_with_all(&["resume", "cancel"])
+                         .help("Pause an in-progress reshape")
+                    )
+                    .arg(Arg::with_name("resume")
+                         .long("resume")
+                         .conflicts_with("cancel")
+                         .help("Resume a paused reshape")
+                    )
+                    .arg(Arg::with_name("cancel")
+                         .long("cancel")
+                         .help("Cancel an in-progress reshape")
+                    )
+                    .arg(Arg::with_name("no-wait")
+                         .long("no-wait")
+                         .conflicts_with_all(&["dry-run", "pause", "resume", "cancel"])
+                         .help("Print the reshape's job path instead of waiting for it")
+                    )
                     .arg(Arg::with_name("froyodev")
                          .help("Froyodev to reshape")
                          .required(true)
                          .index(1)
                     )
         )
+        .subcommand(SubCommand::with_name("trim")
+                    .about("Discard unused blocks in a mounted volume")
+                    .arg(Arg::with_name("volume")
+                         .help("Name of the volume")
+                         .required(true)
+                         .index(1)
+                    )
+        )
+        .subcommand(SubCommand::with_name("volume-policy")
+                    .about("Set how a volume's size is managed as it fills")
+                    .arg(Arg::with_name("off")
+                         .long("off")
+                         .help("Don't automatically extend the volume")
+                    )
+                    .arg(Arg::with_name("threshold")
+                         .long("threshold")
+                         .takes_value(true)
+                         .help("Extend when this percent of the volume is used")
+                    )
+                    .arg(Arg::with_name("extend")
+                         .long("extend")
+                         .takes_value(true)
+                         .help("Extend by this percent of the volume's size")
+                    )
+                    .arg(Arg::with_name("max")
+                         .long("max")
+                         .takes_value(true)
+                         .help("Never extend the volume past this size, e.g. 2T")
+                    )
+                    .arg(Arg::with_name("froyodev")
+                         .help("Name or uuid prefix of the froyodev")
+                         .required(true)
+                         .index(1)
+                    )
+                    .arg(Arg::with_name("volume")
+                         .help("Name of the volume")
+                         .required(true)
+                         .index(2)
+                    )
+        )
+        .subcommand(SubCommand::with_name("meta-backup")
+                    .about("Set how a froyodev's thin pool metadata is backed up")
+                    .arg(Arg::with_name("off")
+                         .long("off")
+                         .help("Don't back up the metadata")
+                    )
+                    .arg(Arg::with_name("dir")
+                         .long("dir")
+                         .takes_value(true)
+                         .help("Directory to store backups in")
+                    )
+                    .arg(Arg::with_name("interval")
+                         .long("interval")
+                         .takes_value(true)
+                         .help("Seconds between backups")
+                    )
+                    .arg(Arg::with_name("keep")
+                         .long("keep")
+                         .takes_value(true)
+                         .help("Number of backups to keep")
+                    )
+                    .arg(Arg::with_name("froyodev")
+                         .help("Name or uuid prefix of the froyodev")
+                         .required(true)
+                         .index(1)
+                    )
+        )
+        .subcommand(SubCommand::with_name("recovery-rate")
+                    .about("Limit the bandwidth used by resyncs and reshapes")
+                    .arg(Arg::with_name("min")
+                         .long("min")
+                         .takes_value(true)
+                         .help("Minimum KiB/sec per device")
+                    )
+                    .arg(Arg::with_name("max")
+                         .long("max")
+                         .takes_value(true)
+                         .help("Maximum KiB/sec per device")
+                    )
+                    .arg(Arg::with_name("schedule")
+                         .long("schedule")
+                         .takes_value(true)
+                         .help("Hours to use the scheduled rates instead, e.g. 22-6")
+                    )
+                    .arg(Arg::with_name("schedule-min")
+                         .long("schedule-min")
+                         .takes_value(true)
+                         .requires("schedule")
+                         .help("Minimum KiB/sec per device during the schedule")
+                    )
+                    .arg(Arg::with_name("schedule-max")
+                         .long("schedule-max")
+                         .takes_value(true)
+                         .requires("schedule")
+                         .help("Maximum KiB/sec per device during the schedule")
+                    )
+                    .arg(Arg::with_name("froyodev")
+                         .help("Name or uuid prefix of the froyodev")
+                         .required(true)
+                         .index(1)
+                    )
+        )
         .subcommand(SubCommand::with_name("teardown")
                     .about("Deactivate a froyodev")
                     .arg(Arg::with_name("froyodev")
-                         .help("Name of the froyodev")
+                         .help("Name or uuid prefix of the froyodev")
+                         .required(true)
+                         .index(1)
+                    )
+        )
+        .subcommand(SubCommand::with_name("available")
+                    .about("List froyodevs found on disk, active or not")
+                    )
+        .subcommand(SubCommand::with_name("activate")
+                    .about("Activate a froyodev found on disk")
+                    .arg(Arg::with_name("froyodev")
+                         .help("Name or uuid prefix of the froyodev")
                          .required(true)
                          .index(1)
                     )
         )
+        .subcommand(SubCommand::with_name("rescan")
+                    .about("Activate all froyodevs found on disk that aren't already")
+                    )
         .subcommand(SubCommand::with_name("dev")
                     .about("Developer/debug commands")
                     .subcommand(SubCommand::with_name("dump_meta")
                                 .about("Output the JSON metadata for a froyodev")
                                 .arg(Arg::with_name("froyodevname")
-                                     .help("Name of the froyodev")
+                                     .help("Name or uuid prefix of the froyodev")
+                                     .required(true)
+                                     .index(1)
+                                     )
+                                )
+                    .subcommand(SubCommand::with_name("check_thin_meta")
+                                .about("Check an inactive froyodev's thin pool metadata")
+                                .arg(Arg::with_name("repair")
+                                     .long("repair")
+                                     .help("Repair the metadata if the check fails")
+                                     )
+                                .arg(Arg::with_name("froyodevname")
+                                     .help("Name or uuid prefix of the froyodev")
+                                     .required(true)
+                                     .index(1)
+                                     )
+                                )
+                    .subcommand(SubCommand::with_name("restore_thin_meta")
+                                .about("Restore an inactive froyodev's thin pool metadata from a backup")
+                                .arg(Arg::with_name("backup")
+                                     .long("backup")
+                                     .takes_value(true)
+                                     .help("Backup file to restore, default is the newest")
+                                     )
+                                .arg(Arg::with_name("froyodevname")
+                                     .help("Name or uuid prefix of the froyodev")
                                      .required(true)
                                      .index(1)
                                      )
                                 )
                     .subcommand(SubCommand::with_name("dbus_server")
                                 .about("Serve the Froyo DBus API")
+                                .arg(Arg::with_name("auth")
+                                     .long("auth")
+                                     .takes_value(true)
+                                     .possible_values(&["uid", "polkit"])
+                                     .help("How to authorize changes, default is uid")
+                                     )
+                                .arg(Arg::with_name("allow-uid")
+                                     .long("allow-uid")
+                                     .takes_value(true)
+                                     .multiple(true)
+                                     .help("Also allow this uid to make changes (uid auth only)")
+                                     )
                                 )
                     )
         .get_matches();
@@ -626,8 +1565,17 @@ fn main() {
         ("destroy", Some(matches)) => destroy(matches),
         ("reshape", Some(matches)) => reshape(matches),
         ("teardown", Some(matches)) => teardown(matches),
+        ("available", Some(matches)) => available(matches),
+        ("activate", Some(matches)) => activate(matches),
+        ("rescan", Some(matches)) => rescan(matches),
+        ("volume-policy", Some(matches)) => volume_policy(matches),
+        ("trim", Some(matches)) => trim(matches),
+        ("meta-backup", Some(matches)) => meta_backup(matches),
+        ("recovery-rate", Some(matches)) => recovery_rate(matches),
         ("dev", Some(matches)) => match matches.subcommand() {
             ("dump_meta", Some(matches)) => dump_meta(matches),
+            ("check_thin_meta", Some(matches)) => check_thin_meta(matches),
+            ("restore_thin_meta", Some(matches)) => restore_thin_meta(matches),
             ("dbus_server", Some(matches)) => dbus_server(matches),
             ("", None) => {
                 println!("No command given, try \"help\"");
@@ -643,10 +1591,11 @@ fn main() {
     };
 
     if let Err(r) = r {
+        let code = r.kind().map_or(1, |kind| kind.exit_code());
         if let Err(e) = write_err(r) {
             panic!("Unable to write to stderr: {}", e)
         }
 
-        exit(1);
+        exit(code);
     }
 }
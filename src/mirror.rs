@@ -5,6 +5,7 @@
 use std::io;
 use std::rc::Rc;
 use std::cell::RefCell;
+use std::collections::BTreeMap;
 
 use uuid::Uuid;
 use devicemapper::DM;
@@ -14,7 +15,16 @@ use types::{Sectors, SectorOffset, FroyoResult, FroyoError, InternalError};
 use consts::*;
 use blockdev::{LinearSegment, BlockDev, BlockDevs};
 use dmdevice::DmDevice;
-use raid::{RaidDev, RaidLinearDev};
+use raid::{RaidDev, RaidLinearDev, RecoveryRate};
+use util::parse_sync_ratio;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MirrorDevSave {
+    pub src: TempDevSave,
+    pub dest: TempDevSave,
+    pub linear_dev: String, // RaidLinearDev id
+    pub linear_dev_idxs: Vec<usize>,
+}
 
 #[derive(Debug, Clone)]
 pub struct MirrorDev {
@@ -34,11 +44,17 @@ impl MirrorDev {
         dest: Rc<RefCell<TempDev>>,
         length: Sectors,
         linear_dev: Rc<RefCell<RaidLinearDev>>,
-        linear_dev_idxs: &[usize])
+        linear_dev_idxs: &[usize],
+        rate: RecoveryRate)
                -> FroyoResult<MirrorDev> {
+        // No metadata devs, so a table reload would restart the copy.
+        // The rate can only be set here.
+        let mut params = vec![(*STRIPE_SECTORS).to_string()];
+        params.extend(rate.raid_params());
         let table = (0, *length, "raid",
-                     format!("raid1 1 {} 2 - {} - {}",
-                             *STRIPE_SECTORS,
+                     format!("raid1 {} {} 2 - {} - {}",
+                             params.len(),
+                             params.join(" "),
                              src.borrow().dmdev.dstr(),
                              dest.borrow().dmdev.dstr()));
         let dm_name = format!("froyo-copymirror-{}", name);
@@ -53,6 +69,15 @@ impl MirrorDev {
         })
     }
 
+    pub fn to_save(&self) -> MirrorDevSave {
+        MirrorDevSave {
+            src: self.src.borrow().to_save(),
+            dest: self.dest.borrow().to_save(),
+            linear_dev: self.linear_dev.borrow().id.clone(),
+            linear_dev_idxs: self.linear_dev_idxs.clone(),
+        }
+    }
+
     pub fn teardown(&self, dm: &DM) -> FroyoResult<()> {
         try!(self.mirror.teardown(dm));
         try!(self.src.borrow().teardown(dm));
@@ -60,12 +85,14 @@ impl MirrorDev {
         Ok(())
     }
 
-    pub fn is_syncing(&self, dm: &DM) -> FroyoResult<bool> {
+    fn status_vals(&self, dm: &DM) -> FroyoResult<Vec<String>> {
         let mut status = try!(self.mirror.table_status(dm));
 
         // See kernel's dm-raid.txt "Status Output"
         let status_line = status.pop().unwrap().3;
-        let status_vals = status_line.split(' ').collect::<Vec<_>>();
+        let status_vals = status_line.split(' ')
+            .map(|x| x.to_owned())
+            .collect::<Vec<_>>();
         if status_vals.len() < 5 {
             return Err(FroyoError::Io(io::Error::new(
                 io::ErrorKind::InvalidData,
@@ -73,16 +100,48 @@ impl MirrorDev {
         }
 
         dbgp!("status line {}", status_line);
+
+        Ok(status_vals)
+    }
+
+    // Sectors copied so far, and the total to copy
+    pub fn sync_progress(&self, dm: &DM) -> FroyoResult<(Sectors, Sectors)> {
+        let status_vals = try!(self.status_vals(dm));
+
+        parse_sync_ratio(&status_vals[3])
+            .ok_or_else(|| FroyoError::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Kernel returned bad sync ratio in raid status")))
+    }
+
+    pub fn is_syncing(&self, dm: &DM) -> FroyoResult<bool> {
+        let status_vals = try!(self.status_vals(dm));
+
         dbgp!("status {}", status_vals[2]);
         dbgp!("action {}", status_vals[4]);
 
-         match status_vals[4] {
-             "idle" => Ok(false),
-             "resync" => Ok(true),
+        // A frozen or just-unfrozen resync may be idle but incomplete,
+        // so check the sync ratio too.
+        let incomplete = parse_sync_ratio(&status_vals[3])
+            .map_or(false, |(done, total)| done < total);
+
+         match &*status_vals[4] {
+             "idle" => Ok(incomplete),
+             "resync" | "frozen" => Ok(true),
              action => Err(FroyoError::Froyo(InternalError(
                  format!("Unexpected action: {}", action).into()))),
          }
     }
+
+    // Stop the copy where it is, until unfreeze()
+    pub fn freeze(&self, dm: &DM) -> FroyoResult<()> {
+        self.mirror.message(dm, "frozen")
+    }
+
+    // Let the copy continue from where it was frozen
+    pub fn unfreeze(&self, dm: &DM) -> FroyoResult<()> {
+        self.mirror.message(dm, "idle")
+    }
 }
 
 // Our TempDev's segments may either be on top of a RaidDev (e.g. if
@@ -153,6 +212,15 @@ impl TempDev {
         name: &str,
         segments: &[(TempLayer, LinearSegment)])
         -> FroyoResult<TempDev> {
+        TempDev::with_id(dm, name, &Uuid::new_v4().to_simple_string(), segments)
+    }
+
+    fn with_id(
+        dm: &DM,
+        name: &str,
+        id: &str,
+        segments: &[(TempLayer, LinearSegment)])
+        -> FroyoResult<TempDev> {
 
         let mut table = Vec::new();
         let mut offset = SectorOffset(0);
@@ -163,12 +231,11 @@ impl TempDev {
             offset = offset + SectorOffset(*seg.length);
         }
 
-        let id = Uuid::new_v4().to_simple_string();
         let dm_name = format!("froyo-linear-temp-{}-{}", name, id);
         let dmdev = try!(DmDevice::new(dm, &dm_name, &table));
 
         Ok(TempDev {
-            id: id,
+            id: id.to_owned(),
             dmdev: dmdev,
             segments: segments.to_vec(),
         })
@@ -192,12 +259,40 @@ impl TempDev {
                     }
                 }
 
-                let td = try!(TempDev::new(dm, &froyo_save.id, &td_segs));
+                // Keep the id, saved RaidLinearDev segments refer to it
+                let td = try!(TempDev::with_id(dm, &froyo_save.id, &td.id, &td_segs));
                 Ok(Some(td))
             }
         }
     }
 
+    // Set up a saved TempDev whose segments may be on raids or
+    // blockdevs. Unlike setup(), all parents must be present.
+    pub fn from_save(
+        dm: &DM,
+        name: &str,
+        save: &TempDevSave,
+        block_devs: &BlockDevs,
+        raids: &BTreeMap<String, Rc<RefCell<RaidDev>>>)
+        -> FroyoResult<TempDev> {
+
+        let mut td_segs = Vec::new();
+        for seg in &save.segments {
+            let layer = match raids.get(&seg.parent) {
+                Some(rd) => TempLayer::Raid(rd.clone()),
+                None => match block_devs.0.get(&seg.parent).and_then(|bm| bm.present()) {
+                    Some(bd) => TempLayer::Block(bd.clone()),
+                    None => return Err(FroyoError::Froyo(InternalError(
+                        format!("Temp dev {} parent {} not found",
+                                save.id, seg.parent).into()))),
+                },
+            };
+            td_segs.push((layer, LinearSegment::new(seg.start, seg.length)));
+        }
+
+        TempDev::with_id(dm, name, &save.id, &td_segs)
+    }
+
 
     pub fn dstr(&self) -> String {
         self.dmdev.dstr()
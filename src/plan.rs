@@ -0,0 +1,620 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Work out ahead of time what a reshape would do, by running the
+// same decisions as the reshape state machine against a copy of the
+// layout that only tracks sizes.
+
+use std::collections::BTreeMap;
+use std::cell::RefCell;
+use std::fmt;
+
+use bytesize::ByteSize;
+
+use blockdev::BlockDevs;
+use raid::{RaidDevs, RaidLinearDev};
+use types::Sectors;
+use util::short_id;
+use consts::*;
+
+// Give up if the simulation hasn't settled after this many steps
+const PLAN_MAX_STEPS: usize = 1000;
+
+// The thin pool devs a reshape moves, in the order it moves them
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ThinPoolPart {
+    Meta,
+    Data,
+}
+
+pub const THIN_POOL_PARTS: [ThinPoolPart; 2] = [ThinPoolPart::Meta, ThinPoolPart::Data];
+
+impl ThinPoolPart {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ThinPoolPart::Meta => "meta",
+            ThinPoolPart::Data => "data",
+        }
+    }
+}
+
+// What the reshape's Idle state started
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReshapeChoice {
+    CreateRaids,
+    CopyFromScratch(ThinPoolPart),
+    CopyToSafeRaid(ThinPoolPart),
+    CopyToScratch(ThinPoolPart),
+}
+
+impl fmt::Display for ReshapeChoice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ReshapeChoice::CreateRaids => write!(f, "recreating empty degraded raids"),
+            ReshapeChoice::CopyFromScratch(part) =>
+                write!(f, "reshaping {} from scratch", part.name()),
+            ReshapeChoice::CopyToSafeRaid(part) =>
+                write!(f, "reshaping {} to safe raid", part.name()),
+            ReshapeChoice::CopyToScratch(part) =>
+                write!(f, "reshaping {} to scratch", part.name()),
+        }
+    }
+}
+
+// The steps a reshape can take. Each returns the state it started, or
+// None if there was nothing for it to do.
+pub trait ReshapeSteps {
+    type State;
+    type Error;
+
+    // Destroy empty degraded raids and create new redundant ones
+    fn recreate_raids(&mut self) -> Result<Option<Self::State>, Self::Error>;
+    fn copy_from_scratch(&mut self, part: ThinPoolPart)
+                         -> Result<Option<Self::State>, Self::Error>;
+    fn copy_to_safe_raid(&mut self, part: ThinPoolPart)
+                         -> Result<Option<Self::State>, Self::Error>;
+    fn copy_to_scratch(&mut self, part: ThinPoolPart)
+                       -> Result<Option<Self::State>, Self::Error>;
+}
+
+// The reshape's Idle state: start the first step that has something
+// to do, or return None when the reshape is finished. The reshape
+// state machine and ReshapeModel both go through here, so a plan
+// makes the same choices as the real thing.
+pub fn start_next_step<R>(r: &mut R) -> Result<Option<(ReshapeChoice, R::State)>, R::Error>
+    where R: ReshapeSteps
+{
+    if let Some(state) = try!(r.recreate_raids()) {
+        return Ok(Some((ReshapeChoice::CreateRaids, state)))
+    }
+
+    for &part in &THIN_POOL_PARTS {
+        if let Some(state) = try!(r.copy_from_scratch(part)) {
+            return Ok(Some((ReshapeChoice::CopyFromScratch(part), state)))
+        }
+    }
+
+    for &part in &THIN_POOL_PARTS {
+        if let Some(state) = try!(r.copy_to_safe_raid(part)) {
+            return Ok(Some((ReshapeChoice::CopyToSafeRaid(part), state)))
+        }
+    }
+
+    for &part in &THIN_POOL_PARTS {
+        if let Some(state) = try!(r.copy_to_scratch(part)) {
+            return Ok(Some((ReshapeChoice::CopyToScratch(part), state)))
+        }
+    }
+
+    Ok(None)
+}
+
+#[derive(Debug, Clone)]
+pub enum PlanStep {
+    DestroyRaid(String, Sectors),
+    CreateRaid(usize, Sectors),
+    SyncRaids,
+    CopyToRaid(&'static str, String, Sectors),
+    CopyToScratch(&'static str, String, Sectors),
+    CopyFromScratch(&'static str, Sectors),
+}
+
+impl PlanStep {
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            PlanStep::DestroyRaid(..) => "DestroyRaid",
+            PlanStep::CreateRaid(..) => "CreateRaid",
+            PlanStep::SyncRaids => "SyncRaids",
+            PlanStep::CopyToRaid(..) => "CopyToRaid",
+            PlanStep::CopyToScratch(..) => "CopyToScratch",
+            PlanStep::CopyFromScratch(..) => "CopyFromScratch",
+        }
+    }
+
+    // Sectors freed, created or copied by the step
+    pub fn sectors(&self) -> Sectors {
+        match *self {
+            PlanStep::DestroyRaid(_, len) |
+            PlanStep::CreateRaid(_, len) |
+            PlanStep::CopyToRaid(_, _, len) |
+            PlanStep::CopyToScratch(_, _, len) |
+            PlanStep::CopyFromScratch(_, len) => len,
+            PlanStep::SyncRaids => Sectors(0),
+        }
+    }
+}
+
+fn bytes(sectors: Sectors) -> String {
+    ByteSize::b((*sectors * SECTOR_SIZE) as usize).to_string(true)
+}
+
+impl fmt::Display for PlanStep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PlanStep::DestroyRaid(ref id, len) =>
+                write!(f, "Destroy empty degraded raid {}, freeing {}",
+                       short_id(id), bytes(len)),
+            PlanStep::CreateRaid(devs, len) =>
+                write!(f, "Create a {} raid across {} block devices", bytes(len), devs),
+            PlanStep::SyncRaids =>
+                write!(f, "Wait for new raids to sync"),
+            PlanStep::CopyToRaid(dev, ref id, len) =>
+                write!(f, "Copy {} of thin pool {} from raid {} to redundant raids",
+                       bytes(len), dev, short_id(id)),
+            PlanStep::CopyToScratch(dev, ref id, len) =>
+                write!(f, "Copy {} of thin pool {} from raid {} to scratch space",
+                       bytes(len), dev, short_id(id)),
+            PlanStep::CopyFromScratch(dev, len) =>
+                write!(f, "Copy {} of thin pool {} from scratch space to redundant raids",
+                       bytes(len), dev),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ReshapePlan {
+    pub steps: Vec<PlanStep>,
+    // Where the reshape would fail, if it would
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+struct PlanBlockDev {
+    sectors: Sectors,
+    avail_areas: Vec<Sectors>,
+}
+
+impl PlanBlockDev {
+    fn largest_avail_area(&self) -> Sectors {
+        self.avail_areas.iter().cloned().max().unwrap_or(Sectors(0))
+    }
+}
+
+#[derive(Debug, Clone)]
+struct PlanRaid {
+    length: Sectors,
+    used: Sectors,
+    safe: bool,
+    // Space each present member takes on its blockdev
+    members: Vec<(String, Sectors)>,
+}
+
+// A RaidLinearDev segment, on a raid or on scratch space
+#[derive(Debug, Clone)]
+struct PlanSegment {
+    raid: Option<String>,
+    length: Sectors,
+}
+
+#[derive(Debug, Clone)]
+pub struct ReshapeModel {
+    block_devs: BTreeMap<String, PlanBlockDev>,
+    raids: BTreeMap<String, PlanRaid>,
+    meta: Vec<PlanSegment>,
+    data: Vec<PlanSegment>,
+    new_raids: usize,
+    steps: Vec<PlanStep>,
+}
+
+impl ReshapeModel {
+    pub fn new(block_devs: &BlockDevs,
+               raid_devs: &RaidDevs,
+               meta_dev: &RefCell<RaidLinearDev>,
+               data_dev: &RefCell<RaidLinearDev>)
+               -> ReshapeModel {
+        let plan_block_devs = block_devs.0.iter()
+            .filter_map(|(id, bm)| bm.present().map(|bd| (id, bd)))
+            .map(|(id, bd)| {
+                let bd = bd.borrow();
+                (id.clone(), PlanBlockDev {
+                    sectors: bd.sectors,
+                    avail_areas: bd.avail_areas().into_iter()
+                        .map(|(_, len)| len)
+                        .collect(),
+                })
+            })
+            .collect();
+
+        let raids = raid_devs.raids.iter()
+            .map(|(id, rd)| {
+                let rd = rd.borrow();
+                let members = rd.members.iter()
+                    .filter_map(|rm| rm.present())
+                    .filter_map(|ld| {
+                        let ld = ld.borrow();
+                        ld.parent.upgrade().map(|bd| (
+                            bd.borrow().id.clone(),
+                            ld.metadata_length() + ld.data_length()))
+                    })
+                    .collect();
+                (id.clone(), PlanRaid {
+                    length: rd.length,
+                    used: rd.length - rd.avail_sectors(),
+                    safe: rd.is_safe(),
+                    members: members,
+                })
+            })
+            .collect();
+
+        let segments = |rld: &RefCell<RaidLinearDev>| {
+            rld.borrow().segments.iter()
+                .map(|rs| PlanSegment {
+                    raid: if rs.parent.on_temp() { None } else { Some(rs.parent.id()) },
+                    length: rs.length,
+                })
+                .collect()
+        };
+
+        ReshapeModel {
+            block_devs: plan_block_devs,
+            raids: raids,
+            meta: segments(meta_dev),
+            data: segments(data_dev),
+            new_raids: 0,
+            steps: Vec::new(),
+        }
+    }
+
+    // Run the reshape state machine's Idle step until it would stop
+    pub fn plan(mut self) -> ReshapePlan {
+        for _ in 0..PLAN_MAX_STEPS {
+            match start_next_step(&mut self) {
+                Ok(Some((ReshapeChoice::CreateRaids, _))) => self.steps.push(PlanStep::SyncRaids),
+                Ok(Some(_)) => {},
+                Ok(None) => return ReshapePlan { steps: self.steps, error: None },
+                Err(e) => return ReshapePlan { steps: self.steps, error: Some(e) },
+            }
+        }
+
+        ReshapePlan {
+            steps: self.steps,
+            error: Some(format!("Reshape plan did not finish after {} steps",
+                                PLAN_MAX_STEPS)),
+        }
+    }
+
+    fn segments(&mut self, part: ThinPoolPart) -> &mut Vec<PlanSegment> {
+        match part {
+            ThinPoolPart::Meta => &mut self.meta,
+            ThinPoolPart::Data => &mut self.data,
+        }
+    }
+
+    fn is_safe(&self, seg: &PlanSegment) -> bool {
+        match seg.raid {
+            Some(ref id) => self.raids.get(id).map_or(false, |rd| rd.safe),
+            None => false,
+        }
+    }
+
+    // Like RaidDevs::alloc_raid_segments
+    fn alloc_raid_segments(&mut self, sectors: Sectors) -> Option<Vec<PlanSegment>> {
+        let split = match RaidDevs::split_allocation(
+            sectors,
+            self.raids.iter()
+                .filter(|&(_, rd)| rd.safe)
+                .map(|(id, rd)| (id.clone(), rd.length - rd.used))) {
+            Some(x) => x,
+            None => return None,
+        };
+
+        for &(ref id, len) in &split {
+            let rd = self.raids.get_mut(id).unwrap();
+            rd.used = rd.used + len;
+        }
+
+        Some(split.into_iter()
+             .map(|(id, len)| PlanSegment { raid: Some(id), length: len })
+             .collect())
+    }
+
+    fn free_raid_segment(&mut self, seg: &PlanSegment) {
+        if let Some(ref id) = seg.raid {
+            if let Some(rd) = self.raids.get_mut(id) {
+                rd.used = rd.used - seg.length;
+            }
+        }
+    }
+
+    fn recreate_empty_degraded_raids(&mut self) -> bool {
+        let empty = self.raids.iter()
+            .filter(|&(_, rd)| rd.used == Sectors(0) && !rd.safe)
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<_>>();
+
+        for id in empty {
+            let rd = self.raids.remove(&id).unwrap();
+            let mut freed = Sectors(0);
+            for (bd_id, len) in rd.members {
+                if let Some(bd) = self.block_devs.get_mut(&bd_id) {
+                    bd.avail_areas.push(len);
+                    freed = freed + len;
+                }
+            }
+            self.steps.push(PlanStep::DestroyRaid(id, freed));
+        }
+
+        let mut created = false;
+        while let Some(step) = self.create_redundant_zone() {
+            self.steps.push(step);
+            created = true;
+        }
+
+        created
+    }
+
+    // Like RaidDevs::create_redundant_zone
+    fn create_redundant_zone(&mut self) -> Option<PlanStep> {
+        let scratch_needed = RaidDevs::scratch_for(self.raids.values().map(|rd| rd.length));
+
+        let ids = self.block_devs.keys().cloned().collect::<Vec<_>>();
+        let sizes = self.block_devs.values()
+            .map(|bd| (bd.sectors, bd.largest_avail_area()))
+            .collect::<Vec<_>>();
+
+        let (member_idxs, common_avail_sectors) =
+            match RaidDevs::zone_members(&sizes, scratch_needed) {
+                Some(x) => x,
+                None => return None,
+            };
+
+        let (_, mdata_sectors, data_sectors) = RaidDevs::zone_layout(common_avail_sectors);
+        let member_sectors = mdata_sectors + data_sectors;
+
+        let mut members = Vec::new();
+        for idx in member_idxs {
+            let id = &ids[idx];
+            let bd = self.block_devs.get_mut(id).unwrap();
+            let largest = bd.largest_avail_area();
+            let pos = bd.avail_areas.iter().position(|&len| len == largest).unwrap();
+            bd.avail_areas[pos] = largest - member_sectors;
+            members.push((id.clone(), member_sectors));
+        }
+
+        let member_count = members.len();
+        let length = data_sectors * Sectors((member_count - REDUNDANCY) as u64);
+        let raid = PlanRaid {
+            length: length,
+            used: Sectors(0),
+            safe: true,
+            members: members,
+        };
+
+        // Real raids get random ids, so just add new ones at the end
+        self.new_raids += 1;
+        self.raids.insert(format!("~new-raid-{}", self.new_raids), raid);
+
+        Some(PlanStep::CreateRaid(member_count, length))
+    }
+
+    fn plan_copy_from_scratch(&mut self, part: ThinPoolPart)
+                              -> Result<Option<PlanStep>, String> {
+        let dev = part.name();
+        let len = self.segments(part).iter()
+            .filter(|seg| seg.raid.is_none())
+            .map(|seg| seg.length)
+            .sum::<Sectors>();
+        if len == Sectors(0) {
+            return Ok(None)
+        }
+
+        let new_segs = try!(self.alloc_raid_segments(len)
+                            .ok_or_else(|| format!("No space to copy {} of thin pool {} \
+                                                    back from scratch",
+                                                   bytes(len), dev)));
+
+        let segs = self.segments(part);
+        let mut kept = segs.drain(..)
+            .filter(|seg| seg.raid.is_some())
+            .collect::<Vec<_>>();
+        kept.extend(new_segs);
+        *segs = kept;
+
+        Ok(Some(PlanStep::CopyFromScratch(dev, len)))
+    }
+
+    fn plan_copy_to_safe_raid(&mut self, part: ThinPoolPart) -> Option<PlanStep> {
+        let (idx, seg) = {
+            let segs = match part {
+                ThinPoolPart::Meta => &self.meta,
+                ThinPoolPart::Data => &self.data,
+            };
+            match segs.iter().enumerate()
+                .find(|&(_, seg)| seg.raid.is_some() && !self.is_safe(seg)) {
+                    Some((idx, seg)) => (idx, seg.clone()),
+                    None => return None,
+                }
+        };
+
+        // Like the state machine, leave it for scratch if there's no room
+        let new_segs = match self.alloc_raid_segments(seg.length) {
+            Some(x) => x,
+            None => return None,
+        };
+
+        self.free_raid_segment(&seg);
+        let segs = self.segments(part);
+        let tail = segs.split_off(idx + 1);
+        segs.pop();
+        segs.extend(new_segs);
+        segs.extend(tail);
+
+        Some(PlanStep::CopyToRaid(part.name(), seg.raid.unwrap(), seg.length))
+    }
+
+    fn plan_copy_to_scratch(&mut self, part: ThinPoolPart) -> Result<Option<PlanStep>, String> {
+        let dev = part.name();
+        // All the segments on the first unsafe raid
+        let raid_id = {
+            let segs = match part {
+                ThinPoolPart::Meta => &self.meta,
+                ThinPoolPart::Data => &self.data,
+            };
+            let mut ids = segs.iter()
+                .filter(|seg| seg.raid.is_some() && !self.is_safe(seg))
+                .map(|seg| seg.raid.clone().unwrap())
+                .collect::<Vec<_>>();
+            ids.sort();
+            match ids.into_iter().next() {
+                Some(id) => id,
+                None => return Ok(None),
+            }
+        };
+
+        let to_move = self.segments(part).iter()
+            .filter(|seg| seg.raid.as_ref() == Some(&raid_id))
+            .cloned()
+            .collect::<Vec<_>>();
+        let len = to_move.iter().map(|seg| seg.length).sum::<Sectors>();
+
+        // Scratch space isn't tracked as allocated, as in the state machine
+        let scratch = self.block_devs.values()
+            .flat_map(|bd| bd.avail_areas.iter())
+            .cloned()
+            .sum::<Sectors>();
+        if scratch < len {
+            return Err(format!("No scratch space for {} of thin pool {}, {} available",
+                               bytes(len), dev, bytes(scratch)))
+        }
+
+        for seg in &to_move {
+            self.free_raid_segment(seg);
+        }
+        for seg in self.segments(part).iter_mut() {
+            if seg.raid.as_ref() == Some(&raid_id) {
+                seg.raid = None;
+            }
+        }
+
+        Ok(Some(PlanStep::CopyToScratch(dev, raid_id, len)))
+    }
+}
+
+impl ReshapeSteps for ReshapeModel {
+    type State = ();
+    type Error = String;
+
+    fn recreate_raids(&mut self) -> Result<Option<()>, String> {
+        Ok(if self.recreate_empty_degraded_raids() { Some(()) } else { None })
+    }
+
+    fn copy_from_scratch(&mut self, part: ThinPoolPart) -> Result<Option<()>, String> {
+        self.plan_copy_from_scratch(part).map(|step| step.map(|step| self.steps.push(step)))
+    }
+
+    fn copy_to_safe_raid(&mut self, part: ThinPoolPart) -> Result<Option<()>, String> {
+        Ok(self.plan_copy_to_safe_raid(part).map(|step| self.steps.push(step)))
+    }
+
+    fn copy_to_scratch(&mut self, part: ThinPoolPart) -> Result<Option<()>, String> {
+        self.plan_copy_to_scratch(part).map(|step| step.map(|step| self.steps.push(step)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use types::Sectors;
+    use consts::*;
+    use super::{ReshapeModel, PlanBlockDev, PlanRaid, PlanSegment, PlanStep};
+
+    const GIG: Sectors = MIN_DATA_ZONE_SECTORS;
+
+    fn gigs(n: u64) -> Sectors {
+        GIG * Sectors(n)
+    }
+
+    // Two of three blockdevs left, with the thin pool on the degraded
+    // raid that spanned all three
+    fn degraded(avail: Sectors) -> ReshapeModel {
+        let mut block_devs = BTreeMap::new();
+        for id in &["a", "b"] {
+            block_devs.insert(id.to_string(), PlanBlockDev {
+                sectors: gigs(100),
+                avail_areas: vec![avail],
+            });
+        }
+
+        let mut raids = BTreeMap::new();
+        raids.insert("r1".to_owned(), PlanRaid {
+            length: gigs(4),
+            used: gigs(2),
+            safe: false,
+            members: vec![("a".to_owned(), gigs(2)), ("b".to_owned(), gigs(2))],
+        });
+
+        ReshapeModel {
+            block_devs: block_devs,
+            raids: raids,
+            meta: vec![PlanSegment { raid: Some("r1".to_owned()), length: GIG }],
+            data: vec![PlanSegment { raid: Some("r1".to_owned()), length: GIG }],
+            new_raids: 0,
+            steps: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn plan_moves_off_degraded_raid() {
+        let plan = degraded(gigs(8)).plan();
+        assert_eq!(plan.error, None);
+
+        let kinds = plan.steps.iter().map(|step| step.kind()).collect::<Vec<_>>();
+        assert_eq!(kinds, vec!["CreateRaid", "SyncRaids", "CopyToRaid", "CopyToRaid",
+                               "DestroyRaid"]);
+
+        match plan.steps[0] {
+            PlanStep::CreateRaid(2, len) => assert!(len > gigs(2)),
+            ref step => panic!("unexpected {:?}", step),
+        }
+        match plan.steps[2] {
+            PlanStep::CopyToRaid("meta", ref id, len) => {
+                assert_eq!(id, "r1");
+                assert_eq!(len, GIG);
+            },
+            ref step => panic!("unexpected {:?}", step),
+        }
+        match plan.steps[3] {
+            PlanStep::CopyToRaid("data", ref id, len) => {
+                assert_eq!(id, "r1");
+                assert_eq!(len, GIG);
+            },
+            ref step => panic!("unexpected {:?}", step),
+        }
+        match plan.steps[4] {
+            PlanStep::DestroyRaid(ref id, len) => {
+                assert_eq!(id, "r1");
+                assert_eq!(len, gigs(4));
+            },
+            ref step => panic!("unexpected {:?}", step),
+        }
+    }
+
+    #[test]
+    fn plan_fails_without_space() {
+        let plan = degraded(Sectors(0)).plan();
+        assert!(plan.steps.is_empty());
+        assert!(plan.error.is_some());
+    }
+}
@@ -15,12 +15,12 @@ use uuid::Uuid;
 use devicemapper::DM;
 
 use froyo::FroyoSave;
-use types::{Sectors, SectorOffset, FroyoError, FroyoResult, InternalError};
+use types::{Sectors, SectorOffset, FroyoError, FroyoErrorKind, FroyoResult, InternalError};
 use blockdev::{LinearDev, LinearDevSave, BlockDev, LinearSegment, BlockDevs, BlockMember};
 use consts::*;
 use dmdevice::DmDevice;
 use mirror::{TempDev};
-use util::align_to;
+use util::{align_to, parse_sync_ratio};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RaidDevSave {
@@ -31,6 +31,93 @@ pub struct RaidDevSave {
     pub members: BTreeMap<String, LinearDevSave>,
 }
 
+// Limits on resync and reshape copy bandwidth, in KiB/sec per raid
+// member. 0 leaves it up to the kernel.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct RecoveryRate {
+    pub min_kib: u64,
+    pub max_kib: u64,
+}
+
+impl RecoveryRate {
+    pub fn validate(&self) -> FroyoResult<()> {
+        if self.min_kib != 0 && self.max_kib != 0 && self.min_kib > self.max_kib {
+            return Err(FroyoError::Froyo(InternalError(
+                format!("Minimum recovery rate {} is above maximum {}",
+                        self.min_kib, self.max_kib).into())))
+        }
+
+        Ok(())
+    }
+
+    // dm-raid optional params, see kernel's dm-raid.txt
+    pub fn raid_params(&self) -> Vec<String> {
+        let mut params = Vec::new();
+        if self.min_kib != 0 {
+            params.push("min_recovery_rate".to_owned());
+            params.push(self.min_kib.to_string());
+        }
+        if self.max_kib != 0 {
+            params.push("max_recovery_rate".to_owned());
+            params.push(self.max_kib.to_string());
+        }
+        params
+    }
+}
+
+// Use a different rate between two hours of the day, local time. The
+// window may wrap past midnight.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecoverySchedule {
+    pub start_hour: u8,
+    pub end_hour: u8,
+    pub rate: RecoveryRate,
+}
+
+impl RecoverySchedule {
+    pub fn contains(&self, hour: u8) -> bool {
+        if self.start_hour <= self.end_hour {
+            hour >= self.start_hour && hour < self.end_hour
+        } else {
+            hour >= self.start_hour || hour < self.end_hour
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct RecoveryRatePolicy {
+    pub rate: RecoveryRate,
+    pub schedule: Option<RecoverySchedule>,
+}
+
+impl RecoveryRatePolicy {
+    pub fn validate(&self) -> FroyoResult<()> {
+        try!(self.rate.validate());
+        if let Some(ref sched) = self.schedule {
+            if sched.start_hour > 23 || sched.end_hour > 23 {
+                return Err(FroyoError::Froyo(InternalError(
+                    format!("Schedule hours must be 0-23, {}-{} given",
+                            sched.start_hour, sched.end_hour).into())))
+            }
+            if sched.start_hour == sched.end_hour {
+                return Err(FroyoError::Froyo(InternalError(
+                    "Schedule start and end hours must differ".into())))
+            }
+            try!(sched.rate.validate());
+        }
+
+        Ok(())
+    }
+
+    // The rate to use at the given hour of the day
+    pub fn current(&self, hour: u8) -> RecoveryRate {
+        match self.schedule {
+            Some(ref sched) if sched.contains(hour) => sched.rate,
+            _ => self.rate,
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct RaidDev {
     pub id: String,
@@ -39,6 +126,7 @@ pub struct RaidDev {
     pub region_sectors: Sectors,
     pub length: Sectors,
     pub members: Vec<RaidMember>,
+    pub recovery_rate: RecoveryRate,
     used: BTreeMap<SectorOffset, Sectors>,
 }
 
@@ -61,6 +149,22 @@ pub enum RaidAction {
     Unknown,
 }
 
+impl RaidAction {
+    // The kernel's name for the action
+    pub fn name(&self) -> &'static str {
+        match *self {
+            RaidAction::Idle => "idle",
+            RaidAction::Frozen => "frozen",
+            RaidAction::Resync => "resync",
+            RaidAction::Recover => "recover",
+            RaidAction::Check => "check",
+            RaidAction::Repair => "repair",
+            RaidAction::Reshape => "reshape",
+            RaidAction::Unknown => "unknown",
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum RaidMember {
     Present(Rc<RefCell<LinearDev>>),
@@ -83,7 +187,8 @@ impl RaidDev {
         devs: &[RaidMember],
         stripe: Sectors,
         region: Sectors,
-        rebuild: Option<usize>)
+        rebuild: Option<usize>,
+        rate: RecoveryRate)
         -> String {
 
         let raid_texts: Vec<_> = devs.iter()
@@ -99,19 +204,20 @@ impl RaidDev {
                  })
             .collect();
 
-        match rebuild {
-            None => format!("raid5_ls 3 {} region_size {} {} {}",
-                            *stripe,
-                            *region,
-                            raid_texts.len(),
-                            raid_texts.join(" ")),
-            Some(idx) => format!("raid5_ls 5 {} region_size {} rebuild {} {} {}",
-                                 *stripe,
-                                 *region,
-                                 idx,
-                                 raid_texts.len(),
-                                 raid_texts.join(" ")),
+        let mut params = vec![(*stripe).to_string(),
+                              "region_size".to_owned(),
+                              (*region).to_string()];
+        if let Some(idx) = rebuild {
+            params.push("rebuild".to_owned());
+            params.push(idx.to_string());
         }
+        params.extend(rate.raid_params());
+
+        format!("raid5_ls {} {} {} {}",
+                params.len(),
+                params.join(" "),
+                raid_texts.len(),
+                raid_texts.join(" "))
     }
 
     pub fn setup(dm: &DM, name: &str, id: String, devs: Vec<RaidMember>,
@@ -143,7 +249,8 @@ impl RaidDev {
         let target_length = first_present_dev_len
             * Sectors((devs.len() - REDUNDANCY) as u64);
 
-        let params = Self::make_raid_params(&devs, stripe, region, None);
+        let params = Self::make_raid_params(
+            &devs, stripe, region, None, RecoveryRate::default());
         let raid_table = [(0u64, *target_length, "raid", params)];
         let dm_name = format!("froyo-raid5-{}-{}", name, id);
         let raid_dev = try!(DmDevice::new(dm, &dm_name, &raid_table));
@@ -155,6 +262,7 @@ impl RaidDev {
             region_sectors: region,
             length: target_length,
             members: devs,
+            recovery_rate: RecoveryRate::default(),
             used: BTreeMap::new(),
         })
     }
@@ -186,13 +294,25 @@ impl RaidDev {
 
     pub fn reload(&mut self, dm: &DM, rebuild: Option<usize>) -> FroyoResult<()> {
         let params = Self::make_raid_params(
-            &self.members, self.stripe_sectors, self.region_sectors, rebuild);
+            &self.members, self.stripe_sectors, self.region_sectors, rebuild,
+            self.recovery_rate);
         let raid_table = [(0u64, *self.length, "raid", params)];
         try!(self.dev.reload(dm, &raid_table));
 
         Ok(())
     }
 
+    // Resync position is kept in the members' metadata, so this
+    // takes effect without restarting a resync.
+    pub fn set_recovery_rate(&mut self, dm: &DM, rate: RecoveryRate) -> FroyoResult<()> {
+        if rate == self.recovery_rate {
+            return Ok(())
+        }
+
+        self.recovery_rate = rate;
+        self.reload(dm, None)
+    }
+
     pub fn to_save(&self) -> RaidDevSave {
         RaidDevSave {
             stripe_sectors: self.stripe_sectors,
@@ -241,7 +361,7 @@ impl RaidDev {
         avail_vec
     }
 
-    fn avail_sectors(&self) -> Sectors {
+    pub fn avail_sectors(&self) -> Sectors {
         self.avail_areas().into_iter()
             .map(|(_, len)| len)
             .sum()
@@ -274,7 +394,7 @@ impl RaidDev {
         (size - needed, segs)
     }
 
-    pub fn status(&self) -> FroyoResult<(RaidStatus, RaidAction)> {
+    fn status_vals(&self) -> FroyoResult<Vec<String>> {
         let dm = try!(DM::new());
 
         let mut status = try!(self.dev.table_status(&dm));
@@ -287,13 +407,45 @@ impl RaidDev {
 
         // See kernel's dm-raid.txt "Status Output"
         let status_line = status.pop().unwrap().3;
-        let status_vals = status_line.split(' ').collect::<Vec<_>>();
+        let status_vals = status_line.split(' ')
+            .map(|x| x.to_owned())
+            .collect::<Vec<_>>();
         if status_vals.len() < 5 {
             return Err(FroyoError::Io(io::Error::new(
                 io::ErrorKind::InvalidData,
                 "Kernel returned too few values from raid status")))
         }
 
+        Ok(status_vals)
+    }
+
+    // Sectors synced so far, and the total to sync
+    pub fn sync_progress(&self) -> FroyoResult<(Sectors, Sectors)> {
+        let status_vals = try!(self.status_vals());
+
+        parse_sync_ratio(&status_vals[3])
+            .ok_or_else(|| FroyoError::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Kernel returned bad sync ratio in raid status")))
+    }
+
+    // Inconsistencies found by the last check or repair. Older
+    // kernels don't report this, so it's 0 there.
+    pub fn mismatch_count(&self) -> FroyoResult<u64> {
+        let status_vals = try!(self.status_vals());
+
+        match status_vals.get(5) {
+            Some(val) => val.parse::<u64>()
+                .map_err(|_| FroyoError::Io(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Kernel returned bad mismatch count in raid status"))),
+            None => Ok(0),
+        }
+    }
+
+    pub fn status(&self) -> FroyoResult<(RaidStatus, RaidAction)> {
+        let status_vals = try!(self.status_vals());
+
         let mut bad = 0;
         for c in status_vals[2].chars() {
             match c {
@@ -318,7 +470,7 @@ impl RaidDev {
             _ => RaidStatus::Failed,
         };
 
-        let raid_action = match status_vals[4] {
+        let raid_action = match &*status_vals[4] {
             "idle" => RaidAction::Idle,
             "frozen" => RaidAction::Frozen,
             "resync" => RaidAction::Resync,
@@ -472,7 +624,7 @@ impl RaidDevs {
             let id = rd.borrow().id.clone();
 
             if let (RaidStatus::Failed, _) = try!(rd.borrow().status()) {
-                return Err(FroyoError::Froyo(InternalError(
+                return Err(FroyoError::Typed(FroyoErrorKind::Corrupt, InternalError(
                     format!("Froyodev {} has a failed raid",
                             froyo_save.name).into())))
             }
@@ -525,12 +677,13 @@ impl RaidDevs {
                             }
                         },
                         None => {
-                            return Err(FroyoError::Io(io::Error::new(
-                                io::ErrorKind::InvalidInput,
-                                format!("Invalid metadata, raiddev {} references \
-                                         blockdev {} that is not found in \
-                                         blockdev list",
-                                        raid_id, sld.parent))))
+                            return Err(FroyoError::Typed(
+                                FroyoErrorKind::Corrupt,
+                                InternalError(format!(
+                                    "Invalid metadata, raiddev {} references \
+                                     blockdev {} that is not found in \
+                                     blockdev list",
+                                    raid_id, sld.parent).into())))
                         },
                     }
                 },
@@ -577,28 +730,81 @@ impl RaidDevs {
 
         let scratch_needed = self.scratch_needed();
 
+        let bds = block_devs.0.values()
+            .filter_map(|bm| bm.present())
+            .collect::<Vec<_>>();
+        let largest_areas = bds.iter()
+            .map(|bd| bd.borrow().largest_avail_area())
+            .collect::<Vec<_>>();
+        let sizes = bds.iter().zip(&largest_areas)
+            .map(|(bd, area)| (bd.borrow().sectors, area.map_or(Sectors(0), |(_, len)| len)))
+            .collect::<Vec<_>>();
+
+        let (member_idxs, common_avail_sectors) =
+            match RaidDevs::zone_members(&sizes, scratch_needed) {
+                Some(x) => x,
+                None => return Ok(None),
+            };
+
+        let (region_sectors, mdata_sectors, data_sectors) =
+            RaidDevs::zone_layout(common_avail_sectors);
+
+        let raid_uuid = Uuid::new_v4().to_simple_string();
+
+        let mut linear_devs = Vec::new();
+        for (num, idx) in member_idxs.into_iter().enumerate() {
+            let bd = &bds[idx];
+            let mdata_sector_start = largest_areas[idx].unwrap().0;
+            let data_sector_start = SectorOffset(*mdata_sector_start + *mdata_sectors);
+
+            let linear = Rc::new(RefCell::new(try!(LinearDev::new(
+                &dm,
+                &format!("{}-{}-{}", name, raid_uuid, num),
+                bd,
+                &[LinearSegment::new(mdata_sector_start, mdata_sectors)],
+                &[LinearSegment::new(data_sector_start, data_sectors)]))));
+
+            bd.borrow_mut().linear_devs.insert(
+                linear.borrow().meta_dev.dm_name.clone(),
+                linear.clone());
+
+            linear_devs.push(RaidMember::Present(linear));
+        }
+
+        let raid = try!(RaidDev::setup(
+            &dm,
+            &name,
+            raid_uuid,
+            linear_devs,
+            STRIPE_SECTORS,
+            region_sectors));
+
+        Ok(Some(raid))
+    }
+
+    // Which blockdevs a new redundant zone goes on, and how much
+    // space to use on each, before zone_layout(). Takes the size and
+    // largest available area of each present blockdev, and returns
+    // indexes into them.
+    pub fn zone_members(bd_sizes: &[(Sectors, Sectors)], scratch_needed: Sectors)
+                        -> Option<(Vec<usize>, Sectors)> {
         // get common data area size, allowing for Froyo data at start and end
-        let mut bd_areas: Vec<_> = block_devs.0.values()
-            .filter_map(|bd| bd.present())
-            .filter_map(|bd| {
-                match bd.borrow().largest_avail_area() {
-                    Some(x) => Some((bd.clone(), x.0, x.1)),
-                    None => None,
-                }
-            })
-            .filter(|&(_, _, len)| len >= scratch_needed)
-            .map(|(bd, off, len)| (bd, off, len - scratch_needed))
-            .filter(|&(_, _, len)| len >= MIN_DATA_ZONE_SECTORS)
-            .collect();
+        let areas = bd_sizes.iter()
+            .map(|&(_, largest)| largest)
+            .enumerate()
+            .filter(|&(_, len)| len >= scratch_needed)
+            .map(|(idx, len)| (idx, len - scratch_needed))
+            .filter(|&(_, len)| len >= MIN_DATA_ZONE_SECTORS)
+            .collect::<Vec<_>>();
 
         // Not enough devs with room for a raid device
-        if bd_areas.len() < 2 {
-            return Ok(None)
+        if areas.len() < 2 {
+            return None
         }
 
         // Ensure we leave enough scratch space to handle a reshape
-        let common_avail_sectors = bd_areas.iter()
-            .map(|&(_, _, len)| len)
+        let common_avail_sectors = areas.iter()
+            .map(|&(_, len)| len)
             .min()
             .unwrap();
 
@@ -610,9 +816,8 @@ impl RaidDevs {
         // Use size of 2nd largest bdev, which is guaranteed to be
         // used fully by raids, unlike the largest.
         let second_largest_bdev = {
-            let mut sizes = block_devs.0.values()
-                .filter_map(|bm| bm.present())
-                .map(|bd| bd.borrow().sectors)
+            let mut sizes = bd_sizes.iter()
+                .map(|&(size, _)| size)
                 .collect::<Vec<_>>();
             sizes.sort();
             sizes.pop();
@@ -623,6 +828,12 @@ impl RaidDevs {
             MIN_DATA_ZONE_SECTORS);
         let common_avail_sectors = min(common_avail_sectors, clamped_size);
 
+        Some((areas.into_iter().map(|(idx, _)| idx).collect(), common_avail_sectors))
+    }
+
+    // Region size, and metadata and data sectors per member, for a
+    // raid using this much space on each member.
+    pub fn zone_layout(common_avail_sectors: Sectors) -> (Sectors, Sectors, Sectors) {
         // Handle raid regions and calc metadata size
         let (region_count, region_sectors) = {
             let mut region_sectors = DEFAULT_REGION_SECTORS;
@@ -647,61 +858,54 @@ impl RaidDevs {
         let data_sectors = (common_avail_sectors - mdata_sectors)
             & Sectors(!(*STRIPE_SECTORS-1));
 
-        let raid_uuid = Uuid::new_v4().to_simple_string();
-
-        let mut linear_devs = Vec::new();
-        for (num, &mut (ref mut bd, sector_start, _)) in bd_areas.iter_mut().enumerate() {
-            let mdata_sector_start = sector_start;
-            let data_sector_start = SectorOffset(*mdata_sector_start + *mdata_sectors);
-
-            let linear = Rc::new(RefCell::new(try!(LinearDev::new(
-                &dm,
-                &format!("{}-{}-{}", name, raid_uuid, num),
-                bd,
-                &[LinearSegment::new(mdata_sector_start, mdata_sectors)],
-                &[LinearSegment::new(data_sector_start, data_sectors)]))));
-
-            bd.borrow_mut().linear_devs.insert(
-                linear.borrow().meta_dev.dm_name.clone(),
-                linear.clone());
-
-            linear_devs.push(RaidMember::Present(linear));
-        }
-
-        let raid = try!(RaidDev::setup(
-            &dm,
-            &name,
-            raid_uuid,
-            linear_devs,
-            STRIPE_SECTORS,
-            region_sectors));
-
-        Ok(Some(raid))
+        (region_sectors, mdata_sectors, data_sectors)
     }
 
-    pub fn alloc_raid_segments(&self, sectors: Sectors) -> Option<Vec<RaidSegment>> {
+    // How much to take from each of these amounts of free space, in
+    // order, to make up `sectors`. None if there isn't enough.
+    pub fn split_allocation<K, I>(sectors: Sectors, avail: I) -> Option<Vec<(K, Sectors)>>
+        where I: IntoIterator<Item = (K, Sectors)>
+    {
         let mut needed = sectors;
-        let mut segs = Vec::new();
-        for rd in self.raids.values() {
+        let mut split = Vec::new();
+        for (key, len) in avail {
             if needed == Sectors(0) {
                 break
             }
-            if !rd.borrow().is_safe() {
-                continue
+            let to_use = min(needed, len);
+            if to_use > Sectors(0) {
+                split.push((key, to_use));
+                needed = needed - to_use;
             }
-            let (gotten, r_segs) = rd.borrow().get_some_space(needed);
-            segs.extend(r_segs.iter()
-                        .map(|&(start, len)|
-                             RaidSegment::new(start, len, RaidLayer::Raid(rd.clone()))));
-            needed = needed - gotten;
         }
 
         match *needed {
-            0 => Some(segs),
+            0 => Some(split),
             _ => None,
         }
     }
 
+    pub fn alloc_raid_segments(&self, sectors: Sectors) -> Option<Vec<RaidSegment>> {
+        let split = match RaidDevs::split_allocation(
+            sectors,
+            self.raids.values()
+                .filter(|rd| rd.borrow().is_safe())
+                .map(|rd| (rd.clone(), rd.borrow().avail_sectors()))) {
+            Some(x) => x,
+            None => return None,
+        };
+
+        let mut segs = Vec::new();
+        for (rd, len) in split {
+            let (_, areas) = rd.borrow().get_some_space(len);
+            segs.extend(areas.into_iter()
+                        .map(|(start, len)|
+                             RaidSegment::new(start, len, RaidLayer::Raid(rd.clone()))));
+        }
+
+        Some(segs)
+    }
+
     pub fn lookup_segment(&self, id: &str, start: SectorOffset, length: Sectors)
                           -> Option<RaidSegment> {
         match self.raids.get(id) {
@@ -724,12 +928,21 @@ impl RaidDevs {
     // raiddev capacity. This is overly generous but let's just do
     // this until we have reshape support
     fn scratch_needed(&self) -> Sectors {
-        self.raids.values()
-            .map(|rd| rd.borrow().length)
-            .max().unwrap_or_else(|| Sectors(0)) / Sectors(2) + Sectors(1)
+        RaidDevs::scratch_for(self.raids.values().map(|rd| rd.borrow().length))
+    }
+
+    pub fn scratch_for<I>(raid_lengths: I) -> Sectors
+        where I: Iterator<Item = Sectors>
+    {
+        raid_lengths.max().unwrap_or_else(|| Sectors(0)) / Sectors(2) + Sectors(1)
     }
 
     pub fn are_idle(&self) -> bool {
+        self.busy_count() == 0
+    }
+
+    // How many raids are syncing or otherwise not idle
+    pub fn busy_count(&self) -> usize {
         self.raids.iter()
             .map(|(_, rd)| {
                 match rd.borrow().status() {
@@ -742,7 +955,8 @@ impl RaidDevs {
                     }
                 }
             })
-            .all(|res| res)
+            .filter(|&idle| !idle)
+            .count()
     }
 
     pub fn max_used_raid_sectors(&self) -> Sectors {
@@ -912,7 +1126,7 @@ pub struct RaidLinearDevSave {
 
 #[derive(Debug)]
 pub struct RaidLinearDev {
-    id: String,
+    pub id: String,
     pub dev: DmDevice,
     pub segments: Vec<RaidSegment>,
 }
@@ -1017,3 +1231,85 @@ impl RaidLinearDev {
         map
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use types::Sectors;
+    use consts::*;
+    use super::RaidDevs;
+
+    const GIG: Sectors = MIN_DATA_ZONE_SECTORS;
+
+    fn gigs(n: u64) -> Sectors {
+        GIG * Sectors(n)
+    }
+
+    #[test]
+    fn split_allocation_in_order() {
+        let avail = vec![("a", Sectors(4)), ("b", Sectors(0)), ("c", Sectors(10)),
+                         ("d", Sectors(10))];
+        assert_eq!(RaidDevs::split_allocation(Sectors(10), avail),
+                   Some(vec![("a", Sectors(4)), ("c", Sectors(6))]));
+    }
+
+    #[test]
+    fn split_allocation_not_enough() {
+        let avail = vec![("a", Sectors(4)), ("b", Sectors(5))];
+        assert_eq!(RaidDevs::split_allocation(Sectors(10), avail), None);
+    }
+
+    #[test]
+    fn split_allocation_nothing() {
+        let avail: Vec<(&str, Sectors)> = Vec::new();
+        assert_eq!(RaidDevs::split_allocation(Sectors(0), avail), Some(Vec::new()));
+    }
+
+    #[test]
+    fn scratch_for_largest_raid() {
+        assert_eq!(RaidDevs::scratch_for(Vec::<Sectors>::new().into_iter()), Sectors(1));
+        let lengths = vec![Sectors(4), Sectors(10), Sectors(6)];
+        assert_eq!(RaidDevs::scratch_for(lengths.into_iter()), Sectors(6));
+    }
+
+    #[test]
+    fn zone_members_needs_two_devs() {
+        let sizes = [(gigs(10), gigs(10)), (gigs(10), Sectors(0))];
+        assert_eq!(RaidDevs::zone_members(&sizes, Sectors(1)), None);
+    }
+
+    #[test]
+    fn zone_members_skips_small_areas() {
+        let sizes = [(gigs(100), gigs(100)), (gigs(100), GIG / Sectors(2)),
+                     (gigs(100), gigs(100))];
+        // Clamped to a tenth of the second largest device
+        assert_eq!(RaidDevs::zone_members(&sizes, Sectors(1)),
+                   Some((vec![0, 2], gigs(10))));
+    }
+
+    #[test]
+    fn zone_members_leaves_scratch() {
+        let sizes = [(gigs(100), gigs(3)), (gigs(100), gigs(5)), (gigs(100), gigs(2))];
+        assert_eq!(RaidDevs::zone_members(&sizes, GIG),
+                   Some((vec![0, 1, 2], GIG)));
+        assert_eq!(RaidDevs::zone_members(&sizes[..2], GIG),
+                   Some((vec![0, 1], gigs(2))));
+    }
+
+    #[test]
+    fn zone_members_max_size() {
+        let huge = MAX_DATA_ZONE_SECTORS * Sectors(100);
+        let sizes = [(huge, huge), (huge, huge)];
+        assert_eq!(RaidDevs::zone_members(&sizes, Sectors(1)),
+                   Some((vec![0, 1], MAX_DATA_ZONE_SECTORS)));
+    }
+
+    #[test]
+    fn zone_layout_fits() {
+        for &common in &[GIG, gigs(10), MAX_DATA_ZONE_SECTORS] {
+            let (region_sectors, mdata_sectors, data_sectors) = RaidDevs::zone_layout(common);
+            assert!(mdata_sectors + data_sectors <= common);
+            assert_eq!(data_sectors % STRIPE_SECTORS, Sectors(0));
+            assert!(*common / *region_sectors <= MAX_REGIONS);
+        }
+    }
+}
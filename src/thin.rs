@@ -3,35 +3,203 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 use std::io;
-use std::process::Command;
+use std::io::Write;
+use std::env;
+use std::error::Error;
+use std::process::{Command, Stdio};
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use std::cell::RefCell;
+use std::cmp::min;
+use std::os::unix::io::{FromRawFd, IntoRawFd};
 
-use devicemapper::{DM, Device, DmFlags, DevId, DM_SUSPEND};
+use devicemapper::{DM, Device, DmFlags, DevId, DM_STATUS_TABLE};
 use uuid::Uuid;
+use time;
 use nix::sys::stat::{mknod, umask, Mode, S_IFBLK, S_IRUSR, S_IWUSR, S_IRGRP, S_IWGRP};
 use nix::errno::EEXIST;
 
-use types::{Sectors, DataBlocks, FroyoError, FroyoResult, InternalError};
+use types::{Sectors, DataBlocks, FroyoError, FroyoErrorKind, FroyoResult, InternalError};
 use raid::{RaidSegment, RaidLinearDev, RaidLinearDevSave};
 use dmdevice::DmDevice;
+use util::mount_points;
 use consts::*;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ThinPoolDevSave {
     pub data_block_size: Sectors,
     pub low_water_blocks: DataBlocks,
+    // In metadata blocks, not data blocks
+    #[serde(default)]
+    pub meta_low_water_blocks: Option<u64>,
+    #[serde(default)]
+    pub discard: DiscardMode,
+    #[serde(default)]
+    pub zero_blocks: bool,
+    #[serde(default)]
+    pub error_if_no_space: bool,
     pub meta_dev: RaidLinearDevSave,
     pub data_dev: RaidLinearDevSave,
 }
 
+impl ThinPoolDevSave {
+    pub fn options(&self) -> ThinPoolOptions {
+        ThinPoolOptions {
+            data_block_size: self.data_block_size,
+            zero_blocks: self.zero_blocks,
+            discard: self.discard,
+            error_if_no_space: self.error_if_no_space,
+        }
+    }
+}
+
+// Thin pool tuning, chosen when the froyodev is created.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ThinPoolOptions {
+    pub data_block_size: Sectors,
+    // Zero newly-provisioned blocks before use, so a thin dev never
+    // sees stale data from deleted ones
+    pub zero_blocks: bool,
+    pub discard: DiscardMode,
+    // Fail writes instead of queueing them when out of data space
+    pub error_if_no_space: bool,
+}
+
+impl Default for ThinPoolOptions {
+    fn default() -> ThinPoolOptions {
+        ThinPoolOptions {
+            data_block_size: DATA_BLOCK_SIZE,
+            zero_blocks: false,
+            discard: DiscardMode::default(),
+            error_if_no_space: false,
+        }
+    }
+}
+
+impl ThinPoolOptions {
+    pub fn validate(&self) -> FroyoResult<()> {
+        let size = self.data_block_size;
+        if size < TPOOL_MIN_DATA_BLOCK_SIZE || size > TPOOL_MAX_DATA_BLOCK_SIZE
+            || *size % *TPOOL_MIN_DATA_BLOCK_SIZE != 0 {
+                return Err(FroyoError::Froyo(InternalError(
+                    format!("Data block size must be a multiple of {} sectors \
+                             between {} and {} sectors, {} given",
+                            *TPOOL_MIN_DATA_BLOCK_SIZE, *TPOOL_MIN_DATA_BLOCK_SIZE,
+                            *TPOOL_MAX_DATA_BLOCK_SIZE, *size).into())))
+            }
+
+        Ok(())
+    }
+}
+
+// What the thin pool does with discards from thin devs. Even with
+// Passdown, the kernel won't pass them to the raids if the raids
+// don't support discards.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum DiscardMode {
+    Ignore,
+    NoPassdown,
+    Passdown,
+}
+
+impl Default for DiscardMode {
+    fn default() -> DiscardMode {
+        DiscardMode::Passdown
+    }
+}
+
+impl DiscardMode {
+    pub fn parse(s: &str) -> FroyoResult<DiscardMode> {
+        match s {
+            "ignore" => Ok(DiscardMode::Ignore),
+            "nopassdown" => Ok(DiscardMode::NoPassdown),
+            "passdown" => Ok(DiscardMode::Passdown),
+            _ => Err(FroyoError::Froyo(InternalError(
+                format!("Discard mode must be ignore, nopassdown, or passdown, \
+                         \"{}\" given", s).into()))),
+        }
+    }
+}
+
+// How often to thin_dump the pool metadata, where to, and how many
+// dumps to keep.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MetaBackupPolicy {
+    pub enabled: bool,
+    pub dir: String,
+    pub interval_secs: u64,
+    pub keep: u32,
+}
+
+impl Default for MetaBackupPolicy {
+    fn default() -> MetaBackupPolicy {
+        MetaBackupPolicy {
+            enabled: true,
+            dir: THIN_META_BACKUP_DIR.to_owned(),
+            interval_secs: THIN_META_BACKUP_INTERVAL_SECS,
+            keep: THIN_META_BACKUP_KEEP,
+        }
+    }
+}
+
+impl MetaBackupPolicy {
+    pub fn validate(&self) -> FroyoResult<()> {
+        if !Path::new(&self.dir).is_absolute() {
+            return Err(FroyoError::Froyo(InternalError(
+                format!("Backup directory must be an absolute path, {} given",
+                        self.dir).into())))
+        }
+        if self.keep == 0 {
+            return Err(FroyoError::Froyo(InternalError(
+                "Must keep at least one backup".into())))
+        }
+        if self.interval_secs == 0 {
+            return Err(FroyoError::Froyo(InternalError(
+                "Backup interval must be at least one second".into())))
+        }
+
+        Ok(())
+    }
+
+    pub fn froyo_dir(&self, froyo_id: &str) -> PathBuf {
+        Path::new(&self.dir).join(froyo_id)
+    }
+
+    // Backups for a froyodev, oldest first
+    pub fn list(&self, froyo_id: &str) -> FroyoResult<Vec<PathBuf>> {
+        let dir = self.froyo_dir(froyo_id);
+        let mut backups = Vec::new();
+
+        if !dir.exists() {
+            return Ok(backups)
+        }
+
+        for entry in try!(fs::read_dir(&dir)) {
+            let path = try!(entry).path();
+            let is_backup = path.file_name()
+                .and_then(|x| x.to_str())
+                .map_or(false, |x| x.starts_with("thin-meta-") && x.ends_with(".xml.gz"));
+            if is_backup {
+                backups.push(path);
+            }
+        }
+
+        // Names contain a sortable timestamp
+        backups.sort();
+        Ok(backups)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ThinPoolDev {
     dev: DmDevice,
     data_block_size: Sectors,
     pub low_water_blocks: DataBlocks,
+    pub meta_low_water_blocks: u64,
+    pub discard: DiscardMode,
+    pub zero_blocks: bool,
+    pub error_if_no_space: bool,
     params: String,
     pub meta_dev: Rc<RefCell<RaidLinearDev>>,
     pub data_dev: Rc<RefCell<RaidLinearDev>>,
@@ -63,7 +231,8 @@ impl ThinPoolDev {
     pub fn new(dm: &DM,
                id: &str,
                meta_segs: Vec<RaidSegment>,
-               data_segs: Vec<RaidSegment>)
+               data_segs: Vec<RaidSegment>,
+               options: ThinPoolOptions)
                -> FroyoResult<ThinPoolDev> {
         // meta
         let meta_name = format!("thin-meta-{}", id);
@@ -83,11 +252,16 @@ impl ThinPoolDev {
             &Uuid::new_v4().to_simple_string(),
             data_segs));
 
+        // Keep the low water mark the same amount of space no matter
+        // the block size
+        let low_water_blocks = DataBlocks(
+            TPOOL_LOW_WATER_BLOCKS * *DATA_BLOCK_SIZE / *options.data_block_size);
+
         ThinPoolDev::setup(
             dm,
             id,
-            DATA_BLOCK_SIZE,
-            DataBlocks(TPOOL_LOW_WATER_BLOCKS),
+            low_water_blocks,
+            options,
             meta_raid_dev,
             data_raid_dev)
     }
@@ -95,44 +269,223 @@ impl ThinPoolDev {
     pub fn setup(
         dm: &DM,
         id: &str,
-        data_block_size: Sectors,
         low_water_blocks: DataBlocks,
+        options: ThinPoolOptions,
         meta_raid_dev: RaidLinearDev,
         data_raid_dev: RaidLinearDev)
         -> FroyoResult<ThinPoolDev> {
 
-        let params = format!("{} {} {} {} 1 skip_block_zeroing",
+        let mut features = Vec::new();
+        if !options.zero_blocks {
+            features.push("skip_block_zeroing");
+        }
+        match options.discard {
+            DiscardMode::Ignore => features.push("ignore_discard"),
+            DiscardMode::NoPassdown => features.push("no_discard_passdown"),
+            DiscardMode::Passdown => {},
+        }
+        if options.error_if_no_space {
+            features.push("error_if_no_space");
+        }
+
+        let params = format!("{} {} {} {} {} {}",
                              meta_raid_dev.dev.dstr(),
                              data_raid_dev.dev.dstr(),
-                             *data_block_size,
-                             *low_water_blocks);
+                             *options.data_block_size,
+                             *low_water_blocks,
+                             features.len(),
+                             features.join(" "));
         let table = [(0u64, *data_raid_dev.length(), "thin-pool", &*params)];
 
         let dm_name = format!("froyo-thin-pool-{}", id);
         let pool_dev = try!(DmDevice::new(dm, &dm_name, &table));
 
-        let tpool = ThinPoolDev {
+        let mut tpool = ThinPoolDev {
             dev: pool_dev,
-            data_block_size: data_block_size,
+            data_block_size: options.data_block_size,
             low_water_blocks: low_water_blocks,
+            meta_low_water_blocks: TPOOL_META_LOW_WATER_BLOCKS,
+            discard: options.discard,
+            zero_blocks: options.zero_blocks,
+            error_if_no_space: options.error_if_no_space,
             params: params.clone(),
             meta_dev: Rc::new(RefCell::new(meta_raid_dev)),
             data_dev: Rc::new(RefCell::new(data_raid_dev)),
         };
 
-        // TODO: if needs_check, run the check
+        if let ThinPoolStatus::Good((ThinPoolWorkingStatus::NeedsCheck, _)) =
+            try!(tpool.status()) {
+                // thin_check needs the pool to be inactive
+                dbgp!("Thin pool {} needs a check", dm_name);
+                try!(tpool.dev.teardown(dm));
+                {
+                    let meta_dev = tpool.meta_dev.borrow();
+                    try!(ThinPoolDev::check_meta(&meta_dev).map_err(|e| {
+                        FroyoError::Typed(FroyoErrorKind::Corrupt, InternalError(
+                            format!("Thin pool metadata check failed ({}), \
+                                     run \"froyo dev check_thin_meta --repair\"",
+                                    e.description()).into()))
+                    }));
+                    try!(ThinPoolDev::clear_needs_check(&meta_dev));
+                }
+                tpool.dev = try!(DmDevice::new(dm, &dm_name, &table));
+            }
+
         match try!(tpool.status()) {
             ThinPoolStatus::Good((ThinPoolWorkingStatus::Good, _)) => {}
-            ThinPoolStatus::Good((ThinPoolWorkingStatus::NeedsCheck, _)) =>
-                return Err(FroyoError::Froyo(InternalError(
-                    "Froyodev thin pool needs a check".into()))),
-            bad => return Err(FroyoError::Froyo(InternalError(
+            bad => return Err(FroyoError::Typed(FroyoErrorKind::Corrupt, InternalError(
                 format!("Froyodev has a failed thin pool: {:?}", bad).into())))
         }
 
         Ok(tpool)
     }
 
+    fn run_thin_tool(cmd: &mut Command) -> FroyoResult<()> {
+        dbgp!("Running {:?}", cmd);
+        let output = try!(cmd.output());
+
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(FroyoError::Froyo(InternalError(
+                format!("{:?} failed: {}", cmd,
+                        String::from_utf8_lossy(&output.stderr)).into())))
+        }
+    }
+
+    fn meta_path(meta_dev: &RaidLinearDev) -> FroyoResult<PathBuf> {
+        meta_dev.dev.dev.path().ok_or_else(|| FroyoError::Froyo(InternalError(
+            format!("No device node for {}", meta_dev.dev.dm_name).into())))
+    }
+
+    // Check the thin pool metadata for errors. The pool must not be
+    // active.
+    pub fn check_meta(meta_dev: &RaidLinearDev) -> FroyoResult<()> {
+        let path = try!(ThinPoolDev::meta_path(meta_dev));
+        ThinPoolDev::run_thin_tool(Command::new("thin_check").arg(&path))
+    }
+
+    pub fn clear_needs_check(meta_dev: &RaidLinearDev) -> FroyoResult<()> {
+        let path = try!(ThinPoolDev::meta_path(meta_dev));
+        ThinPoolDev::run_thin_tool(Command::new("thin_check")
+                                   .arg("--clear-needs-check-flag")
+                                   .arg(&path))
+    }
+
+    // Write a repaired copy of the metadata on src_dev to dest_dev.
+    // The pool must not be active.
+    pub fn repair_meta(src_dev: &RaidLinearDev, dest_dev: &RaidLinearDev)
+                       -> FroyoResult<()> {
+        let src_path = try!(ThinPoolDev::meta_path(src_dev));
+        let dest_path = try!(ThinPoolDev::meta_path(dest_dev));
+        ThinPoolDev::run_thin_tool(Command::new("thin_repair")
+                                   .arg("-i")
+                                   .arg(&src_path)
+                                   .arg("-o")
+                                   .arg(&dest_path))
+    }
+
+    // Restore the metadata in a compressed thin_dump backup to
+    // dest_dev. The pool must not be active.
+    pub fn restore_meta(backup: &Path, dest_dev: &RaidLinearDev) -> FroyoResult<()> {
+        let dest_path = try!(ThinPoolDev::meta_path(dest_dev));
+
+        let output = try!(Command::new("gzip")
+                          .arg("-dc")
+                          .arg(backup)
+                          .output());
+        if !output.status.success() {
+            return Err(FroyoError::Froyo(InternalError(
+                format!("Could not decompress {}: {}", backup.display(),
+                        String::from_utf8_lossy(&output.stderr)).into())))
+        }
+
+        // thin_restore only reads from a file
+        let xml_path = env::temp_dir().join(
+            format!("froyo-thin-restore-{}.xml", dest_dev.id));
+        {
+            let mut f = try!(fs::File::create(&xml_path));
+            try!(f.write_all(&output.stdout));
+        }
+
+        let res = ThinPoolDev::run_thin_tool(Command::new("thin_restore")
+                                             .arg("-i")
+                                             .arg(&xml_path)
+                                             .arg("-o")
+                                             .arg(&dest_path));
+        try!(fs::remove_file(&xml_path));
+
+        res
+    }
+
+    // Dump a metadata snapshot of the live pool to a compressed file
+    // in the policy's directory, and prune old backups.
+    pub fn backup_meta(&self, dm: &DM, froyo_id: &str, policy: &MetaBackupPolicy)
+                       -> FroyoResult<PathBuf> {
+        let dir = policy.froyo_dir(froyo_id);
+        try!(fs::create_dir_all(&dir));
+
+        let meta_path = try!(ThinPoolDev::meta_path(&self.meta_dev.borrow()));
+
+        try!(self.dev.message(dm, "reserve_metadata_snap"));
+        let dump = Command::new("thin_dump")
+            .arg("--metadata-snap")
+            .arg(&meta_path)
+            .output();
+        try!(self.dev.message(dm, "release_metadata_snap"));
+
+        let dump = try!(dump);
+        if !dump.status.success() {
+            return Err(FroyoError::Froyo(InternalError(
+                format!("thin_dump failed: {}",
+                        String::from_utf8_lossy(&dump.stderr)).into())))
+        }
+
+        let timestamp = try!(time::strftime("%Y%m%d%H%M%S", &time::now_utc())
+                             .map_err(|e| FroyoError::Froyo(InternalError(
+                                 format!("{}", e).into()))));
+        let path = dir.join(format!("thin-meta-{}.xml.gz", timestamp));
+        let tmp_path = path.with_extension("tmp");
+        if let Err(e) = ThinPoolDev::compress_to(&dump.stdout, &tmp_path) {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(e)
+        }
+        try!(fs::rename(&tmp_path, &path));
+        dbgp!("Backed up thin pool metadata to {}", path.display());
+
+        let backups = try!(policy.list(froyo_id));
+        let excess = backups.len().saturating_sub(policy.keep as usize);
+        for old in &backups[..excess] {
+            dbgp!("Removing old metadata backup {}", old.display());
+            try!(fs::remove_file(old));
+        }
+
+        Ok(path)
+    }
+
+    // gzip writes straight to the file, so it can't block on a full
+    // pipe while we're still feeding it.
+    fn compress_to(data: &[u8], path: &Path) -> FroyoResult<()> {
+        let f = try!(fs::File::create(path));
+        let out = try!(f.try_clone());
+
+        let mut gzip = try!(Command::new("gzip")
+                            .arg("-c")
+                            .stdin(Stdio::piped())
+                            .stdout(unsafe { Stdio::from_raw_fd(out.into_raw_fd()) })
+                            .spawn());
+        let written = gzip.stdin.take().unwrap().write_all(data);
+        let status = try!(gzip.wait());
+        try!(written);
+        if !status.success() {
+            return Err(FroyoError::Froyo(InternalError(
+                "Compressing thin pool metadata failed".into())))
+        }
+
+        try!(f.sync_all());
+        Ok(())
+    }
+
     pub fn teardown(&mut self, dm: &DM) -> FroyoResult<()> {
         try!(self.dev.teardown(dm));
         try!(self.meta_dev.borrow_mut().teardown(dm));
@@ -145,6 +498,10 @@ impl ThinPoolDev {
         ThinPoolDevSave {
             data_block_size: self.data_block_size,
             low_water_blocks: self.low_water_blocks,
+            meta_low_water_blocks: Some(self.meta_low_water_blocks),
+            discard: self.discard,
+            zero_blocks: self.zero_blocks,
+            error_if_no_space: self.error_if_no_space,
             meta_dev: self.meta_dev.borrow().to_save(),
             data_dev: self.data_dev.borrow().to_save(),
         }
@@ -206,6 +563,45 @@ impl ThinPoolDev {
         }
     }
 
+    // Whether discards are actually reaching the raids. The kernel
+    // turns off passdown if the data dev doesn't support discards.
+    pub fn discard_passdown(&self) -> FroyoResult<bool> {
+        let dm = try!(DM::new());
+
+        let mut status = try!(self.dev.table_status(&dm));
+
+        if status.len() != 1 {
+            return Err(FroyoError::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Expected 1 line from thin pool status")))
+        }
+
+        let status_line = status.pop().unwrap().3;
+        if status_line.starts_with("Fail") {
+            return Ok(false)
+        }
+
+        let status_vals = status_line.split(' ').collect::<Vec<_>>();
+        if status_vals.len() < 8 {
+            return Err(FroyoError::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Kernel returned too few values from thin pool status")))
+        }
+
+        match status_vals[5] {
+            "discard_passdown" => Ok(true),
+            // With ignore_discard, none are passed down either
+            "no_discard_passdown" | "ignore_discard" => Ok(false),
+            _ => Err(FroyoError::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Kernel returned unexpected value in thin pool status")))
+        }
+    }
+
+    pub fn dm_name(&self) -> &str {
+        &self.dev.dm_name
+    }
+
     // return size of a data block in bytes
     pub fn data_block_size(&self) -> u64 {
         *self.data_block_size * SECTOR_SIZE
@@ -252,16 +648,66 @@ pub struct ThinDevSave {
     pub name: String,
     pub thin_number: u32,
     pub size: Sectors,
+    #[serde(default)]
+    pub pending_grow: bool,
+    #[serde(default)]
+    pub size_policy: ThinSizePolicy,
+}
+
+// How a thin dev's virtual size is managed as it fills up.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ThinSizePolicy {
+    pub auto_extend: bool,
+    pub threshold_pct: u8,
+    pub extend_pct: u8,
+    // Never extend past this size. Zero means no limit.
+    pub max_size: Sectors,
+}
+
+impl Default for ThinSizePolicy {
+    fn default() -> ThinSizePolicy {
+        ThinSizePolicy {
+            auto_extend: true,
+            threshold_pct: THIN_EXTEND_THRESHOLD_PCT,
+            extend_pct: THIN_EXTEND_PCT,
+            max_size: Sectors(0),
+        }
+    }
+}
+
+impl ThinSizePolicy {
+    pub fn validate(&self) -> FroyoResult<()> {
+        if self.threshold_pct == 0 || self.threshold_pct > 100 {
+            return Err(FroyoError::Froyo(InternalError(
+                format!("Threshold must be 1-100%, {} given",
+                        self.threshold_pct).into())))
+        }
+        if self.extend_pct == 0 {
+            return Err(FroyoError::Froyo(InternalError(
+                "Extend percentage must be nonzero".into())))
+        }
+
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct ThinDev {
     dev: DmDevice,
-    name: String,
+    pub name: String,
     pub thin_number: u32,
     pub size: Sectors,
+    pub size_policy: ThinSizePolicy,
     dm_name: String,
     params: String,
+    // The device was extended but the filesystem on it has not been
+    // grown to match yet, because it wasn't mounted.
+    pub pending_grow: bool,
+    pub grow_failed: bool,
+    // When throttled, the thin target moves to this device, and the
+    // thin dev becomes a dm-delay over it. This keeps the device
+    // number that's mounted the same.
+    throttle_dev: Option<DmDevice>,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -312,13 +758,24 @@ impl ThinDev {
 
         try!(ThinDev::create_devnode(name, thin_dev.dev));
 
+        // We may have exited while throttled
+        let throttle_name = ThinDev::throttle_dm_name(&dm_name);
+        let throttle_dev = match dm.device_status(&DevId::Name(&throttle_name)) {
+            Ok(_) => Some(try!(DmDevice::new(dm, &throttle_name, &table))),
+            Err(_) => None,
+        };
+
         let thin = ThinDev {
             dev: thin_dev,
             name: name.to_owned(),
             thin_number: thin_number,
             size: size,
+            size_policy: ThinSizePolicy::default(),
             dm_name: dm_name,
             params: params.clone(),
+            pending_grow: false,
+            grow_failed: false,
+            throttle_dev: throttle_dev,
         };
 
         if let ThinStatus::Fail = try!(thin.status()) {
@@ -333,46 +790,209 @@ impl ThinDev {
         // Do this first so if devnode is in use this fails before we
         // remove the devnode
         try!(self.dev.teardown(dm));
+        if let Some(ref throttle_dev) = self.throttle_dev {
+            try!(throttle_dev.teardown(dm));
+        }
         try!(self.remove_devnode());
 
         Ok(())
     }
 
+    fn throttle_dm_name(dm_name: &str) -> String {
+        format!("{}-throttle", dm_name)
+    }
+
+    fn thin_table(&self) -> Vec<(u64, u64, String, String)> {
+        vec![(0u64, *self.size, "thin".to_owned(), self.params.clone())]
+    }
+
+    // Delay writes only; reads don't use any more pool space.
+    fn delay_table(&self, throttle_dev: &DmDevice, delay_ms: u32)
+                   -> Vec<(u64, u64, String, String)> {
+        vec![(0u64, *self.size, "delay".to_owned(),
+              format!("{} 0 0 {} 0 {}",
+                      throttle_dev.dstr(), throttle_dev.dstr(), delay_ms))]
+    }
+
+    pub fn is_throttled(&self) -> bool {
+        self.throttle_dev.is_some()
+    }
+
+    // Slow down writes to the thin dev by delay_ms each.
+    pub fn throttle(&mut self, dm: &DM, delay_ms: u32) -> FroyoResult<()> {
+        if self.is_throttled() {
+            return Ok(())
+        }
+
+        dbgp!("Throttling thin #{}", self.thin_number);
+        let throttle_name = ThinDev::throttle_dm_name(&self.dm_name);
+        let throttle_dev = try!(DmDevice::new(dm, &throttle_name, &self.thin_table()));
+
+        if let Err(e) = self.dev.reload(dm, &self.delay_table(&throttle_dev, delay_ms)) {
+            try!(throttle_dev.teardown(dm));
+            return Err(e)
+        }
+
+        self.throttle_dev = Some(throttle_dev);
+
+        Ok(())
+    }
+
+    pub fn unthrottle(&mut self, dm: &DM) -> FroyoResult<()> {
+        if let Some(throttle_dev) = self.throttle_dev.take() {
+            dbgp!("Unthrottling thin #{}", self.thin_number);
+            if let Err(e) = self.dev.reload(dm, &self.thin_table()) {
+                self.throttle_dev = Some(throttle_dev);
+                return Err(e)
+            }
+            try!(throttle_dev.teardown(dm));
+        }
+
+        Ok(())
+    }
+
+    // An Err from growing the filesystem leaves the thin dev extended
     pub fn extend(&mut self, sectors: Sectors) -> FroyoResult<()> {
 
+        let old_size = self.size;
         self.size = self.size + sectors;
 
-        let dm = try!(DM::new());
-        let id = &DevId::Name(&self.dm_name);
+        if let Err(e) = self.reload_size() {
+            // Keep size matching what's loaded, it's what gets saved
+            self.size = old_size;
+            return Err(e)
+        }
+
+        self.pending_grow = true;
+        try!(self.grow_fs());
 
-        let table = [(0u64, *self.size, "thin", &*self.params)];
+        Ok(())
+    }
 
-        try!(dm.table_load(id, &table));
-        try!(dm.device_suspend(id, DM_SUSPEND));
-        try!(dm.device_suspend(id, DmFlags::empty()));
+    fn reload_size(&self) -> FroyoResult<()> {
+        let dm = try!(DM::new());
 
-        // TODO: we need to know where it's mounted in order to call
-        // this
-        // let output = try!(Command::new("xfs_growfs")
-        //                   .arg(&mount_point)
-        //                   .output());
+        match self.throttle_dev {
+            Some(ref throttle_dev) => {
+                // Grow the bottom device first
+                try!(throttle_dev.reload(&dm, &self.thin_table()));
+                let delay_ms = try!(self.delay_ms(&dm));
+                try!(self.dev.reload(&dm, &self.delay_table(throttle_dev, delay_ms)));
+            },
+            None => try!(self.dev.reload(&dm, &self.thin_table())),
+        }
 
         Ok(())
     }
 
+    // Grow the filesystem to fill the thin dev. Filesystems can only
+    // be grown online, so if it's not mounted leave pending_grow set
+    // and return false, and we'll try again later.
+    pub fn grow_fs(&mut self) -> FroyoResult<bool> {
+        let mounts = try!(mount_points(self.dev.dev));
+        let (mount_point, fs_type) = match mounts.into_iter().next() {
+            Some(x) => x,
+            None => {
+                dbgp!("thin #{} not mounted, deferring fs grow", self.thin_number);
+                return Ok(false)
+            }
+        };
+
+        let dev_name = format!("/dev/froyo/{}", self.name);
+        let output = match &*fs_type {
+            "xfs" => try!(Command::new("xfs_growfs")
+                          .arg(&mount_point)
+                          .output()),
+            "ext4" | "ext3" | "ext2" => try!(Command::new("resize2fs")
+                                             .arg(&dev_name)
+                                             .output()),
+            "btrfs" => try!(Command::new("btrfs")
+                            .arg("filesystem")
+                            .arg("resize")
+                            .arg("max")
+                            .arg(&mount_point)
+                            .output()),
+            x => {
+                self.pending_grow = false;
+                self.grow_failed = true;
+                return Err(FroyoError::Froyo(InternalError(
+                    format!("Don't know how to grow a {} filesystem", x).into())))
+            }
+        };
+
+        // Don't keep retrying a grow that failed
+        self.pending_grow = false;
+
+        if output.status.success() {
+            dbgp!("Grew {} filesystem on {} at {}",
+                  fs_type, dev_name, mount_point.display());
+            self.grow_failed = false;
+            Ok(true)
+        } else {
+            self.grow_failed = true;
+            Err(FroyoError::Froyo(InternalError(
+                format!("{} grow error: {}", fs_type,
+                        String::from_utf8_lossy(&output.stderr)).into())))
+        }
+    }
+
+    // If the thin dev is filling up, how much its policy says to
+    // extend it by, limited by the policy's max size.
+    pub fn wanted_extension(&self, mapped: Sectors) -> Option<Sectors> {
+        let policy = &self.size_policy;
+        if !policy.auto_extend {
+            return None
+        }
+
+        if *mapped * 100 < *self.size * policy.threshold_pct as u64 {
+            return None
+        }
+
+        let mut extend = Sectors(*self.size * policy.extend_pct as u64 / 100);
+        if policy.max_size != Sectors(0) {
+            if self.size >= policy.max_size {
+                return None
+            }
+            extend = min(extend, policy.max_size - self.size);
+        }
+
+        Some(extend)
+    }
+
     pub fn to_save(&self) -> ThinDevSave {
         ThinDevSave {
             name: self.name.clone(),
             thin_number: self.thin_number,
             size: self.size,
+            pending_grow: self.pending_grow,
+            size_policy: self.size_policy,
         }
     }
 
+    // The current write delay of a throttled thin dev
+    fn delay_ms(&self, dm: &DM) -> FroyoResult<u32> {
+        let (_, table) = try!(
+            dm.table_status(&DevId::Name(&self.dm_name), DM_STATUS_TABLE));
+
+        // "<dev> <offset> <delay> <write dev> <write offset> <write delay>"
+        table.get(0)
+            .and_then(|line| line.3.split(' ').nth(5))
+            .and_then(|x| x.parse::<u32>().ok())
+            .ok_or_else(|| FroyoError::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Could not parse delay table")))
+    }
+
     pub fn status(&self) -> FroyoResult<ThinStatus> {
         let dm = try!(DM::new());
 
+        // Thin status is on the bottom device if throttled
+        let dm_name = match self.throttle_dev {
+            Some(ref throttle_dev) => &throttle_dev.dm_name,
+            None => &self.dm_name,
+        };
         let (_, mut status) = try!(
-            dm.table_status(&DevId::Name(&self.dm_name), DmFlags::empty()));
+            dm.table_status(&DevId::Name(dm_name), DmFlags::empty()));
 
         if status.len() != 1 {
             return Err(FroyoError::Io(io::Error::new(
@@ -391,6 +1011,29 @@ impl ThinDev {
             status_vals[0].parse::<u64>().unwrap())))
     }
 
+    // Tell the filesystem to discard its unused blocks, so the thin
+    // pool can reclaim them.
+    pub fn trim(&self) -> FroyoResult<()> {
+        let mount_point = match try!(mount_points(self.dev.dev)).into_iter().next() {
+            Some((mp, _)) => mp,
+            None => return Err(FroyoError::Froyo(InternalError(
+                format!("Volume {} is not mounted", self.name).into()))),
+        };
+
+        let output = try!(Command::new("fstrim")
+                          .arg(&mount_point)
+                          .output());
+
+        if output.status.success() {
+            dbgp!("Trimmed {}", mount_point.display())
+        } else {
+            return Err(FroyoError::Froyo(InternalError(
+                format!("fstrim error: {}",
+                        String::from_utf8_lossy(&output.stderr)).into())))
+        }
+        Ok(())
+    }
+
     fn create_devnode(name: &str, dev: Device) -> FroyoResult<()> {
         let mut pathbuf = PathBuf::from("/dev/froyo");
 
@@ -10,6 +10,7 @@ use std::borrow::Cow;
 use serde;
 use serde_json;
 use nix;
+use nix::errno::{Errno, EBUSY, ENOSPC, ENOENT};
 use term;
 use dbus;
 
@@ -114,11 +115,76 @@ impl Error for InternalError {
     }
 }
 
+//
+// Errors that clients may want to tell apart. Each has a stable D-Bus
+// error name, and an exit code for the CLI.
+//
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FroyoErrorKind {
+    NotFound,
+    InUse,
+    NeedsForce,
+    InsufficientSpace,
+    WouldFail,
+    Busy,
+    Corrupt,
+}
+
+const FROYO_ERROR_PREFIX: &'static str = "org.freedesktop.Froyo1.Error.";
+
+impl FroyoErrorKind {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            FroyoErrorKind::NotFound => "NotFound",
+            FroyoErrorKind::InUse => "InUse",
+            FroyoErrorKind::NeedsForce => "NeedsForce",
+            FroyoErrorKind::InsufficientSpace => "InsufficientSpace",
+            FroyoErrorKind::WouldFail => "WouldFail",
+            FroyoErrorKind::Busy => "Busy",
+            FroyoErrorKind::Corrupt => "Corrupt",
+        }
+    }
+
+    pub fn dbus_name(&self) -> String {
+        format!("{}{}", FROYO_ERROR_PREFIX, self.name())
+    }
+
+    pub fn from_dbus_name(name: &str) -> Option<FroyoErrorKind> {
+        if !name.starts_with(FROYO_ERROR_PREFIX) {
+            return None
+        }
+        match &name[FROYO_ERROR_PREFIX.len()..] {
+            "NotFound" => Some(FroyoErrorKind::NotFound),
+            "InUse" => Some(FroyoErrorKind::InUse),
+            "NeedsForce" => Some(FroyoErrorKind::NeedsForce),
+            "InsufficientSpace" => Some(FroyoErrorKind::InsufficientSpace),
+            "WouldFail" => Some(FroyoErrorKind::WouldFail),
+            "Busy" => Some(FroyoErrorKind::Busy),
+            "Corrupt" => Some(FroyoErrorKind::Corrupt),
+            _ => None,
+        }
+    }
+
+    // 1 is left for errors without a kind
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            FroyoErrorKind::NotFound => 2,
+            FroyoErrorKind::InUse => 3,
+            FroyoErrorKind::NeedsForce => 4,
+            FroyoErrorKind::InsufficientSpace => 5,
+            FroyoErrorKind::WouldFail => 6,
+            FroyoErrorKind::Busy => 7,
+            FroyoErrorKind::Corrupt => 8,
+        }
+    }
+}
+
 // Define a common error enum.
 // See http://blog.burntsushi.net/rust-error-handling/
 #[derive(Debug)]
 pub enum FroyoError {
     Froyo(InternalError),
+    Typed(FroyoErrorKind, InternalError),
     Io(io::Error),
     Serde(serde_json::error::Error),
     Nix(nix::Error),
@@ -130,6 +196,7 @@ impl fmt::Display for FroyoError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             FroyoError::Froyo(ref err) => write!(f, "Froyo error: {}", err.0),
+            FroyoError::Typed(_, ref err) => write!(f, "Froyo error: {}", err.0),
             FroyoError::Io(ref err) => write!(f, "IO error: {}", err),
             FroyoError::Serde(ref err) => write!(f, "Serde error: {}", err),
             FroyoError::Nix(ref err) => write!(f, "Nix error: {}", err.errno().desc()),
@@ -144,6 +211,7 @@ impl Error for FroyoError {
     fn description(&self) -> &str {
         match *self {
             FroyoError::Froyo(ref err) => &err.0,
+            FroyoError::Typed(_, ref err) => &err.0,
             FroyoError::Io(ref err) => err.description(),
             FroyoError::Serde(ref err) => Error::description(err),
             FroyoError::Nix(ref err) => err.errno().desc(),
@@ -155,6 +223,7 @@ impl Error for FroyoError {
     fn cause(&self) -> Option<&Error> {
         match *self {
             FroyoError::Froyo(ref err) => Some(err),
+            FroyoError::Typed(_, ref err) => Some(err),
             FroyoError::Io(ref err) => Some(err),
             FroyoError::Serde(ref err) => Some(err),
             FroyoError::Nix(ref err) => Some(err),
@@ -164,6 +233,31 @@ impl Error for FroyoError {
     }
 }
 
+impl FroyoError {
+    // Errors from the kernel are given a kind where that is clear
+    // from the errno. Errors from the daemon keep the kind it sent.
+    pub fn kind(&self) -> Option<FroyoErrorKind> {
+        let errno = match *self {
+            FroyoError::Typed(kind, _) => return Some(kind),
+            FroyoError::Dbus(ref err) => return err.name()
+                .and_then(FroyoErrorKind::from_dbus_name),
+            FroyoError::Io(ref err) => match err.raw_os_error() {
+                Some(x) => Errno::from_i32(x),
+                None => return None,
+            },
+            FroyoError::Nix(ref err) => err.errno(),
+            _ => return None,
+        };
+
+        match errno {
+            EBUSY => Some(FroyoErrorKind::Busy),
+            ENOSPC => Some(FroyoErrorKind::InsufficientSpace),
+            ENOENT => Some(FroyoErrorKind::NotFound),
+            _ => None,
+        }
+    }
+}
+
 impl From<InternalError> for FroyoError {
     fn from(err: InternalError) -> FroyoError {
         FroyoError::Froyo(err)
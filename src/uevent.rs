@@ -0,0 +1,156 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Block device hotplug, from the kernel's uevent netlink socket
+
+use std::collections::BTreeMap;
+use std::u8;
+use std::os::unix::io::RawFd;
+use std::path::PathBuf;
+
+use nix;
+use nix::errno::{EAGAIN, ENOBUFS};
+use nix::sys::socket::{socket, bind, recv, AddressFamily, SockType, SockAddr};
+use nix::sys::socket::{SOCK_CLOEXEC, SOCK_NONBLOCK, MSG_DONTWAIT};
+use nix::unistd::close;
+use devicemapper::Device;
+
+use types::FroyoResult;
+
+const NETLINK_KOBJECT_UEVENT: i32 = 15;
+// The kernel's multicast group. udev rebroadcasts on another.
+const UEVENT_KERNEL_GROUP: u32 = 1;
+const UEVENT_BUFFER_SIZE: usize = 8192;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UeventAction {
+    Add,
+    Remove,
+}
+
+impl UeventAction {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            UeventAction::Add => "add",
+            UeventAction::Remove => "remove",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Uevent {
+    pub action: UeventAction,
+    pub path: PathBuf,
+    // None if the minor number is too big for a Device, so the
+    // device can only be told by its path
+    pub dev: Option<Device>,
+}
+
+impl Uevent {
+    // A uevent is "<action>@<devpath>" followed by KEY=VALUE pairs,
+    // all NUL-terminated. Only block add/remove events are of
+    // interest, and not for our own dm devices.
+    fn parse(buf: &[u8]) -> Option<Uevent> {
+        let vals = buf.split(|&b| b == 0)
+            .skip(1)
+            .filter_map(|kv| {
+                let kv = String::from_utf8_lossy(kv);
+                let mut kv = kv.splitn(2, '=');
+                match (kv.next(), kv.next()) {
+                    (Some(k), Some(v)) => Some((k.to_owned(), v.to_owned())),
+                    _ => None,
+                }
+            })
+            .collect::<BTreeMap<_, _>>();
+
+        if vals.get("SUBSYSTEM").map_or(true, |s| s != "block") {
+            return None
+        }
+
+        let action = match vals.get("ACTION").map(|s| &**s) {
+            Some("add") => UeventAction::Add,
+            Some("remove") => UeventAction::Remove,
+            _ => return None,
+        };
+
+        let name = match vals.get("DEVNAME") {
+            Some(name) if !name.starts_with("dm-") => name,
+            _ => return None,
+        };
+
+        let major = vals.get("MAJOR").and_then(|s| s.parse::<u32>().ok());
+        let minor = vals.get("MINOR").and_then(|s| s.parse::<u32>().ok());
+        let dev = match (major, minor) {
+            (Some(major), Some(minor)) if minor <= u8::MAX as u32 =>
+                Some(Device { major: major, minor: minor as u8 }),
+            (Some(major), Some(minor)) => {
+                dbgp!("uevent for /dev/{} ({}:{}), matching by path",
+                      name, major, minor);
+                None
+            },
+            _ => return None,
+        };
+
+        Some(Uevent {
+            action: action,
+            path: PathBuf::from(format!("/dev/{}", name)),
+            dev: dev,
+        })
+    }
+}
+
+pub struct UeventSocket {
+    fd: RawFd,
+}
+
+impl UeventSocket {
+    pub fn new() -> FroyoResult<UeventSocket> {
+        let fd = try!(socket(AddressFamily::Netlink, SockType::Datagram,
+                             SOCK_CLOEXEC | SOCK_NONBLOCK, NETLINK_KOBJECT_UEVENT));
+        if let Err(e) = bind(fd, &SockAddr::new_netlink(0, UEVENT_KERNEL_GROUP)) {
+            let _ = close(fd);
+            return Err(e.into())
+        }
+
+        Ok(UeventSocket { fd: fd })
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.fd
+    }
+
+    // Block device uevents received since last called, and whether
+    // some were lost because the socket's buffer overflowed. Doesn't
+    // wait.
+    pub fn pending(&self) -> FroyoResult<(Vec<Uevent>, bool)> {
+        let mut buf = vec![0u8; UEVENT_BUFFER_SIZE];
+        let mut events = Vec::new();
+        let mut lost = false;
+        loop {
+            match recv(self.fd, &mut buf, MSG_DONTWAIT) {
+                Ok(len) => {
+                    if let Some(event) = Uevent::parse(&buf[..len]) {
+                        dbgp!("uevent: {} {}", event.action.name(), event.path.display());
+                        events.push(event);
+                    }
+                },
+                Err(nix::Error::Sys(EAGAIN)) => break,
+                // What's still queued can be read as usual
+                Err(nix::Error::Sys(ENOBUFS)) => {
+                    dbgp!("uevent socket overflowed, events were lost");
+                    lost = true;
+                },
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok((events, lost))
+    }
+}
+
+impl Drop for UeventSocket {
+    fn drop(&mut self) {
+        let _ = close(self.fd);
+    }
+}
@@ -3,9 +3,13 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
 use std::os::unix::prelude::AsRawFd;
 
-use types::{FroyoResult, FroyoError};
+use devicemapper::Device;
+
+use types::{FroyoResult, FroyoError, Sectors};
 
 pub fn align_to(num: u64, align_to: u64) -> u64 {
     let agn = align_to - 1;
@@ -24,8 +28,53 @@ pub fn blkdev_size(file: &File) -> FroyoResult<u64> {
     }
 }
 
+// Parse a dm-raid status sync ratio, "<in sync>/<total>"
+pub fn parse_sync_ratio(ratio: &str) -> Option<(Sectors, Sectors)> {
+    let vals = ratio.split('/')
+        .filter_map(|x| x.parse::<u64>().ok())
+        .collect::<Vec<_>>();
+    match vals.len() {
+        2 => Some((Sectors(vals[0]), Sectors(vals[1]))),
+        _ => None,
+    }
+}
+
 pub fn short_id(id: &str) -> String {
     let mut shortstr = id.to_owned();
     shortstr.truncate(8);
     shortstr
 }
+
+// Find where a block device is mounted by looking for its "major:minor"
+// in /proc/self/mountinfo. Returns (mount point, fs type) pairs.
+pub fn mount_points(dev: Device) -> FroyoResult<Vec<(PathBuf, String)>> {
+    let mut f = try!(File::open("/proc/self/mountinfo"));
+    let mut buf = String::new();
+    try!(f.read_to_string(&mut buf));
+
+    let dstr = format!("{}:{}", dev.major, dev.minor);
+    let mut mounts = Vec::new();
+    for line in buf.lines() {
+        // See proc(5). Optional fields end with a lone "-", then
+        // comes the fs type.
+        let vals = line.split(' ').collect::<Vec<_>>();
+        if vals.len() < 5 || vals[2] != dstr {
+            continue
+        }
+
+        let fs_type = vals.iter()
+            .skip_while(|v| **v != "-")
+            .nth(1)
+            .map_or("unknown", |v| *v);
+
+        // Spaces etc. in the mount point are octal-escaped
+        let mount_point = vals[4].replace("\\040", " ")
+            .replace("\\011", "\t")
+            .replace("\\012", "\n")
+            .replace("\\134", "\\");
+
+        mounts.push((PathBuf::from(mount_point), fs_type.to_owned()));
+    }
+
+    Ok(mounts)
+}